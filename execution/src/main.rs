@@ -28,7 +28,9 @@ fn main() {
     let mut http_server = HttpServer::create(end_point!(0.0.0.0:8080), 10);
 
     http_server.set_write_timeout(5 * 1000);
-    http_server.open_server_log(false);
+    // the server logs through the `log` crate, e.g. RUST_LOG=http_server=debug
+    env_logger::init();
+    http_server.open_server_log(true);
 
     let middlewares = inject_middlewares! {
         |_req:& Request,_res:&mut Response|->bool{
@@ -59,12 +61,15 @@ fn main() {
     http_server
         .route(POST, "/multiple")
         .reg(|req: &Request, res: &mut Response| {
-			//println!("multiple");
-			let files = req.get_files();
-			let texts = req.get_queries();
-			let s = format!("texts:{:#?}\n files:{:#?}",texts,files);
-			res.add_header("Content-type".to_string(), "text/html; charset=utf-8".to_string());
-			res.write_string(&s);
+            //println!("multiple");
+            let files = req.get_files();
+            let texts = req.get_queries();
+            let s = format!("texts:{:#?}\n files:{:#?}", texts, files);
+            res.add_header(
+                "Content-type".to_string(),
+                "text/html; charset=utf-8".to_string(),
+            );
+            res.write_string(&s);
         });
 
     http_server
@@ -137,5 +142,5 @@ fn main() {
         },
     );
 
-    http_server.run();
+    http_server.run().expect("server stopped");
 }
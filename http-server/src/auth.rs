@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use crate::{MiddleWare, Request, Response};
+
+type Validator = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// A middleware that lets a request through only with `Basic` credentials
+/// the validator accepts, and answers anything else with a `401` asking
+/// for them. Put it in front of a route with `inject_middlewares!`.
+#[derive(Clone)]
+pub struct BasicAuth {
+    realm: String,
+    validator: Validator,
+}
+
+impl BasicAuth {
+    /// `validator` gets the user name and the password.
+    pub fn new<F>(realm: &str, validator: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        BasicAuth {
+            realm: realm.to_string(),
+            validator: Arc::new(validator),
+        }
+    }
+}
+
+impl MiddleWare for BasicAuth {
+    fn call(&self, req: &Request, res: &mut Response) -> bool {
+        if let Some((user, password)) = req.basic_auth() {
+            if (self.validator)(&user, &password) {
+                return true;
+            }
+        }
+        res.write_state(401);
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        res.add_header(
+            String::from("WWW-Authenticate"),
+            format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+        );
+        false
+    }
+}
+
+/// Encodes as standard base64 with padding.
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let mut n = 0u32;
+        for (i, &b) in chunk.iter().enumerate() {
+            n |= (b as u32) << (16 - 8 * i);
+        }
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes standard base64 with padding, `None` for anything malformed.
+pub(crate) fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    let value = |b: u8| match b {
+        b'A'..=b'Z' => Some(b - b'A'),
+        b'a'..=b'z' => Some(b - b'a' + 26),
+        b'0'..=b'9' => Some(b - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let mut out = Vec::with_capacity(bytes.len() / 4 * 3);
+    for (index, quad) in bytes.chunks(4).enumerate() {
+        let last = index == bytes.len() / 4 - 1;
+        let padding = quad.iter().rev().take_while(|&&b| b == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n: u32 = 0;
+        for &b in &quad[..4 - padding] {
+            n = (n << 6) | value(b)? as u32;
+        }
+        n <<= 6 * padding as u32;
+        let decoded = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        out.extend_from_slice(&decoded[..3 - padding]);
+    }
+    Some(out)
+}
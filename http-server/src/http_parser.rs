@@ -1,35 +1,166 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::ErrorKind;
 use std::net::{Shutdown, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
 
 use std::rc::Rc;
 use std::str::Utf8Error;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{io, io::prelude::*};
 
-use uuid;
-
+#[cfg(feature = "compression")]
+pub mod compress;
 pub mod connection;
+pub mod stream;
+pub use connection::conditional::{evaluate_preconditions, PreconditionResult};
+pub use connection::cookie::{Cookie, SameSite};
+pub use connection::websocket::{WebSocket, WebSocketMessage};
+use connection::StreamedBody;
 pub use connection::{
-    BodyContent, BodyType, MultipleFormData, MultipleFormFile, Request, Response,
-    ResponseChunkMeta, ResponseRangeMeta,
+    BodyContent, BodyReader, BodyType, HttpVersion, MiddleWareTrace, MultipleFormData,
+    MultipleFormFile, Request, Response, ResponseChunkMeta, ResponseRangeMeta, SseStream,
 };
+pub use stream::ConnStream;
+
+use crate::thread_pool::WorkerLoad;
 
 pub trait Router {
     fn call(&self, req: &Request, res: &mut Response);
+
+    /// Whether the handler reads the body itself, as it arrives, through
+    /// `Request::body_reader`, instead of the server reading all of it
+    /// first. Its body is never parsed as a form or text.
+    fn reads_body(&self) -> bool {
+        false
+    }
 }
 
 pub trait MiddleWare {
     fn call(&self, req: &Request, res: &mut Response) -> bool;
 }
 
+/// Runs once the router is done, or once a middleware stopped the chain,
+/// with the response about to be sent; e.g. to add an `X-Response-Time`
+/// or CORS headers to every answer.
+pub trait AfterMiddleWare {
+    fn call(&self, req: &Request, res: &mut Response);
+}
+
+/// Looks at the finished response just before it is written, e.g. to log
+/// the status and the time the route took. Runs for a short-circuited
+/// request as well, see `HttpServer::after` and
+/// `RouterRegister::reg_with_hooks`.
+pub trait AfterWare {
+    fn call(&self, req: &Request, res: &Response);
+}
+
+/// Runs once a response is written, or failed to be, with `written`
+/// telling which; e.g. for an access log. A response the handler wrote to
+/// the connection itself counts as written.
+pub trait AfterWrite {
+    fn call(&self, req: &Request, res: &Response, written: bool);
+}
+
+/// One finished request, as `HttpServer::access_log` hands it out.
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry<'e> {
+    pub method: &'e str,
+    pub url: &'e str,
+    pub version: &'e str,
+    pub status: u16,
+    // what the server wrote for the response, head included; 0 for a
+    // response the handler wrote to the connection itself, e.g. SSE
+    pub bytes_sent: u64,
+    // from the request head being read until the response was written
+    pub elapsed: Duration,
+    // false when the client went away mid-response
+    pub written: bool,
+}
+
+/// Gets an `AccessLogEntry` for every request the router answered.
+pub trait AccessLog {
+    fn call(&self, entry: &AccessLogEntry);
+}
+
+/// How much a server log line matters, most severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// Where the server's own log lines go, see `HttpServer::set_logger`. It is
+/// only called once the log is on with `HttpServer::open_server_log`.
+pub trait ServerLogger {
+    fn log(&self, level: LogLevel, msg: &str);
+}
+
+/// A line per message on stdout, the default without the `log` feature.
+pub struct StdoutLogger;
+
+impl ServerLogger for StdoutLogger {
+    fn log(&self, _level: LogLevel, msg: &str) {
+        println!("{}", msg);
+    }
+}
+
+/// Hands the lines to the `log` crate with the `http_server` target, so
+/// whatever logger the application installed decides where they go. The
+/// default with the `log` feature.
+#[cfg(feature = "log")]
+pub struct LogCrateLogger;
+
+#[cfg(feature = "log")]
+impl ServerLogger for LogCrateLogger {
+    fn log(&self, level: LogLevel, msg: &str) {
+        let level = match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+        };
+        log::log!(target: "http_server", level, "{}", msg);
+    }
+}
+
+/// Takes over a `CONNECT` request. It gets the requested authority
+/// (`host:port`) and the client socket, and from then on owns that socket:
+/// it writes the response line itself (e.g. `200 Connection Established`)
+/// and relays bytes for as long as the tunnel lives. It runs on the worker
+/// thread, so a long tunnel should be moved to a thread of its own.
+pub trait ConnectHandler {
+    fn call(&self, authority: &str, stream: TcpStream);
+}
+
 pub type MiddleWareVec = Vec<Arc<dyn MiddleWare + Send + Sync>>;
 
-pub type RouterValue = (Option<MiddleWareVec>, Arc<dyn Router + Send + Sync>);
+pub type AfterMiddleWareVec = Vec<Arc<dyn AfterMiddleWare + Send + Sync>>;
+
+pub type AfterWareVec = Vec<Arc<dyn AfterWare + Send + Sync>>;
+
+pub type RouterValue = (
+    Option<MiddleWareVec>,
+    Arc<dyn Router + Send + Sync>,
+    Option<AfterMiddleWareVec>,
+    Option<AfterWareVec>,
+);
+
+pub type RouteTable = HashMap<String, RouterValue>;
 
-pub type RouterMap = Arc<HashMap<String, RouterValue>>;
+// keyed by lowercase host name, the routes registered without one live
+// under DEFAULT_HOST
+pub type RouterMap = Arc<HashMap<String, RouteTable>>;
+
+pub(crate) const DEFAULT_HOST: &str = "";
 
 impl<T> MiddleWare for T
 where
@@ -40,6 +171,60 @@ where
     }
 }
 
+impl<T> AfterMiddleWare for T
+where
+    T: Fn(&Request, &mut Response),
+{
+    fn call(&self, req: &Request, res: &mut Response) {
+        (*self)(req, res)
+    }
+}
+
+impl<T> AfterWare for T
+where
+    T: Fn(&Request, &Response),
+{
+    fn call(&self, req: &Request, res: &Response) {
+        (*self)(req, res)
+    }
+}
+
+impl<T> AfterWrite for T
+where
+    T: Fn(&Request, &Response, bool),
+{
+    fn call(&self, req: &Request, res: &Response, written: bool) {
+        (*self)(req, res, written)
+    }
+}
+
+impl<T> ConnectHandler for T
+where
+    T: Fn(&str, TcpStream),
+{
+    fn call(&self, authority: &str, stream: TcpStream) {
+        (*self)(authority, stream)
+    }
+}
+
+impl<T> AccessLog for T
+where
+    T: Fn(&AccessLogEntry),
+{
+    fn call(&self, entry: &AccessLogEntry) {
+        (*self)(entry)
+    }
+}
+
+impl<T> ServerLogger for T
+where
+    T: Fn(LogLevel, &str),
+{
+    fn log(&self, level: LogLevel, msg: &str) {
+        (*self)(level, msg)
+    }
+}
+
 impl<T> Router for T
 where
     T: Fn(&Request, &mut Response),
@@ -51,87 +236,548 @@ where
 
 trait UnifiedError {
     fn to_string(&self) -> String;
-    fn kind(&self) -> ErrorKind;
 }
 
 impl UnifiedError for Utf8Error {
     fn to_string(&self) -> String {
         ToString::to_string(&self)
     }
-
-    fn kind(&self) -> ErrorKind {
-        io::ErrorKind::InvalidData
-    }
 }
 
 impl UnifiedError for io::Error {
     fn to_string(&self) -> String {
         ToString::to_string(&self)
     }
-    fn kind(&self) -> ErrorKind {
-        io::Error::kind(self)
-    }
+}
+
+type HeadResult = Result<(String, Option<Vec<u8>>), HeadError>;
+
+/// Why no request head came in. `status` is what the client is answered
+/// with before the connection closes, none when no request had begun.
+struct HeadError {
+    status: Option<u16>,
+    cause: Box<dyn UnifiedError>,
 }
 
 #[derive(Clone)]
 pub struct ConnectionData {
     pub(super) router_map: RouterMap,
     pub(super) server_config: ServerConfig,
+    pub(super) connect_handler: Option<Arc<dyn ConnectHandler + Send + Sync>>,
+    // set by `ShutdownHandle::shutdown`, connections are not kept alive past it
+    pub(super) shutdown: Arc<AtomicBool>,
+    #[cfg(feature = "tls")]
+    pub(super) tls_config: Option<Arc<rustls::ServerConfig>>,
 }
 #[derive(Clone)]
 pub struct ServerConfig {
     pub(super) upload_directory: String,
     pub(super) read_timeout: u32,
     pub(super) chunk_size: u32,
+    pub(super) flush_each_chunk: bool,
     pub(super) write_timeout: u32,
     pub(super) open_log: bool,
     pub(super) max_body_size: usize,
+    // read an oversized body up to max_body_size instead of refusing it
+    pub(super) truncate_body: bool,
+    pub(super) max_response_memory: usize,
     pub(super) max_header_size: usize,
     pub(super) read_buff_increase_size: usize,
+    pub(super) upload_write_buffer: usize,
+    pub(super) expect_continue: bool,
+    pub(super) keep_alive_timeout: u32,
+    // 0 for none, see `HttpServer::set_header_timeout`
+    pub(super) header_timeout: u32,
+    // 0 for none, see `HttpServer::set_handler_timeout`
+    pub(super) handler_timeout: u32,
+    // connections waiting for a worker, 0 for no limit
+    pub(super) max_pending_connections: usize,
+    pub(super) max_keep_alive_requests: u32,
+    pub(super) enable_trace: bool,
+    // the server's own error bodies as `Response::json_error` writes them
+    pub(super) json_errors: bool,
+    // run before routing, see `HttpServer::on_request`
+    pub(super) on_request: MiddleWareVec,
+    // run after the routes' own, see `HttpServer::set_after_middlewares`
+    pub(super) after_middlewares: AfterMiddleWareVec,
+    // run last of all before writing, see `HttpServer::after`
+    pub(super) after: AfterWareVec,
+    pub(super) after_write: Vec<Arc<dyn AfterWrite + Send + Sync>>,
+    pub(super) access_log: Vec<Arc<dyn AccessLog + Send + Sync>>,
+    pub(super) state: connection::AppState,
+    // answers for a panicked handler, see `HttpServer::set_internal_error`
+    pub(super) internal_error: Option<Arc<dyn Router + Send + Sync>>,
+    pub(super) logger: Arc<dyn ServerLogger + Send + Sync>,
+    #[cfg(feature = "compression")]
+    pub(super) compression: Option<compress::CompressionConfig>,
+}
+
+impl ServerConfig {
+    /// Hands `msg` to the logger when the server log is on, formatting it
+    /// only then.
+    pub(super) fn log(&self, level: LogLevel, msg: std::fmt::Arguments) {
+        if self.open_log {
+            self.logger.log(level, &msg.to_string());
+        }
+    }
+
+    /// Refuses values the server can't work with, e.g. a chunk size of 0
+    /// would never get through a body.
+    pub(super) fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: &str| Err(io::Error::new(ErrorKind::InvalidInput, msg.to_string()));
+        if self.chunk_size == 0 {
+            return invalid("chunk size must be greater than 0");
+        }
+        if self.read_buff_increase_size == 0 {
+            return invalid("read buffer increase size must be greater than 0");
+        }
+        if self.max_header_size == 0 {
+            return invalid("max header size must be greater than 0");
+        }
+        if self.max_body_size < self.read_buff_increase_size {
+            return invalid("max body size must be at least the read buffer increase size");
+        }
+        Ok(())
+    }
 }
 
 enum HasBody {
     Len(usize),
+    Chunked,
     None,
     Bad,
+    // a transfer coding other than chunked, e.g. gzip, which isn't decoded
+    Unsupported,
 }
 
 fn has_body(head_map: &HashMap<&str, &str>) -> HasBody {
-    let i = head_map.keys().find(|&&k| -> bool {
-        if k.to_lowercase() == "content-length" {
-            return true;
+    let length = find_header(head_map, "content-length");
+    match find_header(head_map, "transfer-encoding") {
+        Some(coding) => {
+            let codings: Vec<&str> = coding.split(',').map(str::trim).collect();
+            let chunked_at = codings
+                .iter()
+                .position(|c| c.eq_ignore_ascii_case("chunked"));
+            // chunked has to be the final coding, and a length next to it is
+            // how requests get smuggled past a proxy
+            if length.is_some() || chunked_at.is_some_and(|at| at + 1 != codings.len()) {
+                HasBody::Bad
+            } else if codings.len() == 1 && chunked_at.is_some() {
+                HasBody::Chunked
+            } else {
+                HasBody::Unsupported
+            }
         }
-        false
-    });
-    if let Some(&k) = i {
-        let v = head_map.get(k).unwrap(); // guaranteed by above
-        match v.parse::<usize>() {
-            Ok(size) => return HasBody::Len(size),
-            Err(_) => return HasBody::Bad,
+        None => match length {
+            Some(v) => match v.parse::<usize>() {
+                Ok(size) => HasBody::Len(size),
+                Err(_) => HasBody::Bad,
+            },
+            None => HasBody::None,
+        },
+    }
+}
+
+fn find_header<'m>(head_map: &HashMap<&str, &'m str>, key: &str) -> Option<&'m str> {
+    head_map
+        .iter()
+        .find(|(&k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, &v)| v)
+}
+
+/// Whether `Accept-Encoding` lists `name` (or `*`) with a non-zero weight.
+fn accepts_coding(head_map: &HashMap<&str, &str>, name: &str) -> bool {
+    let accept = match find_header(head_map, "accept-encoding") {
+        Some(v) => v,
+        None => return false,
+    };
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        if !coding.eq_ignore_ascii_case(name) && coding != "*" {
+            return false;
+        }
+        parts.all(|param| match param.trim().split_once('=') {
+            Some((k, q)) if k.trim().eq_ignore_ascii_case("q") => {
+                q.trim().parse::<f32>().map(|q| q > 0.0).unwrap_or(false)
+            }
+            _ => true,
+        })
+    })
+}
+
+fn vary_on_accept_encoding(response: &mut Response) {
+    let vary = match response
+        .header_pair
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("vary"))
+    {
+        Some((_, v)) if v.to_ascii_lowercase().contains("accept-encoding") => return,
+        Some((_, v)) => format!("{}, Accept-Encoding", v),
+        None => String::from("Accept-Encoding"),
+    };
+    response.remove_header(String::from("Vary"));
+    response.add_header(String::from("Vary"), vary);
+}
+
+/// Swaps a file body for its `.br` or `.gz` sidecar, see
+/// `ResponseConfig::precompressed`. Offsets into the sidecar mean nothing
+/// to the client, so a range keeps the original file.
+fn serve_precompressed(response: &mut Response) {
+    if !response.precompressed || !matches!(response.range, ResponseRangeMeta::None) {
+        return;
+    }
+    let path = match &response.body {
+        BodyType::File(path) => path.clone(),
+        _ => return,
+    };
+    vary_on_accept_encoding(response);
+    for (coding, suffix) in [("br", ".br"), ("gzip", ".gz")] {
+        if !accepts_coding(&response.request_header, coding) {
+            continue;
         }
+        let sidecar = format!("{}{}", path, suffix);
+        if let Ok(meta) = std::fs::metadata(&sidecar) {
+            if meta.is_file() {
+                response.remove_header(String::from("Content-length"));
+                response.add_header(String::from("Content-length"), meta.len().to_string());
+                response.add_header(String::from("Content-Encoding"), String::from(coding));
+                response.body = BodyType::File(sidecar);
+                return;
+            }
+        }
+    }
+}
+
+fn expects_continue(head_map: &HashMap<&str, &str>) -> bool {
+    match find_header(head_map, "expect") {
+        Some(v) => v.eq_ignore_ascii_case("100-continue"),
+        None => false,
+    }
+}
+
+/// Sends the interim `100 Continue` that a client asking for it waits on
+/// before sending the body, unless part of the body is already here. An
+/// HTTP/1.0 client doesn't know interim responses and never gets one.
+fn write_continue(
+    stream: &mut (dyn ConnStream + 'static),
+    version: &str,
+    head_map: &HashMap<&str, &str>,
+    body_started: bool,
+    server_config: &ServerConfig,
+) -> io::Result<()> {
+    if server_config.expect_continue
+        && HttpVersion::parse(version) == HttpVersion::Http11
+        && !body_started
+        && expects_continue(head_map)
+    {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    Ok(())
+}
+
+/// Sends a short plain-text response for a request that is refused before
+/// routing, e.g. an oversized or malformed one. `close` adds
+/// `Connection: close` for the cases where the stream can't be reused.
+fn write_rejection(
+    stream: &mut (dyn ConnStream + 'static),
+    version: &str,
+    code: u16,
+    reason: &str,
+    close: bool,
+    server_config: &ServerConfig,
+) -> io::Result<()> {
+    let conn = Rc::new(RefCell::new(stream));
+    let mut response = Response {
+        header_pair: HashMap::new(),
+        version,
+        method: "",
+        http_state: code,
+        body: BodyType::None,
+        chunked: ResponseChunkMeta::new(server_config.chunk_size, server_config.flush_each_chunk),
+        conn_: Rc::clone(&conn),
+        range: ResponseRangeMeta::None,
+        request_header: Rc::default(),
+        middleware_trace: MiddleWareTrace::default(),
+        multi_header_pair: Vec::new(),
+        sent: false,
+        max_memory: server_config.max_response_memory,
+        memory_exceeded: false,
+        logger: server_config
+            .open_log
+            .then(|| Arc::clone(&server_config.logger)),
+        #[cfg(feature = "compression")]
+        compress: false,
+        precompressed: false,
+        not_modified: None,
+    };
+    if server_config.json_errors {
+        response.json_error(code, reason);
     } else {
-        return HasBody::None;
+        response.write_string(reason).status(code);
+        response.add_header(
+            String::from("Content-Type"),
+            String::from("text/plain; charset=utf-8"),
+        );
+    }
+    if close {
+        response.add_header(String::from("Connection"), String::from("close"));
+    }
+    // the server was out of room or going down, a moment later it may not be
+    if code == 503 {
+        response.add_header(String::from("Retry-After"), String::from("1"));
+    }
+    let mut stream = conn.borrow_mut();
+    write_once(*stream, &mut response)
+}
+
+// a rejection rendered before it is sent, `write_rejection` wants a stream
+struct Rendered {
+    sock: TcpStream,
+    out: Vec<u8>,
+}
+
+impl Read for Rendered {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Rendered {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
+impl ConnStream for Rendered {
+    fn tcp(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+// a rejected connection, written to and read away without ever blocking
+struct Rejected {
+    stream: TcpStream,
+    reply: Vec<u8>,
+    written: usize,
+    left: u64,
+    deadline: Instant,
+}
+
+impl Rejected {
+    // false once it is done with and may be dropped
+    fn advance(&mut self) -> bool {
+        let mut sock = &self.stream;
+        while self.written < self.reply.len() {
+            match sock.write(&self.reply[self.written..]) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.written += n;
+                    if self.written == self.reply.len() {
+                        let _ = sock.shutdown(Shutdown::Write);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        // as in `linger_close`, what the client still sends is read away
+        let mut buf = [0u8; 8192];
+        loop {
+            match sock.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(n) => self.left = self.left.saturating_sub(n as u64),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+            if self.left == 0 {
+                return false;
+            }
+        }
+        Instant::now() < self.deadline
+    }
+}
+
+// how many rejected connections wait for `Rejecter`'s thread at most,
+// past it they are closed straight away
+const MAX_REJECTED: usize = 256;
+const REJECTED_POLL: Duration = Duration::from_millis(10);
+
+/// Turns away the connections no worker will serve with a 503, e.g. past
+/// `HttpServer::set_max_pending_connections`, without holding up the
+/// accept loop: the reply is written without blocking, and a thread of
+/// its own sends what did not fit and reads away the request until the
+/// client closes, so the reply is not lost to a reset.
+pub(crate) struct Rejecter {
+    tx: mpsc::SyncSender<Rejected>,
+}
+
+impl Rejecter {
+    pub(crate) fn start() -> Self {
+        let (tx, rx) = mpsc::sync_channel(MAX_REJECTED);
+        thread::spawn(move || {
+            let mut open: Vec<Rejected> = Vec::new();
+            let mut closed = false;
+            loop {
+                if open.is_empty() {
+                    match rx.recv() {
+                        Ok(conn) => open.push(conn),
+                        Err(_) => return,
+                    }
+                } else if closed {
+                    thread::sleep(REJECTED_POLL);
+                } else {
+                    match rx.recv_timeout(REJECTED_POLL) {
+                        Ok(conn) => open.push(conn),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => closed = true,
+                    }
+                }
+                open.extend(rx.try_iter());
+                open.retain_mut(Rejected::advance);
+            }
+        });
+        Rejecter { tx }
+    }
+
+    /// A TLS connection is just closed, answering would take a handshake.
+    pub(crate) fn reject_unavailable(
+        &self,
+        conn_data: &ConnectionData,
+        stream: TcpStream,
+        reason: &str,
+    ) {
+        #[cfg(feature = "tls")]
+        if conn_data.tls_config.is_some() {
+            return;
+        }
+        let Ok(sock) = stream.try_clone() else {
+            return;
+        };
+        if stream.set_nonblocking(true).is_err() {
+            return;
+        }
+        let mut rendered = Rendered {
+            sock,
+            out: Vec::new(),
+        };
+        let _ = write_rejection(
+            &mut rendered,
+            "HTTP/1.1",
+            503,
+            reason,
+            true,
+            &conn_data.server_config,
+        );
+        let mut conn = Rejected {
+            stream,
+            reply: rendered.out,
+            written: 0,
+            left: LINGER_BYTES,
+            deadline: Instant::now() + LINGER_TIME,
+        };
+        if conn.advance() {
+            let _ = self.tx.try_send(conn);
+        }
+    }
+}
+
+// what `linger_close` reads away at most, and for how long
+const LINGER_BYTES: u64 = 1 << 20;
+const LINGER_TIME: Duration = Duration::from_secs(2);
+
+/// Closes a connection whose request was answered before it was read in
+/// full. A socket closed with data still unread sends a reset, which can
+/// destroy the response before the client has read it, so only the write
+/// side is shut and whatever the client still sends is read away until it
+/// closes too, or `LINGER_BYTES` or `LINGER_TIME` run out.
+fn linger_close(mut sock: &TcpStream) {
+    let _ = sock.shutdown(Shutdown::Write);
+    let deadline = Instant::now() + LINGER_TIME;
+    let mut left = LINGER_BYTES;
+    let mut buf = [0u8; 8192];
+    while left > 0 {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        if wait.is_zero() || sock.set_read_timeout(Some(wait)).is_err() {
+            break;
+        }
+        match sock.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => left = left.saturating_sub(n as u64),
+        }
+    }
+}
+
+/// Answers a request that could not be parsed with a 400 and closes the
+/// connection, so the client learns why instead of seeing a reset.
+fn reject_malformed(
+    stream: &mut (dyn ConnStream + 'static),
+    reason: &str,
+    server_config: &ServerConfig,
+) {
+    let _ = write_rejection(stream, "HTTP/1.1", 400, reason, true, server_config);
+    linger_close(stream.tcp());
+}
+
+/// Answers a body over `max_body_size` with a 413 without reading it, so
+/// the connection is closed rather than reused.
+fn reject_oversized(
+    stream: &mut (dyn ConnStream + 'static),
+    version: &str,
+    server_config: &ServerConfig,
+) {
+    let _ = write_rejection(
+        stream,
+        version,
+        413,
+        "request body too large",
+        true,
+        server_config,
+    );
+    linger_close(stream.tcp());
+}
+
+#[allow(clippy::too_many_arguments)]
 fn construct_http_event(
-    stream: &mut TcpStream,
+    stream: &mut (dyn ConnStream + 'static),
     router: &RouterMap,
     method: &str,
     url: &str,
     version: &str,
     head_map: HashMap<&str, &str>,
+    raw_head: &str,
     body: BodyContent,
-    _need_alive: bool,
+    raw_body: &[u8],
+    body_stream: Option<StreamedBody>,
+    body_truncated: bool,
+    need_alive: bool,
+    request_index: u32,
     server_config: &ServerConfig,
+    clock: &HandlerClock,
+    started: Instant,
+    load: &WorkerLoad,
 ) -> bool {
     let conn = Rc::new(RefCell::new(stream));
-    let request = Request {
-        header_pair: head_map.clone(),
+    let head_map = Rc::new(head_map);
+    let mut request = Request {
+        header_pair: Rc::clone(&head_map),
         url,
         method,
         version,
+        raw_head,
         body,
+        raw_body,
+        body_stream,
+        body_truncated,
+        request_index,
+        path_params: Vec::new(),
+        extensions: RefCell::new(HashMap::new()),
+        state: &server_config.state,
         conn_: Rc::clone(&conn),
     };
     let mut response = Response {
@@ -141,81 +787,518 @@ fn construct_http_event(
         //url,
         http_state: 200,
         body: BodyType::None,
-        chunked: ResponseChunkMeta::new(server_config.chunk_size),
+        chunked: ResponseChunkMeta::new(server_config.chunk_size, server_config.flush_each_chunk),
         conn_: Rc::clone(&conn),
         range: ResponseRangeMeta::None,
         request_header: head_map,
+        middleware_trace: MiddleWareTrace::default(),
+        multi_header_pair: Vec::new(),
+        sent: false,
+        max_memory: server_config.max_response_memory,
+        memory_exceeded: false,
+        logger: server_config
+            .open_log
+            .then(|| Arc::clone(&server_config.logger)),
+        #[cfg(feature = "compression")]
+        compress: false,
+        precompressed: false,
+        not_modified: None,
     };
-    do_router(&router, &request, &mut response);
-    // if need_alive{
-    //    response.add_header(String::from("Connection"), String::from("keep-alive"));
-    // }
-    let mut stream = conn.borrow_mut();
-    if !response.chunked.enable {
-        match write_once(*stream, &mut response) {
-            Ok(_) => {}
-            Err(e) => {
-                if server_config.open_log {
-                    println!("write once error:{}", ToString::to_string(&e));
-                }
+    clock.start();
+    // the matched route's, run along with `server_config.after`
+    let mut route_hooks = None;
+    // the router is skipped when a middleware short-circuits, but the
+    // response that middleware left behind is written out the same way below
+    if method == "TRACE" {
+        // never handed to the user's routers, see `trace_echo`
+        trace_echo(router, &request, &mut response, server_config.enable_trace);
+    } else {
+        // a panicking handler gets a 500 rather than the connection dropped.
+        // Asserted unwind safe: after a panic the request is only read, and
+        // the response, whatever state the handler left it in, is reset
+        let routed = panic::catch_unwind(AssertUnwindSafe(|| {
+            let proceed = server_config
+                .on_request
+                .iter()
+                .all(|hook| hook.call(&request, &mut response));
+            if proceed {
+                route_hooks = do_router(router, &mut request, &mut response);
+            }
+        }));
+        if let Err(e) = routed {
+            server_config.log(
+                LogLevel::Error,
+                format_args!(
+                    "{}: handler panicked:{}",
+                    peer_addr(conn.borrow().tcp()),
+                    panic_message(&*e)
+                ),
+            );
+            if response.sent {
                 return false;
             }
-        }
-    } else {
-        // chunked transfer
-        match write_chunk(*stream, &mut response) {
-            Ok(_) => {}
-            Err(e) => {
-                if server_config.open_log {
-                    println!("write chunked error:{}", ToString::to_string(&e));
+            let answered = match &server_config.internal_error {
+                Some(handler) => {
+                    clear_response(&mut response);
+                    response.write_state(500);
+                    panic::catch_unwind(AssertUnwindSafe(|| {
+                        handler.call(&request, &mut response);
+                    }))
+                    .is_ok()
                 }
-                return false;
+                None => false,
+            };
+            if !answered {
+                reset_to_internal_error(&mut response, server_config.json_errors);
             }
         }
+        for after in server_config.after_middlewares.iter().rev() {
+            after.call(&request, &mut response);
+        }
     }
-    true
+    if !clock.finish() {
+        // the watchdog has answered for this request already
+        return false;
+    }
+    for hook in route_hooks
+        .into_iter()
+        .flatten()
+        .chain(&server_config.after)
+    {
+        hook.call(&request, &response);
+    }
+    // looked at only now, connections may have queued up while the handler
+    // ran; this one shouldn't keep the worker from them when no other
+    // worker is free to take them
+    let need_alive = need_alive && !load.backed_up();
+    // the header says what the loop in `serve_connection` will do, so a
+    // client knows whether to reuse the connection
+    if !need_alive {
+        // whatever the handler put there, this is the last response
+        response.remove_header(String::from("Connection"));
+        response.add_header(String::from("Connection"), String::from("close"));
+    } else if !response
+        .header_pair
+        .keys()
+        .any(|k| k.eq_ignore_ascii_case("connection"))
+    {
+        response.add_header(String::from("Connection"), String::from("keep-alive"));
+    }
+    // a handler may ask to close, e.g. to end a body that has no length
+    let closing = response.header_pair.iter().any(|(k, v)| {
+        k.eq_ignore_ascii_case("connection")
+            && v.split(',').any(|t| t.trim().eq_ignore_ascii_case("close"))
+    });
+    let log_access = |response: &Response, bytes_sent: u64, written: bool| {
+        if server_config.access_log.is_empty() {
+            return;
+        }
+        let entry = AccessLogEntry {
+            method,
+            url,
+            version,
+            status: response.http_state,
+            bytes_sent,
+            elapsed: started.elapsed(),
+            written,
+        };
+        for hook in &server_config.access_log {
+            hook.call(&entry);
+        }
+    };
+    if response.sent {
+        for hook in &server_config.after_write {
+            hook.call(&request, &response, true);
+        }
+        log_access(&response, 0, true);
+        return false;
+    }
+    serve_precompressed(&mut response);
+    #[cfg(feature = "compression")]
+    if response.compress {
+        compress::compress_response(&mut response, &compress::CompressionConfig::default());
+    } else if let Some(config) = &server_config.compression {
+        compress::compress_response(&mut response, config);
+    }
+    let (written, bytes_sent) = {
+        let mut stream = conn.borrow_mut();
+        let mut stream = CountingStream {
+            inner: &mut **stream,
+            written: 0,
+        };
+        let written = if let BodyType::Stream(_) = response.body {
+            write_stream(&mut stream, &mut response).map_err(|e| ("write stream", e))
+        } else if !response.chunked.enable {
+            write_once(&mut stream, &mut response).map_err(|e| ("write once", e))
+        } else {
+            // chunked transfer
+            write_chunk(&mut stream, &mut response).map_err(|e| ("write chunked", e))
+        };
+        (written, stream.written)
+    };
+    for hook in &server_config.after_write {
+        hook.call(&request, &response, written.is_ok());
+    }
+    log_access(&response, bytes_sent, written.is_ok());
+    if let Err((what, e)) = written {
+        server_config.log(
+            LogLevel::Warn,
+            format_args!("{}: {} error:{}", peer_addr(conn.borrow().tcp()), what, e),
+        );
+        return false;
+    }
+    !closing
 }
 
-fn is_keep_alive(head_map: &HashMap<&str, &str>) -> bool {
-    let i = head_map.keys().find(|&&k| {
-        if k.to_lowercase() == "connection" {
-            true
-        } else {
-            false
+/// Counts what goes out for the access log.
+struct CountingStream<'s> {
+    inner: &'s mut dyn ConnStream,
+    written: u64,
+}
+
+impl Read for CountingStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for CountingStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl ConnStream for CountingStream<'_> {
+    fn tcp(&self) -> &TcpStream {
+        self.inner.tcp()
+    }
+}
+
+/// HTTP/1.1 connections persist unless the client says `close`, HTTP/1.0
+/// ones only when it asks for `keep-alive`.
+fn is_keep_alive(head_map: &HashMap<&str, &str>, version: &str) -> bool {
+    let has_token = |token: &str| match find_header(head_map, "connection") {
+        Some(v) => v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)),
+        None => false,
+    };
+    if HttpVersion::parse(version) == HttpVersion::Http10 {
+        has_token("keep-alive")
+    } else {
+        !has_token("close")
+    }
+}
+
+/// The client's address for log lines, `-` once it is gone.
+fn peer_addr(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map_or_else(|_| String::from("-"), |addr| addr.to_string())
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> &str {
+    e.downcast_ref::<&str>()
+        .copied()
+        .or_else(|| e.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown")
+}
+
+/// Drops whatever a panicking handler had put in `response` for a plain 500.
+fn reset_to_internal_error(response: &mut Response, json: bool) {
+    clear_response(response);
+    if json {
+        response.json_error(500, "internal server error");
+    } else {
+        response.write_string("internal server error").status(500);
+    }
+}
+
+/// Drops whatever a handler set up before it panicked.
+fn clear_response(response: &mut Response) {
+    response.header_pair.clear();
+    response.multi_header_pair.clear();
+    response.range = ResponseRangeMeta::None;
+    response.chunked.enable = false;
+    response.precompressed = false;
+    response.memory_exceeded = false;
+    #[cfg(feature = "compression")]
+    {
+        response.compress = false;
+    }
+}
+
+#[derive(Default)]
+enum ClockState {
+    #[default]
+    Idle,
+    Running(Instant),
+    // the watchdog gave up on the handler and answered in its place
+    Abandoned,
+}
+
+/// Whether a connection's current request is in its handlers and since
+/// when, for the watchdog of `HttpServer::set_handler_timeout`.
+#[derive(Default)]
+struct HandlerClock(Mutex<ClockState>);
+
+impl HandlerClock {
+    fn state(&self) -> std::sync::MutexGuard<'_, ClockState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start(&self) {
+        *self.state() = ClockState::Running(Instant::now());
+    }
+
+    /// `false` when the watchdog abandoned the request, its response must
+    /// not be written then.
+    fn finish(&self) -> bool {
+        let mut state = self.state();
+        if let ClockState::Abandoned = *state {
+            return false;
         }
-    });
-    match i {
-        Some(&k) => {
-            let &v = head_map.get(k).unwrap();
-            if v.to_lowercase() == "keep-alive" {
-                true
-            } else {
-                false
+        *state = ClockState::Idle;
+        true
+    }
+}
+
+/// `load` tells how busy the workers are, a kept-alive connection is
+/// closed rather than held on to while another waits for none to be free.
+pub fn handle_incoming(
+    (conn_data, stream): (Arc<ConnectionData>, TcpStream),
+    load: &Arc<WorkerLoad>,
+) {
+    match conn_data.server_config.handler_timeout {
+        0 => guard_connection(&conn_data, stream, &HandlerClock::default(), load),
+        limit => watch_connection(
+            conn_data,
+            stream,
+            Duration::from_millis(limit as u64),
+            Arc::clone(load),
+        ),
+    }
+}
+
+fn guard_connection(
+    conn_data: &ConnectionData,
+    stream: TcpStream,
+    clock: &HandlerClock,
+    load: &WorkerLoad,
+) {
+    // a panic anywhere below costs only this connection: unwinding drops and
+    // thereby closes the socket, and the worker goes on to the next one
+    let peer = peer_addr(&stream);
+    let r = panic::catch_unwind(AssertUnwindSafe(|| {
+        accept_connection(conn_data, stream, clock, load)
+    }));
+    if let Err(e) = r {
+        conn_data.server_config.log(
+            LogLevel::Error,
+            format_args!(
+                "{}: connection handling panicked:{}",
+                peer,
+                panic_message(&*e)
+            ),
+        );
+    }
+}
+
+/// Serves the connection on a thread of its own while the worker watches
+/// the clock. A handler running past `limit` gets its request answered
+/// with 504 and the connection shut down, and the worker moves on; the
+/// thread is left to the handler and ends only if the handler does.
+fn watch_connection(
+    conn_data: Arc<ConnectionData>,
+    stream: TcpStream,
+    limit: Duration,
+    load: Arc<WorkerLoad>,
+) {
+    let mut raw = match stream.try_clone() {
+        Ok(raw) => raw,
+        Err(_) => {
+            guard_connection(&conn_data, stream, &HandlerClock::default(), &load);
+            return;
+        }
+    };
+    let clock = Arc::new(HandlerClock::default());
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    {
+        let conn_data = Arc::clone(&conn_data);
+        let clock = Arc::clone(&clock);
+        thread::spawn(move || {
+            guard_connection(&conn_data, stream, &clock, &load);
+            let _ = done_tx.send(());
+        });
+    }
+    let tick = limit.min(Duration::from_millis(100));
+    loop {
+        match done_rx.recv_timeout(tick) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return,
+        }
+        let mut state = clock.state();
+        let overran = matches!(*state, ClockState::Running(start) if start.elapsed() >= limit);
+        if !overran {
+            continue;
+        }
+        // under the lock, so the handler's own response can't be written
+        // in between
+        *state = ClockState::Abandoned;
+        conn_data.server_config.log(
+            LogLevel::Warn,
+            format_args!(
+                "{}: handler exceeded {} ms, abandoned",
+                peer_addr(&raw),
+                limit.as_millis()
+            ),
+        );
+        #[cfg(feature = "tls")]
+        let plain = conn_data.tls_config.is_none();
+        #[cfg(not(feature = "tls"))]
+        let plain = true;
+        // a TLS session can't be written to from here, it is just closed
+        if plain {
+            let _ = write_rejection(
+                &mut raw,
+                "HTTP/1.1",
+                504,
+                "the request took too long",
+                true,
+                &conn_data.server_config,
+            );
+        }
+        let _ = raw.shutdown(Shutdown::Both);
+        return;
+    }
+}
+
+fn accept_connection(
+    conn_data: &ConnectionData,
+    stream: TcpStream,
+    clock: &HandlerClock,
+    load: &WorkerLoad,
+) {
+    let _ = stream.set_read_timeout(socket_timeout(conn_data.server_config.read_timeout));
+    let _ = stream.set_write_timeout(socket_timeout(conn_data.server_config.write_timeout));
+
+    #[cfg(feature = "tls")]
+    if let Some(tls_config) = &conn_data.tls_config {
+        // a plain HTTP request on this port would just fail the handshake,
+        // tell the client what went wrong instead
+        if let Ok(false) = stream::starts_tls(&stream) {
+            let mut stream = stream;
+            let _ = write_rejection(
+                &mut stream,
+                "HTTP/1.1",
+                400,
+                "plain HTTP request sent to an HTTPS port",
+                true,
+                &conn_data.server_config,
+            );
+            let _ = stream.shutdown(Shutdown::Write);
+            return;
+        }
+        let peer = peer_addr(&stream);
+        match stream::accept_tls(Arc::clone(tls_config), stream) {
+            // a tunnel needs the bare socket, which a TLS session can't hand out
+            Ok(mut tls_stream) => serve_connection(conn_data, &mut tls_stream, false, clock, load),
+            Err(e) => {
+                conn_data.server_config.log(
+                    LogLevel::Debug,
+                    format_args!("{}: tls handshake error:{}", peer, e),
+                );
             }
         }
-        None => false,
+        return;
+    }
+    // e.g. a browser sent to `https://` this port, there is no answer it
+    // could read, so just close and say why
+    if let Ok(true) = stream::starts_tls(&stream) {
+        conn_data.server_config.log(
+            LogLevel::Debug,
+            format_args!(
+                "{}: tls handshake on a plain HTTP port, closing the connection",
+                peer_addr(&stream)
+            ),
+        );
+        let _ = stream.shutdown(Shutdown::Both);
+        return;
     }
+    let mut stream = stream;
+    serve_connection(conn_data, &mut stream, true, clock, load);
 }
 
-pub fn handle_incoming((conn_data, mut stream): (Arc<ConnectionData>, TcpStream)) {
-    let _ = stream.set_read_timeout(Some(std::time::Duration::from_millis(
-        conn_data.server_config.read_timeout as u64,
-    )));
-    let _ = stream.set_write_timeout(Some(std::time::Duration::from_millis(
-        conn_data.server_config.write_timeout as u64,
-    )));
+/// A timeout setting as a socket takes it, where 0 means no timeout.
+fn socket_timeout(millis: u32) -> Option<Duration> {
+    (millis > 0).then(|| Duration::from_millis(millis as u64))
+}
 
-    // let mut buff = [b'\0';1024];
-    // let _ = stream.read(& mut buff);
-    // let response = "hello";
-    // let s = format!("HTTP/1.1 200 OK\r\nContent-length:{}\r\n\r\n{}",response.len(),response);
-    // let _ = stream.write(s.as_bytes());
+/// Waits up to `timeout`, or for as long as it takes when `None`, for the
+/// client to start its next request, `false` when it closed the
+/// connection or didn't send anything. The wait ends early, with `false`,
+/// once a connection needs the worker or the server shuts down: the
+/// socket is parked with `load`, whose pool wakes it for either.
+fn await_next_request(
+    sock: &TcpStream,
+    timeout: Option<Duration>,
+    load: &WorkerLoad,
+    shutdown: &AtomicBool,
+) -> bool {
+    let give_up = || load.backed_up() || shutdown.load(Ordering::SeqCst);
+    if give_up() {
+        return false;
+    }
+    let parked = load.park(sock);
+    // once more, a connection queued before parking didn't wake this one
+    if give_up() {
+        return false;
+    }
+    let _ = sock.set_read_timeout(timeout);
+    let mut byte = [0u8; 1];
+    let started = matches!(sock.peek(&mut byte), Ok(n) if n > 0);
+    started && !parked.is_some_and(|parked| parked.woken())
+}
 
+fn serve_connection(
+    conn_data: &ConnectionData,
+    stream: &mut (dyn ConnStream + 'static),
+    allow_tunnel: bool,
+    clock: &HandlerClock,
+    load: &WorkerLoad,
+) {
+    let server_config = &conn_data.server_config;
+    let peer = peer_addr(stream.tcp());
+    let mut served: u32 = 0;
     'Back: loop {
-        let read_result = read_http_head(&mut stream, &conn_data.server_config);
-        if let Ok((mut head_content, possible_body)) = read_result {
+        if served > 0 {
+            // waiting for the next request on a kept-alive connection
+            let keep_alive = socket_timeout(server_config.keep_alive_timeout);
+            // on a bare socket the wait can be cut short for the connections
+            // queued behind this one, a TLS session may have the next request
+            // read and decrypted already
+            if allow_tunnel
+                && !await_next_request(stream.tcp(), keep_alive, load, &conn_data.shutdown)
+            {
+                break;
+            }
+            let _ = stream.tcp().set_read_timeout(keep_alive);
+        }
+        let read_result = read_http_head(stream, server_config);
+        let started = Instant::now();
+        if served > 0 {
+            let _ = stream
+                .tcp()
+                .set_read_timeout(socket_timeout(server_config.read_timeout));
+        }
+        served += 1;
+        if let Ok((head_content, possible_body)) = read_result {
             //println!("{}",head_content);
-            let head_result = parse_header(&mut head_content);
+            let head_result = parse_header(&head_content);
             // let response = "hello";
             // let s = format!(
             //     "HTTP/1.1 200 OK\r\nContent-length:{}\r\n\r\n{}",
@@ -228,187 +1311,420 @@ pub fn handle_incoming((conn_data, mut stream): (Arc<ConnectionData>, TcpStream)
             //println!("{:#?}", head_result.as_ref().unwrap());
             match head_result {
                 Ok((method, url, version, map)) => {
-                    let need_alive = is_keep_alive(&map);
-                    match has_body(&map) {
-                        HasBody::Len(size) => match possible_body {
-                            Some(partial_body) => {
-                                let mut body = partial_body;
-                                let body = read_body(
-                                    &mut stream,
-                                    &map,
-                                    &mut body,
-                                    size,
-                                    &conn_data.server_config,
-                                );
-                                if let BodyContent::Bad = body {
-                                    break;
-                                }
-                                if let BodyContent::TooLarge = body {
-                                    if conn_data.server_config.open_log {
-                                        println!("the non-multiple-form body is too large");
-                                    }
-                                    break;
+                    if method == "CONNECT" && allow_tunnel {
+                        if let Some(handler) = &conn_data.connect_handler {
+                            match stream.tcp().try_clone() {
+                                Ok(tunnel) => handler.call(url, tunnel),
+                                Err(e) => {
+                                    conn_data.server_config.log(
+                                        LogLevel::Warn,
+                                        format_args!("{}: tunnel error:{}", peer, e),
+                                    );
                                 }
-                                //println!("{:?}", body);
-                                let r = construct_http_event(
-                                    &mut stream,
-                                    &conn_data.router_map,
-                                    method,
-                                    url,
+                            }
+                            break;
+                        }
+                    }
+                    let need_alive = is_keep_alive(&map, version)
+                        && (server_config.max_keep_alive_requests == 0
+                            || served < server_config.max_keep_alive_requests)
+                        && !conn_data.shutdown.load(Ordering::Relaxed);
+                    let framing = has_body(&map);
+                    if conn_data.server_config.expect_continue {
+                        let body_expected = !matches!(framing, HasBody::None | HasBody::Len(0));
+                        match find_header(&map, "expect") {
+                            Some(expect) if !expect.eq_ignore_ascii_case("100-continue") => {
+                                let _ = write_rejection(
+                                    stream,
                                     version,
-                                    map,
-                                    body,
-                                    need_alive,
+                                    417,
+                                    "unsupported expectation",
+                                    true,
                                     &conn_data.server_config,
                                 );
-                                if need_alive && r {
-                                    continue 'Back;
-                                } else {
-                                    break;
-                                }
+                                linger_close(stream.tcp());
+                                break;
                             }
-                            None => {
-                                //println!("in this logic, {}", size);
-                                let mut body: Vec<u8> = Vec::new();
-                                let body = read_body(
-                                    &mut stream,
-                                    &map,
-                                    &mut body,
-                                    size,
-                                    &conn_data.server_config,
-                                );
-                                if let BodyContent::Bad = body {
-                                    break;
-                                }
-                                if let BodyContent::TooLarge = body {
-                                    if conn_data.server_config.open_log {
-                                        println!("the non-multiple-form body is too large");
-                                    }
-                                    break;
-                                }
-                                let r = construct_http_event(
-                                    &mut stream,
+                            Some(_)
+                                if possible_body.is_none()
+                                    && body_expected
+                                    && find_router(
+                                        &conn_data.router_map,
+                                        find_header(&map, "host"),
+                                        method,
+                                        url,
+                                    )
+                                    .is_none() =>
+                            {
+                                // nothing would read the body, so answer without asking
+                                // for it and close since it's still unread
+                                construct_http_event(
+                                    stream,
                                     &conn_data.router_map,
                                     method,
                                     url,
                                     version,
                                     map,
-                                    body,
-                                    need_alive,
+                                    &head_content,
+                                    BodyContent::None,
+                                    &[],
+                                    None,
+                                    false,
+                                    false,
+                                    served,
                                     &conn_data.server_config,
+                                    clock,
+                                    started,
+                                    load,
                                 );
-                                if need_alive && r {
-                                    continue 'Back;
-                                } else {
+                                linger_close(stream.tcp());
+                                break;
+                            }
+                            _ => {}
+                        }
+                    }
+                    let mut truncated = false;
+                    let mut trailers = String::new();
+                    let (mut container, size) = match framing {
+                        HasBody::Len(mut size) => {
+                            let max_body_size = conn_data.server_config.max_body_size;
+                            if size > max_body_size && conn_data.server_config.truncate_body {
+                                // only the first `max_body_size` bytes are read, the
+                                // connection is closed after the response instead of
+                                // reading past the rest
+                                size = max_body_size;
+                                truncated = true;
+                            } else if size > max_body_size {
+                                // refuse on the declared length alone, before any of
+                                // the body is read
+                                conn_data.server_config.log(
+                                    LogLevel::Info,
+                                    format_args!("{}: the body is too large", peer),
+                                );
+                                reject_oversized(stream, version, &conn_data.server_config);
+                                break;
+                            }
+                            if write_continue(
+                                stream,
+                                version,
+                                &map,
+                                possible_body.is_some(),
+                                &conn_data.server_config,
+                            )
+                            .is_err()
+                            {
+                                break;
+                            }
+                            let mut container = possible_body.unwrap_or_default();
+                            container.truncate(size);
+                            (container, size)
+                        }
+                        HasBody::Chunked => {
+                            if write_continue(
+                                stream,
+                                version,
+                                &map,
+                                possible_body.is_some(),
+                                &conn_data.server_config,
+                            )
+                            .is_err()
+                            {
+                                break;
+                            }
+                            let head_rest = possible_body.unwrap_or_default();
+                            match read_chunked_body(stream, head_rest, &conn_data.server_config) {
+                                Ok((container, trailer_block)) => {
+                                    trailers = trailer_block;
+                                    let size = container.len();
+                                    (container, size)
+                                }
+                                Err(BodyContent::TooLarge) => {
+                                    conn_data.server_config.log(
+                                        LogLevel::Info,
+                                        format_args!("{}: the chunked body is too large", peer),
+                                    );
+                                    reject_oversized(stream, version, &conn_data.server_config);
+                                    break;
+                                }
+                                Err(_) => {
+                                    conn_data.server_config.log(
+                                        LogLevel::Debug,
+                                        format_args!("{}: invalid chunked body", peer),
+                                    );
+                                    reject_malformed(
+                                        stream,
+                                        "malformed chunked body",
+                                        &conn_data.server_config,
+                                    );
                                     break;
                                 }
                             }
-                        },
-                        HasBody::None => {
-                            let r = construct_http_event(
-                                &mut stream,
-                                &conn_data.router_map,
-                                method,
-                                url,
+                        }
+                        HasBody::None => (Vec::new(), 0),
+                        HasBody::Bad => {
+                            conn_data.server_config.log(
+                                LogLevel::Debug,
+                                format_args!("{}: invalid http body content", peer),
+                            );
+                            reject_malformed(
+                                stream,
+                                "invalid Content-Length or Transfer-Encoding",
+                                &conn_data.server_config,
+                            );
+                            break;
+                        }
+                        HasBody::Unsupported => {
+                            conn_data.server_config.log(
+                                LogLevel::Debug,
+                                format_args!("{}: unsupported transfer coding", peer),
+                            );
+                            let _ = write_rejection(
+                                stream,
                                 version,
-                                map,
-                                BodyContent::None,
-                                need_alive,
+                                501,
+                                "only the chunked Transfer-Encoding is supported",
+                                true,
                                 &conn_data.server_config,
                             );
-                            if need_alive && r {
-                                continue 'Back;
-                            } else {
-                                break;
-                            }
+                            linger_close(stream.tcp());
+                            break;
                         }
-                        HasBody::Bad => {
-                            if conn_data.server_config.open_log {
-                                println!("invalid http body content");
-                            }
-                            let _ = stream.shutdown(Shutdown::Both);
+                    };
+                    let mut map = map;
+                    merge_trailers(&mut map, &trailers);
+                    let reads_body = find_router(
+                        &conn_data.router_map,
+                        find_header(&map, "host"),
+                        method,
+                        url,
+                    )
+                    .is_some_and(|(route, _)| route.1.reads_body());
+                    // what such a handler leaves on the connection, skipped below
+                    let body_left = Cell::new((size - container.len()) as u64);
+                    let (body, raw_body, body_stream) = if reads_body {
+                        let streamed = StreamedBody {
+                            head: &container,
+                            head_taken: Cell::new(0),
+                            left: &body_left,
+                        };
+                        (BodyContent::None, &[][..], Some(streamed))
+                    } else if truncated {
+                        // a form cut off midway wouldn't parse, so only the
+                        // bytes are handed out
+                        let remainder = size - container.len();
+                        let (body, raw_body) = read_body_according_to_type(
+                            stream,
+                            "",
+                            &mut container,
+                            remainder,
+                            &conn_data.server_config,
+                        );
+                        (body, raw_body, None)
+                    } else {
+                        let (body, raw_body) =
+                            read_body(stream, &map, &mut container, size, &conn_data.server_config);
+                        (body, raw_body, None)
+                    };
+                    if let BodyContent::Bad = body {
+                        conn_data
+                            .server_config
+                            .log(LogLevel::Debug, format_args!("{}: invalid http body", peer));
+                        reject_malformed(
+                            stream,
+                            "malformed request body",
+                            &conn_data.server_config,
+                        );
+                        break;
+                    }
+                    if let BodyContent::TooLarge = body {
+                        reject_oversized(stream, version, &conn_data.server_config);
+                        break;
+                    }
+                    //println!("{:?}", body);
+                    let r = construct_http_event(
+                        stream,
+                        &conn_data.router_map,
+                        method,
+                        url,
+                        version,
+                        map,
+                        &head_content,
+                        body,
+                        raw_body,
+                        body_stream,
+                        truncated,
+                        need_alive && !truncated,
+                        served,
+                        &conn_data.server_config,
+                        clock,
+                        started,
+                        load,
+                    );
+                    if need_alive && !truncated && r {
+                        let left = body_left.get();
+                        if left > 0
+                            && io::copy(&mut (&mut *stream).take(left), &mut io::sink()).ok()
+                                != Some(left)
+                        {
                             break;
                         }
+                        continue 'Back;
+                    } else if truncated || body_left.get() > 0 {
+                        // as with a 413, the rest of the body is left unread
+                        linger_close(stream.tcp());
+                        break;
+                    } else {
+                        break;
                     }
                 }
                 Err(e) => {
-                    if conn_data.server_config.open_log {
-                        println!("invalid http head content:{}", ToString::to_string(&e));
+                    conn_data.server_config.log(
+                        LogLevel::Debug,
+                        format_args!("{}: invalid http head content:{}", peer, e),
+                    );
+                    if e.kind() == ErrorKind::Unsupported {
+                        let _ = write_rejection(
+                            stream,
+                            "HTTP/1.1",
+                            505,
+                            "only HTTP/1.0 and HTTP/1.1 are supported",
+                            true,
+                            &conn_data.server_config,
+                        );
+                        linger_close(stream.tcp());
+                    } else {
+                        reject_malformed(
+                            stream,
+                            "malformed request head",
+                            &conn_data.server_config,
+                        );
                     }
-                    let _ = stream.shutdown(Shutdown::Both);
                     break;
                 }
             }
         } else if let Err(e) = read_result {
-            if conn_data.server_config.open_log {
-                println!("error during reading header:{}", e.to_string());
+            conn_data.server_config.log(
+                LogLevel::Debug,
+                format_args!(
+                    "{}: error during reading header:{}",
+                    peer,
+                    e.cause.to_string()
+                ),
+            );
+            // a request was begun, so it is answered rather than reset
+            if let Some(status) = e.status {
+                let reason = match status {
+                    408 => "request head took too long",
+                    431 => "request head too large",
+                    _ => "malformed request head",
+                };
+                let _ = write_rejection(
+                    stream,
+                    "HTTP/1.1",
+                    status,
+                    reason,
+                    true,
+                    &conn_data.server_config,
+                );
+                linger_close(stream.tcp());
+            } else {
+                let _ = stream.tcp().shutdown(Shutdown::Both);
             }
-            let _ = stream.shutdown(Shutdown::Both);
             break;
         }
     }
     //println!("totally exit");
 }
 
-fn write_once(stream: &mut TcpStream, response: &mut Response) -> io::Result<()> {
+fn write_once(stream: &mut dyn ConnStream, response: &mut Response) -> io::Result<()> {
     if response.method == "HEAD" {
         let s = response.header_to_string();
-        stream.write(&s)?;
+        stream.write_all(&s)?;
         stream.flush()?;
         Ok(())
     } else {
         let mut lazy_buffs = response.take_body_buff()?;
         let s = response.header_to_string();
-        let total_len = lazy_buffs.len();
         let chunked_size = response.chunked.chunk_size;
-        let mut start = 0;
-        stream.write(&s)?;
-        loop {
-            if start >= total_len {
-                break;
-            }
-            let mut end = start + chunked_size;
-            if end > total_len {
-                end = total_len;
-            }
-            let slice = &mut lazy_buffs[start..end];
-            stream.write(slice)?;
-            start = end;
+        stream.write_all(&s)?;
+        while let Some(slice) = lazy_buffs.next_chunk(chunked_size)? {
+            stream.write_all(slice)?;
         }
         stream.flush()?;
         Ok(())
     }
 }
 
-fn write_chunk(stream: &mut TcpStream, response: &mut Response) -> io::Result<()> {
+fn write_chunk(stream: &mut dyn ConnStream, response: &mut Response) -> io::Result<()> {
     let mut lazy_buffs = response.take_body_buff()?; //修改内部状态更新header头
     let header = response.header_to_string();
-    let _ = stream.write(&header)?;
+    stream.write_all(&header)?;
     stream.flush()?;
     if response.method == "HEAD" {
         return Ok(());
     }
-    let mut start = 0;
     let chunked_size = response.chunked.chunk_size;
-    loop {
-        if start >= lazy_buffs.len() {
-            break;
+    let mut frame = Vec::new();
+    while let Some(slice) = lazy_buffs.next_chunk(chunked_size)? {
+        write_chunk_frame(stream, &mut frame, slice)?;
+        if response.chunked.flush_each {
+            stream.flush()?;
         }
-        let mut end = start + chunked_size;
-        if end > lazy_buffs.len() {
-            end = lazy_buffs.len();
-        }
-        let slice = &mut lazy_buffs[start..end];
-        let size = end - start;
-        let size = format!("{:X}", size);
-        stream.write(size.as_bytes())?;
-        stream.write(b"\r\n")?;
-        stream.write(slice)?;
-        stream.write(b"\r\n")?;
+    }
+    stream.write_all(b"0\r\n\r\n")?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Writes `data` as one chunk, size line and CRLFs included, with a single
+/// write. `frame` is reused from chunk to chunk.
+fn write_chunk_frame(
+    stream: &mut dyn ConnStream,
+    frame: &mut Vec<u8>,
+    data: &[u8],
+) -> io::Result<()> {
+    frame.clear();
+    write!(frame, "{:X}\r\n", data.len())?;
+    frame.extend_from_slice(data);
+    frame.extend_from_slice(b"\r\n");
+    stream.write_all(frame)
+}
+
+/// Writes a `BodyType::Stream` body, chunked if that was enabled and as is
+/// otherwise.
+fn write_stream(stream: &mut dyn ConnStream, response: &mut Response) -> io::Result<()> {
+    let header = response.header_to_string();
+    stream.write_all(&header)?;
+    if response.method == "HEAD" {
         stream.flush()?;
-        start = end;
+        return Ok(());
+    }
+    let chunked = response.chunked.enable;
+    let reader = match &mut response.body {
+        BodyType::Stream(reader) => reader,
+        _ => return Ok(()),
+    };
+    let mut buffs = vec![b'\0'; response.chunked.chunk_size];
+    let mut frame = Vec::new();
+    loop {
+        let size = match reader.read(&mut buffs) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if chunked {
+            write_chunk_frame(stream, &mut frame, &buffs[..size])?;
+            if response.chunked.flush_each {
+                stream.flush()?;
+            }
+        } else {
+            stream.write_all(&buffs[..size])?;
+        }
+    }
+    if chunked {
+        stream.write_all(b"0\r\n\r\n")?;
     }
-    stream.write(b"0\r\n\r\n")?;
     stream.flush()?;
     Ok(())
 }
@@ -436,23 +1752,69 @@ fn find_double_crlf(slice: &[u8]) -> (bool, i64) {
         .windows(double_crlf.len())
         .position(|v| v == double_crlf)
     {
-        Some(pos) => {
-            return (true, pos as i64);
-        }
-        None => {
-            return (false, -1);
+        Some(pos) => (true, pos as i64),
+        None => (false, -1),
+    }
+}
+
+// buffers bigger than this are freed rather than kept for the next request
+const MAX_POOLED_BUFFER: usize = 1 << 20;
+const MAX_POOLED_BUFFERS: usize = 4;
+
+thread_local! {
+    // a worker serves one connection at a time, so the buffers it reads
+    // into can be reused from request to request instead of allocated
+    static READ_BUFFERS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An empty scratch buffer from the thread's pool, given back on drop.
+struct PooledBuffer(Vec<u8>);
+
+impl PooledBuffer {
+    fn take() -> Self {
+        let buffer = READ_BUFFERS.with(|pool| pool.borrow_mut().pop());
+        PooledBuffer(buffer.unwrap_or_default())
+    }
+}
+
+impl std::ops::Deref for PooledBuffer {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut buffer = std::mem::take(&mut self.0);
+        if buffer.capacity() > MAX_POOLED_BUFFER {
+            return;
         }
+        buffer.clear();
+        // `try_with`, the thread may be going away
+        let _ = READ_BUFFERS.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_POOLED_BUFFERS {
+                pool.push(buffer);
+            }
+        });
     }
 }
 
-fn read_http_head(
-    stream: &mut TcpStream,
-    server_config: &ServerConfig,
-) -> Result<(String, Option<Vec<u8>>), Box<dyn UnifiedError>> {
-    let mut read_buffs = Vec::new();
+fn read_http_head(stream: &mut dyn ConnStream, server_config: &ServerConfig) -> HeadResult {
+    let mut read_buffs = PooledBuffer::take();
     read_buffs.resize(server_config.read_buff_increase_size, b'\0');
     let mut total_read_size = 0;
     let mut start_read_pos = 0;
+    // from the first byte on, so an idle kept-alive connection isn't cut
+    // short while one dribbling its head a byte at a time is
+    let mut first_byte_at = None;
 
     loop {
         match stream.read(&mut read_buffs[start_read_pos..]) {
@@ -461,8 +1823,9 @@ fn read_http_head(
                 if read_size == 0 {
                     let info = format!("file:{}, line: {}, lost connection", file!(), line!());
                     let e = io::Error::new(io::ErrorKind::InvalidInput, info);
-                    return Err(Box::new(e));
+                    return Err(head_error(total_read_size, 400, e));
                 }
+                let started = *first_byte_at.get_or_insert_with(Instant::now);
                 total_read_size += read_size;
                 let slice = &read_buffs[..total_read_size];
                 let r = find_double_crlf(slice);
@@ -480,17 +1843,28 @@ fn read_http_head(
                         }
                         Err(e) => {
                             //println!("{:#?}",&read_buffs[..pos]);
-                            return Err(Box::new(e));
+                            return Err(head_error(total_read_size, 400, e));
                         }
                     }
                 } else {
+                    // checked only while the head is incomplete, one that
+                    // ends in this read is on time
+                    let limit = server_config.header_timeout as u64;
+                    if limit != 0 && started.elapsed() > Duration::from_millis(limit) {
+                        let e =
+                            io::Error::new(io::ErrorKind::TimedOut, "request head took too long");
+                        return Err(head_error(total_read_size, 408, e));
+                    }
                     if total_read_size > server_config.max_header_size {
                         let e = io::Error::new(io::ErrorKind::InvalidData, "header too large");
-                        return Err(Box::new(e));
+                        return Err(head_error(total_read_size, 431, e));
                     }
                     start_read_pos = total_read_size;
-                    let len = read_buffs.len();
-                    read_buffs.resize(len + server_config.read_buff_increase_size, b'\0');
+                    // grown only once full, a read may bring a single byte
+                    if start_read_pos == read_buffs.len() {
+                        let len = read_buffs.len();
+                        read_buffs.resize(len + server_config.read_buff_increase_size, b'\0');
+                    }
                     continue;
                 }
             }
@@ -500,142 +1874,353 @@ fn read_http_head(
                 // 	println!("{:?},{}",read_buffs.len(),start_read_pos);
                 // 	panic!()
                 // }
-                return Err(Box::new(e));
+                // a client that stopped halfway through its head timed out
+                let status = match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => 408,
+                    _ => 400,
+                };
+                return Err(head_error(total_read_size, status, e));
             }
         }
     }
 }
 
-fn parse_header(
-    head_content: &mut String,
-) -> io::Result<(&str, &str, &str, HashMap<&'_ str, &'_ str>)> {
+fn head_error(read_size: usize, status: u16, cause: impl UnifiedError + 'static) -> HeadError {
+    HeadError {
+        status: (read_size > 0).then_some(status),
+        cause: Box::new(cause),
+    }
+}
+
+fn parse_header(head_content: &str) -> io::Result<(&str, &str, &str, HashMap<&'_ str, &'_ str>)> {
     let mut head_map = HashMap::new();
-    match head_content.find("\r\n") {
-        Some(pos) => {
-            let url = &head_content[..pos];
-            //println!("url:{}",url);
-            let url_result: Vec<&str> = url
-                .split(" ")
-                .map(|item| {
-                    let i = item.trim();
-                    i
-                })
-                .collect();
-            // head_map.insert("method", url_result[0]);
-            // head_map.insert("url", url_result[1]);
-            // head_map.insert("http_version", url_result[2]);
-
-            let substr = &head_content[pos + 2..];
-            let result = substr.split("\r\n");
-            for item in result {
-                match item.split_once(":") {
-                    Some((key, value)) => {
-                        head_map.insert(key.trim(), value.trim());
-                    }
-                    None => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "invalid k/v pair in head",
-                        ));
-                    }
+    // a bare request line without any header field is valid, e.g. from an
+    // HTTP/1.0 client
+    let (url, substr) = head_content
+        .split_once("\r\n")
+        .unwrap_or((head_content, ""));
+    //println!("url:{}",url);
+    let url_result: Vec<&str> = url
+        .split(" ")
+        .map(|item| {
+            let i = item.trim();
+            i
+        })
+        .collect();
+    // head_map.insert("method", url_result[0]);
+    // head_map.insert("url", url_result[1]);
+    // head_map.insert("http_version", url_result[2]);
+
+    if !substr.is_empty() {
+        let result = substr.split("\r\n");
+        for item in result {
+            match item.split_once(":") {
+                Some((key, value)) => {
+                    head_map.insert(key.trim(), value.trim());
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid k/v pair in head",
+                    ));
                 }
-                //head_map.insert(String::from(pair[0]),pair[1]);
             }
-            //println!("{:#?}", head_map);
-            // method, url, version,header_pairs
-            Ok((url_result[0], url_result[1], url_result[2], head_map))
+            //head_map.insert(String::from(pair[0]),pair[1]);
         }
-        None => Err(io::Error::new(
+    }
+    //println!("{:#?}", head_map);
+    if url_result.len() != 3 {
+        return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            "invalid Header:No METHOD URL VERSION\\r\\n",
-        )),
+            "invalid request line",
+        ));
+    }
+    // only 1.x is spoken here, a request line with e.g. `HTTP/2.0` gets a
+    // 505 while one that names no HTTP version at all is malformed
+    let version = url_result[2];
+    if let HttpVersion::Other(_) = HttpVersion::parse(version) {
+        let is_http = version
+            .get(..5)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("HTTP/"));
+        return Err(if is_http {
+            io::Error::new(io::ErrorKind::Unsupported, "unsupported http version")
+        } else {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid http version")
+        });
     }
+    // method, url, version,header_pairs
+    Ok((url_result[0], url_result[1], url_result[2], head_map))
 }
 
-fn invoke_router(result: &RouterValue, req: &Request, res: &mut Response) {
-    let router = &result.1;
-    match &result.0 {
-        Some(middlewares) => {
-            // at least one middleware
-            let mut r = true;
-            for middleware in middlewares {
-                if !middleware.call(req, res) {
-                    r = false;
-                    break;
-                }
-            }
-            if r {
-                router.call(req, res);
+/// Runs the middleware chain and, unless one of them returned `false`, the
+/// router itself, then the after-middlewares in reverse order whether or
+/// not the router ran. The return value tells whether the router was
+/// reached; either way the caller goes on to finalize and send whatever is
+/// in `res`, so a short-circuited request takes the same send path as a
+/// handled one.
+fn invoke_router(result: &RouterValue, req: &Request, res: &mut Response) -> bool {
+    let (middlewares, router, after_middlewares, _) = result;
+    let mut reached = true;
+    if let Some(middlewares) = middlewares {
+        for (index, middleware) in middlewares.iter().enumerate() {
+            res.middleware_trace.executed.push(index);
+            if !middleware.call(req, res) {
+                res.middleware_trace.short_circuited = Some(index);
+                reached = false;
+                break;
             }
         }
-        None => {
-            // there is no middleware
-            router.call(req, res);
+    }
+    if reached {
+        router.call(req, res);
+    }
+    if let Some(after_middlewares) = after_middlewares {
+        for after in after_middlewares.iter().rev() {
+            after.call(req, res);
         }
     }
+    reached
 }
 
-fn do_router(router: &RouterMap, req: &Request, res: &mut Response) {
-    let url = req.url.split_once("?");
-    let url = match url {
+/// The `:name` segments of a parameterized route with what the request
+/// path had in their place.
+pub(crate) type PathParams<'u> = Vec<(String, &'u str)>;
+
+fn find_route<'r, 'u>(
+    router: &'r RouteTable,
+    method: &str,
+    url: &'u str,
+) -> Option<(&'r RouterValue, PathParams<'u>)> {
+    let url = match url.split_once("?") {
         Some((url, _)) => url,
-        None => req.url,
+        None => url,
+    };
+    // a GET route answers HEAD too unless HEAD has one of its own, the body
+    // it writes is left out when sending. Each kind of route is tried for
+    // both before the next, so HEAD is answered by the route GET would take
+    let methods: &[&str] = if method == "HEAD" {
+        &["HEAD", "GET"]
+    } else {
+        &[method]
     };
-    let key = format!("{}{}", req.method, url);
-    //println!("{key}");
-    match router.get(&key) {
-        Some(result) => {
-            invoke_router(result, req, res);
+    for m in methods {
+        //println!("{key}");
+        if let Some(result) = router.get(&format!("{}{}", m, url)) {
+            return Some((result, Vec::new()));
         }
-        None => {
-            // may be wildcard
-            let r = router.keys().find(|&k| -> bool {
-                let last = k.len() - 1;
-                if &k[last..] == "*" {
-                    if key.len() > last - 1 {
-                        if &k[..last - 1] == &key[..last - 1] {
-                            true
-                        } else {
-                            false
-                        }
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            });
-            match r {
-                Some(k) => {
-                    let wild_router = router.get(k).unwrap();
-                    invoke_router(wild_router, req, res);
+    }
+    for m in methods {
+        if let Some((k, params)) = match_path_params(router, m, url) {
+            return Some((router.get(k).unwrap(), params));
+        }
+    }
+    // the longest wildcard of either, HEAD's on a tie
+    let mut best: Option<(usize, &String)> = None;
+    for m in methods {
+        if let Some((len, k)) = match_wildcard(router, &format!("{}{}", m, url)) {
+            if best.is_none_or(|(best_len, _)| len - m.len() > best_len) {
+                best = Some((len - m.len(), k));
+            }
+        }
+    }
+    best.map(|(_, k)| (router.get(k).unwrap(), Vec::new()))
+}
+
+/// The wildcard route for `key` (method and path), `/a/*` takes `/a` and
+/// anything under `/a/`, and the longest such prefix wins so `/a/b/*` is
+/// preferred for `/a/b/c`. Comes with the length of that prefix.
+fn match_wildcard<'r>(router: &'r RouteTable, key: &str) -> Option<(usize, &'r String)> {
+    router
+        .keys()
+        .filter_map(|k| {
+            let base = k.strip_suffix('*')?;
+            let base = base.strip_suffix('/').unwrap_or(base);
+            let under = key
+                .strip_prefix(base)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+            if under {
+                Some((base.len(), k))
+            } else {
+                None
+            }
+        })
+        .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1)))
+}
+
+/// Matches `url` against the routes with `:name` segments, e.g.
+/// `/user/:id`. When several fit, the one whose literal segments come first
+/// wins, so `/user/me` beats `/user/:id` and `/user/:id/posts` beats
+/// `/:kind/:id/posts`.
+fn match_path_params<'r, 'u>(
+    router: &'r RouteTable,
+    method: &str,
+    url: &'u str,
+) -> Option<(&'r String, PathParams<'u>)> {
+    let segments: Vec<&str> = url.split('/').collect();
+    let mut best: Option<(Vec<bool>, &String, PathParams)> = None;
+    for k in router.keys() {
+        let pattern = match k.strip_prefix(method) {
+            Some(pattern) if pattern.starts_with('/') && pattern.contains("/:") => pattern,
+            _ => continue,
+        };
+        let pattern: Vec<&str> = pattern.split('/').collect();
+        if pattern.len() != segments.len() {
+            continue;
+        }
+        let mut literal = Vec::with_capacity(pattern.len());
+        let mut params = Vec::new();
+        let fits = pattern
+            .iter()
+            .zip(&segments)
+            .all(|(&p, &s)| match p.strip_prefix(':') {
+                Some(name) if !s.is_empty() => {
+                    literal.push(false);
+                    params.push((name.to_string(), s));
+                    true
                 }
+                Some(_) => false,
                 None => {
-                    let not_found = router.get("NEVER_FOUND_FOR_ALL").unwrap();
-                    not_found.1.call(req, res);
+                    literal.push(true);
+                    p == s
                 }
-            }
-            // match router.get(&key) {
-            //     Some(result) => {
-            //         invoke_router(result, req, res);
-            //     }
-            //     None => {
-            //         // actually have not this router
-            //         let not_found = router.get("NEVER_FOUND_FOR_ALL").unwrap();
-            //         not_found.1.call(req, res);
-            //     }
-            // }
+            });
+        if fits && best.as_ref().is_none_or(|(b, _, _)| literal > *b) {
+            best = Some((literal, k, params));
+        }
+    }
+    best.map(|(_, k, params)| (k, params))
+}
+
+/// The routes for a `Host` header value. Without virtual hosts, or without a
+/// `Host` at all, that is the default table; `None` means the request names
+/// a host that isn't served here.
+fn host_routes<'r>(router: &'r RouterMap, host: Option<&str>) -> Option<&'r RouteTable> {
+    let default = router.get(DEFAULT_HOST).unwrap();
+    let host = match host {
+        Some(host) if router.len() > 1 => host,
+        _ => return Some(default),
+    };
+    // strip the port, minding bracketed IPv6 literals
+    let name = match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[..pos],
+        _ => host,
+    };
+    router.get(&name.to_lowercase())
+}
+
+/// Looks up the route for a request, `None` when only the not-found or
+/// unknown-host handler would take it. A virtual host falls back to the
+/// default routes for paths it doesn't register itself, and so does a host
+/// no virtual host is registered for.
+fn find_router<'r, 'u>(
+    router: &'r RouterMap,
+    host: Option<&str>,
+    method: &str,
+    url: &'u str,
+) -> Option<(&'r RouterValue, PathParams<'u>)> {
+    let default = router.get(DEFAULT_HOST).unwrap();
+    let routes = host_routes(router, host).unwrap_or(default);
+    find_route(routes, method, url).or_else(|| {
+        if std::ptr::eq(routes, default) {
+            None
+        } else {
+            find_route(default, method, url)
+        }
+    })
+}
+
+/// The methods some route answers `url` with, for the `Allow` header of a
+/// 405. Keys are the method followed by the path, so the method is what
+/// comes before the first `/`.
+fn allowed_methods(router: &RouterMap, host: Option<&str>, url: &str) -> Vec<String> {
+    let default = router.get(DEFAULT_HOST).unwrap();
+    let tables = host_routes(router, host).into_iter().chain(Some(default));
+    // each method once, however many routes it has, before any is looked up
+    let registered: HashSet<&str> = tables
+        .flat_map(|routes| routes.keys())
+        .filter_map(|k| k.find('/').map(|pos| &k[..pos]))
+        .collect();
+    let mut methods: Vec<String> = registered
+        .into_iter()
+        .filter(|method| find_router(router, host, method, url).is_some())
+        .map(String::from)
+        .collect();
+    if methods.iter().any(|m| m == "GET") && !methods.iter().any(|m| m == "HEAD") {
+        methods.push(String::from("HEAD"));
+    }
+    methods.sort();
+    methods
+}
+
+// never echoed by TRACE, even when enabled
+const TRACE_HIDDEN: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+/// TRACE echoes the request head back as `message/http`. That makes it a
+/// way to read headers a script should not see (cross-site tracing), so the
+/// server refuses it with 405 unless `HttpServer::enable_trace` was called,
+/// and even then leaves the credentials out.
+fn trace_echo(router: &RouterMap, req: &Request, res: &mut Response, enabled: bool) {
+    if !enabled {
+        let allowed = allowed_methods(router, req.get_header("Host"), req.url);
+        res.write_string("TRACE is not allowed").status(405);
+        res.add_header(
+            String::from("Content-Type"),
+            String::from("text/plain; charset=utf-8"),
+        );
+        res.add_header(String::from("Allow"), allowed.join(", "));
+        return;
+    }
+    let mut echo = format!("{} {} {}\r\n", req.method, req.url, req.version);
+    for (k, v) in req.header_pair.iter() {
+        if TRACE_HIDDEN.iter().any(|h| k.eq_ignore_ascii_case(h)) {
+            continue;
         }
+        echo.push_str(&format!("{}: {}\r\n", k, v));
     }
+    echo.push_str("\r\n");
+    res.write_string(&echo).status(200);
+    res.add_header(String::from("Content-Type"), String::from("message/http"));
+}
+
+/// Routes the request, see `invoke_router`. Hands back the matched route's
+/// hooks for `construct_http_event` to run once the response is final.
+fn do_router<'r>(
+    router: &'r RouterMap,
+    req: &mut Request,
+    res: &mut Response,
+) -> Option<&'r AfterWareVec> {
+    let host = req.get_header("Host");
+    let result = match find_router(router, host, req.method, req.url) {
+        Some((result, params)) => {
+            req.path_params = params;
+            result
+        }
+        None => {
+            let default = router.get(DEFAULT_HOST).unwrap();
+            if host_routes(router, host).is_none() {
+                default.get("UNKNOWN_HOST_FOR_ALL").unwrap()
+            } else {
+                let allowed = allowed_methods(router, host, req.url);
+                if !allowed.is_empty() {
+                    res.write_state(405);
+                    res.add_header(String::from("Allow"), allowed.join(", "));
+                    return None;
+                }
+                default.get("NEVER_FOUND_FOR_ALL").unwrap()
+            }
+        }
+    };
+    invoke_router(result, req, res);
+    result.3.as_ref()
 }
 
-fn read_body<'a, 'b, 'c>(
-    stream: &mut TcpStream,
-    head_map: &HashMap<&'a str, &'b str>,
+fn read_body<'c>(
+    stream: &mut dyn ConnStream,
+    head_map: &HashMap<&str, &str>,
     body: &'c mut Vec<u8>,
     len: usize,
     server_config: &ServerConfig,
-) -> BodyContent<'c> {
+) -> (BodyContent<'c>, &'c [u8]) {
     if len > 0 {
         let body_type_key = head_map.keys().find(|&&k| -> bool {
             if k.to_lowercase() == "content-type" {
@@ -653,26 +2238,141 @@ fn read_body<'a, 'b, 'c>(
                     let remainder = len - has_read_len;
                     //println!("neee size, {}", remainder);
                     //println!("need to read out the remainder body content");
-                    return read_body_according_to_type(
-                        stream,
-                        body_type,
-                        body,
-                        remainder,
-                        server_config,
-                    );
+                    read_body_according_to_type(stream, body_type, body, remainder, server_config)
                 } else {
                     // body has completely read out when reading head
                     //println!("body has completely read out when reading head");
-                    return read_body_according_to_type(stream, body_type, body, 0, server_config);
+                    read_body_according_to_type(stream, body_type, body, 0, server_config)
                 }
             }
             None => {
                 //invalid body
-                return BodyContent::Bad;
+                (BodyContent::Bad, &[])
             }
         }
     } else {
-        return BodyContent::None;
+        (BodyContent::None, &[])
+    }
+}
+
+/// Reads one CRLF terminated line of the chunked framing, a byte at a time
+/// so nothing past the body is taken off the stream.
+fn read_chunk_line(reader: &mut dyn Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let mut byte = [b'\0'; 1];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() >= max_len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "chunk line too long",
+            ));
+        }
+        line.push(byte[0]);
+    }
+    match line.pop() {
+        Some(b'\r') => Ok(line),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "chunk line without CRLF",
+        )),
+    }
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, starting with the part of it
+/// that arrived along with the head. The trailer lines come back as they
+/// were sent, CRLF separated, for `merge_trailers`. The error is
+/// `BodyContent::TooLarge` past `max_body_size`, otherwise `Bad`.
+fn read_chunked_body(
+    stream: &mut dyn ConnStream,
+    head_rest: Vec<u8>,
+    server_config: &ServerConfig,
+) -> Result<(Vec<u8>, String), BodyContent<'static>> {
+    let mut reader = io::Cursor::new(head_rest).chain(stream);
+    let max_line = server_config.max_header_size;
+    let mut body = Vec::new();
+    loop {
+        let line = read_chunk_line(&mut reader, max_line).map_err(|_| BodyContent::Bad)?;
+        // chunk extensions after `;` carry nothing we use
+        let size = match line.split(|&b| b == b';').next().map(std::str::from_utf8) {
+            Some(Ok(size)) => {
+                usize::from_str_radix(size.trim(), 16).map_err(|_| BodyContent::Bad)?
+            }
+            _ => return Err(BodyContent::Bad),
+        };
+        if size == 0 {
+            break;
+        }
+        if size > server_config.max_body_size.saturating_sub(body.len()) {
+            return Err(BodyContent::TooLarge);
+        }
+        let start = body.len();
+        body.resize(start + size, b'\0');
+        reader
+            .read_exact(&mut body[start..])
+            .map_err(|_| BodyContent::Bad)?;
+        let mut crlf = [b'\0'; 2];
+        match reader.read_exact(&mut crlf) {
+            Ok(_) if &crlf == b"\r\n" => {}
+            _ => return Err(BodyContent::Bad),
+        }
+    }
+    let mut trailers = Vec::new();
+    loop {
+        let line = read_chunk_line(&mut reader, max_line).map_err(|_| BodyContent::Bad)?;
+        if line.is_empty() {
+            break;
+        }
+        if !trailers.is_empty() {
+            trailers.extend_from_slice(b"\r\n");
+        }
+        trailers.extend_from_slice(&line);
+        if trailers.len() > server_config.max_header_size {
+            return Err(BodyContent::Bad);
+        }
+    }
+    let trailers = String::from_utf8(trailers).map_err(|_| BodyContent::Bad)?;
+    Ok((body, trailers))
+}
+
+/// Adds the trailer fields the request announced in its `Trailer` header to
+/// `head_map`. Anything else is ignored, as are fields the head already has
+/// and those that must not come in a trailer, such as the framing ones.
+fn merge_trailers<'h>(head_map: &mut HashMap<&'h str, &'h str>, trailers: &'h str) {
+    let declared: Vec<String> = match find_header(head_map, "trailer") {
+        Some(v) => v
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect(),
+        None => return,
+    };
+    for line in trailers.split("\r\n") {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        let lower = key.to_ascii_lowercase();
+        let forbidden = matches!(
+            lower.as_str(),
+            "content-length"
+                | "transfer-encoding"
+                | "trailer"
+                | "host"
+                | "content-type"
+                | "content-encoding"
+                | "content-range"
+                | "authorization"
+                | "cookie"
+                | "expect"
+                | "connection"
+        );
+        if forbidden || !declared.contains(&lower) || find_header(head_map, key).is_some() {
+            continue;
+        }
+        head_map.insert(key, value);
     }
 }
 
@@ -683,12 +2383,12 @@ fn read_body<'a, 'b, 'c>(
 // }
 
 fn read_body_according_to_type<'a>(
-    stream: &mut TcpStream,
+    stream: &mut dyn ConnStream,
     body_type: &str,
     container: &'a mut Vec<u8>,
     mut need_read_size: usize,
     server_config: &ServerConfig,
-) -> BodyContent<'a> {
+) -> (BodyContent<'a>, &'a [u8]) {
     //println!("raw:{body_type}");
     let tp = body_type.to_lowercase();
     if !tp.contains("multipart/form-data") {
@@ -699,7 +2399,7 @@ fn read_body_according_to_type<'a>(
             let total_len = len + need_read_size;
 
             if total_len > server_config.max_body_size {
-                return BodyContent::TooLarge;
+                return (BodyContent::TooLarge, &[]);
             }
             container.resize(total_len, b'\0');
             let mut start_pos = len;
@@ -707,14 +2407,14 @@ fn read_body_according_to_type<'a>(
                 match stream.read(&mut container[start_pos..]) {
                     Ok(read_size) => {
                         if read_size == 0 {
-                            return BodyContent::Bad;
+                            return (BodyContent::Bad, &[]);
                         }
                         //println!("read size is:{}",read_size);
                         need_read_size -= read_size;
                         start_pos += read_size;
                     }
                     Err(_) => {
-                        return BodyContent::Bad;
+                        return (BodyContent::Bad, &[]);
                     }
                 }
                 //println!("{}",need_read_size);
@@ -723,41 +2423,40 @@ fn read_body_according_to_type<'a>(
                 }
             }
         }
-        if tp != "application/x-www-form-urlencoded" {
-            match std::str::from_utf8(&container[..]) {
-                Ok(s) => {
-                    return BodyContent::PureText(s);
-                }
-                Err(_) => {
-                    return BodyContent::Bad;
-                }
+        // done writing, the raw bytes are handed out next to the parsed body
+        let container: &'a [u8] = container;
+        let body = if tp != "application/x-www-form-urlencoded" {
+            match std::str::from_utf8(container) {
+                Ok(s) => BodyContent::PureText(s),
+                Err(_) => BodyContent::Bad,
             }
         } else {
-            return parse_url_form_body(container);
-        }
+            parse_url_form_body(container)
+        };
+        (body, container)
     } else {
-        // parse multiple form data
+        // parse multiple form data, the files go to disk so there are no raw
+        // bytes to keep
         let split = body_type.split_once(";");
-        match split {
+        let body = match split {
             Some((_, boundary)) => match boundary.trim().split_once("=") {
                 Some((_, boundary)) => {
                     let boundary = format!("--{}", boundary.trim());
                     //println!("boundary: {}", boundary);
                     let end_boundary = format!("{}--", &boundary);
                     //println!("end boundary {}",end_boundary);
-                    if container.len() == 0 {
+                    if container.is_empty() {
                         //读头时没有读到body
                         let divider_len = boundary.len() + 2; // include --Boundary\r\n
                         container.resize(divider_len, b'\0');
-                        match stream.read_exact(container) {
-                            Ok(_) => {
-                                need_read_size -= divider_len;
-                            }
+                        match read_body_exact(stream, &mut need_read_size, container) {
+                            Ok(_) => {}
                             Err(e) => {
-                                if server_config.open_log {
-                                    println!("{}", ToString::to_string(&e));
-                                }
-                                return BodyContent::Bad;
+                                server_config.log(
+                                    LogLevel::Debug,
+                                    format_args!("{}: {}", peer_addr(stream.tcp()), e),
+                                );
+                                return (BodyContent::Bad, &[]);
                             }
                         }
                     }
@@ -769,46 +2468,38 @@ fn read_body_according_to_type<'a>(
                         server_config,
                     );
                     match r {
-                        Ok(form) => {
-                            return BodyContent::Multi(form);
-                        }
+                        Ok(form) => BodyContent::Multi(form),
                         Err(e) => {
-                            if server_config.open_log {
-                                println!("{}", ToString::to_string(&e));
-                            }
-                            return BodyContent::Bad;
+                            server_config.log(
+                                LogLevel::Debug,
+                                format_args!("{}: {}", peer_addr(stream.tcp()), e),
+                            );
+                            BodyContent::Bad
                         }
                     }
                 }
-                None => return BodyContent::Bad,
+                None => BodyContent::Bad,
             },
-            None => return BodyContent::Bad,
-        }
-    };
+            None => BodyContent::Bad,
+        };
+        (body, &[])
+    }
 }
 
-fn parse_url_form_body(container: &mut Vec<u8>) -> BodyContent<'_> {
-    match std::str::from_utf8(&container[..]) {
+fn parse_url_form_body(container: &[u8]) -> BodyContent<'_> {
+    match std::str::from_utf8(container) {
         Ok(s) => {
             let t: HashMap<&str, &str> = s
                 .split("&")
-                .map(|x| match x.split_once("=").map(|(a, b)| (a, b)) {
+                .map(|x| match x.split_once("=") {
                     Some((k, v)) => (k, v),
                     None => ("", ""),
                 })
-                .filter(|(k, v)| {
-                    if k.len() == 0 || v.len() == 0 {
-                        false
-                    } else {
-                        true
-                    }
-                })
+                .filter(|(k, v)| !(k.is_empty() || v.is_empty()))
                 .collect();
-            return BodyContent::UrlForm(t);
-        }
-        Err(_) => {
-            return BodyContent::Bad;
+            BodyContent::UrlForm(t)
         }
+        Err(_) => BodyContent::Bad,
     }
 }
 
@@ -850,7 +2541,7 @@ fn find_substr_once(slice: &[u8], sub: &[u8], start: usize) -> FindSet {
         if compare_str == sub {
             FindSet {
                 find_pos: start as i64,
-                end_pos: end_pos,
+                end_pos,
             }
         } else {
             FindSet {
@@ -863,22 +2554,17 @@ fn find_substr_once(slice: &[u8], sub: &[u8], start: usize) -> FindSet {
 
 fn is_file(slice: &[u8]) -> bool {
     let key = "filename=\"".as_bytes();
-    match slice.windows(key.len()).position(|x| x == key) {
-        Some(_) => true,
-        None => false,
-    }
+    slice.windows(key.len()).position(|x| x == key).is_some()
 }
 
 fn parse_file_content_type(slice: &[u8]) -> (&str, &str) {
     //println!("571 {}",std::str::from_utf8(slice).unwrap());
-    let end = slice.len() - 4;
-    let s = std::str::from_utf8(&slice[..end]).unwrap_or_else(|_| "");
+    let end = slice.len().saturating_sub(4);
+    let s = std::str::from_utf8(&slice[..end]).unwrap_or("");
     //println!("572 {s}");
     match s.split_once(":") {
-        Some((k, v)) => {
-            return (k, v.trim());
-        }
-        None => return ("", ""),
+        Some((k, v)) => (k, v.trim()),
+        None => ("", ""),
     }
 }
 
@@ -889,41 +2575,61 @@ fn get_file_extension(s: &str) -> &str {
     }
 }
 
-fn get_config_from_disposition(s: &str, is_file: bool) -> (String, Option<String>) {
+fn get_config_from_disposition(s: &str, is_file: bool) -> Option<(String, Option<String>)> {
     //println!("file disposition: {}", s);
     let name = "name=\"";
-    let r = match s.find(name) {
-        Some(pos) => {
-            let pos = pos + name.len();
-            let name_end = "\"";
-            match s[pos..].find(name_end) {
-                Some(pos_end) => (String::from(&s[pos..pos + pos_end]), pos_end),
-                None => todo!(),
-            }
-        }
-        None => todo!(),
-    };
+    let pos = s.find(name)? + name.len();
+    let name_end = "\"";
+    let pos_end = s[pos..].find(name_end)?;
+    let r = (String::from(&s[pos..pos + pos_end]), pos_end);
     if is_file {
         let file_name_key = "filename=\"";
         let bias = r.1 + 2;
-        let filename = match s[bias..].find(file_name_key) {
-            Some(pos) => {
-                let pos = bias + pos + file_name_key.len();
-                let end = "\"";
-                match s[pos..].find(end) {
-                    Some(end) => String::from(&s[pos..pos + end]),
-                    None => todo!(),
-                }
-            }
-            None => todo!(),
-        };
-        return (r.0, Some(filename));
+        let pos = bias + s.get(bias..)?.find(file_name_key)? + file_name_key.len();
+        let end = "\"";
+        let end = s[pos..].find(end)?;
+        let filename = String::from(&s[pos..pos + end]);
+        return Some((r.0, Some(filename)));
+    }
+    Some((r.0, None))
+}
+
+/// Reads the next bytes of a form body into `buf`, never past the
+/// `need_size` bytes of it still on the stream, so a malformed form can't
+/// read into the request after it. 0 once the body is all read.
+fn read_body_part(
+    stream: &mut dyn ConnStream,
+    need_size: &mut usize,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let max = buf.len().min(*need_size);
+    if max == 0 {
+        return Ok(0);
     }
-    return (r.0, None);
+    let size = stream.read(&mut buf[..max])?;
+    *need_size -= size;
+    Ok(size)
+}
+
+/// Fills `buf` from the form body, failing if the body ends first.
+fn read_body_exact(
+    stream: &mut dyn ConnStream,
+    need_size: &mut usize,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    if buf.len() > *need_size {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "multipart body ends early",
+        ));
+    }
+    stream.read_exact(buf)?;
+    *need_size -= buf.len();
+    Ok(())
 }
 
 fn contains_substr(
-    stream: &mut TcpStream,
+    stream: &mut dyn ConnStream,
     need_size: &mut usize,
     body_slice: &mut Vec<u8>,
     pat: &[u8],
@@ -959,9 +2665,8 @@ fn contains_substr(
             body_slice.resize(start_read_pos + need, b'\0');
             //let mut buff = vec![b'\0'; need];
 
-            match stream.read_exact(&mut body_slice[start_read_pos..]) {
+            match read_body_exact(stream, need_size, &mut body_slice[start_read_pos..]) {
                 Ok(_) => {
-                    *need_size -= need;
                     // let mut complete = Vec::new();
                     // complete.extend_from_slice(may_sub_slice);
                     // complete.extend_from_slice(&buff);
@@ -983,21 +2688,50 @@ fn contains_substr(
             }
         }
     }
-    return io::Result::Ok(FindSet {
+    io::Result::Ok(FindSet {
         find_pos: -1,
         end_pos: 0,
-    });
+    })
 }
 
 fn read_multiple_form_body<'a>(
-    stream: &mut TcpStream,
+    stream: &mut dyn ConnStream,
+    body: &'a mut Vec<u8>,
+    boundaries: (&String, &String),
+    need_size: usize,
+    server_config: &ServerConfig,
+) -> io::Result<HashMap<String, MultipleFormData<'a>>> {
+    let mut created = Vec::new();
+    let r = read_form_parts(
+        stream,
+        body,
+        boundaries,
+        need_size,
+        server_config,
+        &mut created,
+    );
+    if r.is_err() {
+        // no request gets to see the form, so whatever went to disk before
+        // the error, even a complete earlier file, is removed
+        for path in created {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    r
+}
+
+/// Parses the form, writing its files to the upload directory; the path of
+/// each file is pushed to `created` as soon as it exists.
+fn read_form_parts<'a>(
+    stream: &mut dyn ConnStream,
     body: &'a mut Vec<u8>,
     (boundary, end): (&String, &String),
     mut need_size: usize,
     server_config: &ServerConfig,
+    created: &mut Vec<String>,
 ) -> io::Result<HashMap<String, MultipleFormData<'a>>> {
     let mut state = 0;
-    let mut buffs = Vec::new();
+    let mut buffs = PooledBuffer::take();
     buffs.extend_from_slice(body);
     let crlf_sequence = b"\r\n";
     let boundary_sequence = boundary.as_bytes();
@@ -1024,13 +2758,11 @@ fn read_multiple_form_body<'a>(
                 let r = contains_substr(stream, &mut need_size, &mut buffs, boundary_sequence, 0)?; // 确保找到boundary_sequence
 
                 if r.find_pos != -1 {
-                    let mut subsequent = Vec::new();
                     let start = r.end_pos as usize + 2; //--Boundary?? 跳过?? 有可能是\r\n
                     if start > buffs.len() {
                         let mut buff_two = [b'\0'; 2];
-                        match stream.read_exact(&mut buff_two) {
+                        match read_body_exact(stream, &mut need_size, &mut buff_two) {
                             Ok(_) => {
-                                need_size -= 2;
                                 buffs.extend_from_slice(&buff_two);
                             }
                             Err(e) => {
@@ -1045,8 +2777,7 @@ fn read_multiple_form_body<'a>(
                         //确定是否是完全结束的分隔符，如果对--Boundary 和--Boundary--分别进行查找，如果他们起始位置一致，那么就是结尾符
                         break 'Outer;
                     }
-                    subsequent.extend_from_slice(&buffs[start..]);
-                    buffs = subsequent;
+                    buffs.drain(..start);
                     state = 1;
                     continue 'Outer;
                 } else {
@@ -1070,7 +2801,7 @@ fn read_multiple_form_body<'a>(
                             start_read_pos + server_config.read_buff_increase_size,
                             b'\0',
                         );
-                        match stream.read(&mut buffs[start_read_pos..]) {
+                        match read_body_part(stream, &mut need_size, &mut buffs[start_read_pos..]) {
                             Ok(size) => {
                                 if size == 0 {
                                     let info = format!(
@@ -1081,7 +2812,6 @@ fn read_multiple_form_body<'a>(
                                     let e = io::Error::new(io::ErrorKind::InvalidInput, info);
                                     return io::Result::Err(e);
                                 }
-                                need_size -= size;
                                 buffs.resize(start_read_pos + size, b'\0');
                             }
                             Err(e) => {
@@ -1099,13 +2829,11 @@ fn read_multiple_form_body<'a>(
                         //println!("是文本内容");
                         // 是文本内容
 
-                        let mut subsequent = Vec::new();
                         text_only_sequence.extend_from_slice(boundary_sequence);
                         text_only_sequence.extend_from_slice(b"\r\n");
                         text_only_sequence.extend_from_slice(content_disposition);
 
-                        subsequent.extend_from_slice(&buffs[content_disposition_end..]); // 移除content_disposition的内容
-                        buffs = subsequent;
+                        buffs.drain(..content_disposition_end); // 移除content_disposition的内容
 
                         let mut find_boundary = FindSet {
                             find_pos: -1,
@@ -1127,7 +2855,11 @@ fn read_multiple_form_body<'a>(
                                     start_read_pos + server_config.read_buff_increase_size,
                                     b'\0',
                                 );
-                                match stream.read(&mut buffs[start_read_pos..]) {
+                                match read_body_part(
+                                    stream,
+                                    &mut need_size,
+                                    &mut buffs[start_read_pos..],
+                                ) {
                                     Ok(size) => {
                                         if size == 0 {
                                             let info = format!(
@@ -1141,7 +2873,6 @@ fn read_multiple_form_body<'a>(
                                         }
                                         //buffs.extend_from_slice(&buff[..size]);
                                         buffs.resize(start_read_pos + size, b'\0');
-                                        need_size -= size;
                                     }
                                     Err(e) => {
                                         return io::Result::Err(e);
@@ -1154,32 +2885,31 @@ fn read_multiple_form_body<'a>(
                             let text_slice = &buffs[..start];
                             text_only_sequence.extend_from_slice(text_slice);
 
-                            let mut subsequent = Vec::new();
-                            subsequent.extend_from_slice(&buffs[start..]);
-
-                            buffs = subsequent;
+                            buffs.drain(..start);
                             state = 0;
                             continue 'Outer;
                         }
                     } else {
                         //文件
-                        let s = std::str::from_utf8(content_disposition).unwrap();
-                        let config = get_config_from_disposition(s, true);
-                        let filename = config.1.unwrap();
+                        let bad_disposition =
+                            || io::Error::new(ErrorKind::InvalidData, "bad Content-Disposition");
+                        let s = std::str::from_utf8(content_disposition)
+                            .map_err(|_| bad_disposition())?;
+                        let config =
+                            get_config_from_disposition(s, true).ok_or_else(bad_disposition)?;
+                        let filename = config.1.ok_or_else(bad_disposition)?;
                         let uid = uuid::Uuid::new_v4().to_string();
                         let extension = get_file_extension(&filename);
                         let filepath =
                             format!("{}/{}{}", &server_config.upload_directory, uid, extension);
                         let mut file = MultipleFormFile {
-                            filename: filename,
-                            filepath: filepath,
+                            filename,
+                            filepath,
                             content_type: String::new(),
                             form_indice: config.0,
                         };
 
-                        let mut subsequent = Vec::new();
-                        subsequent.extend_from_slice(&buffs[content_disposition_end..]); // 移除content_disposition的内容
-                        buffs = subsequent;
+                        buffs.drain(..content_disposition_end); // 移除content_disposition的内容
                         let double_crlf = b"\r\n\r\n";
 
                         let mut find_double_crlf = FindSet {
@@ -1201,7 +2931,11 @@ fn read_multiple_form_body<'a>(
                                     start_read_pos + server_config.read_buff_increase_size,
                                     b'\0',
                                 );
-                                match stream.read(&mut buffs[start_read_pos..]) {
+                                match read_body_part(
+                                    stream,
+                                    &mut need_size,
+                                    &mut buffs[start_read_pos..],
+                                ) {
                                     Ok(size) => {
                                         if size == 0 {
                                             let info = format!(
@@ -1215,7 +2949,6 @@ fn read_multiple_form_body<'a>(
                                         }
                                         //buffs.extend_from_slice(&buff[..size]);
                                         buffs.resize(start_read_pos + size, b'\0');
-                                        need_size -= size;
                                     }
                                     Err(e) => {
                                         return io::Result::Err(e);
@@ -1227,19 +2960,24 @@ fn read_multiple_form_body<'a>(
                         if find_double_crlf.find_pos != -1 {
                             // Content-type:...\r\n\r\n
                             let content_type = &buffs[..find_double_crlf.end_pos];
-                            let result = parse_file_content_type(&content_type);
+                            let result = parse_file_content_type(content_type);
                             file.content_type = result.1.to_string();
-                            let mut subsequent = Vec::new();
-                            subsequent.extend_from_slice(&buffs[find_double_crlf.end_pos..]); // 移除content-type:...\r\n\r\n
-                            buffs = subsequent;
+                            buffs.drain(..find_double_crlf.end_pos); // 移除content-type:...\r\n\r\n
 
-                            let mut file_handle = OpenOptions::new()
+                            // the name is a fresh uuid, so there is never an old
+                            // file to truncate; it is spelled out all the same
+                            let file_handle = OpenOptions::new()
                                 .write(true)
                                 .create(true)
-                                .open(file.filepath.clone())
-                                .unwrap();
+                                .truncate(true)
+                                .open(file.filepath.clone())?;
+                            created.push(file.filepath.clone());
+                            // the loop below hands over a few bytes at a time
+                            let mut file_handle = io::BufWriter::with_capacity(
+                                server_config.upload_write_buffer,
+                                file_handle,
+                            );
 
-                            let file_path = file.filepath.clone();
                             multiple_data_collection
                                 .insert(file.form_indice.clone(), MultipleFormData::File(file));
 
@@ -1251,10 +2989,10 @@ fn read_multiple_form_body<'a>(
                                 //以\r为关键字判断是否是文件内容的一部分还是分隔符的一部分
                                 if find_cr.find_pos == -1 {
                                     //如果整个字节串里没有\r, 那么一定都是文件内容
-                                    file_handle.write(&buffs).unwrap();
+                                    file_handle.write_all(&buffs)?;
                                     //buffs.clear();
                                     buffs.resize(server_config.read_buff_increase_size, b'\0');
-                                    match stream.read(&mut buffs[0..]) {
+                                    match read_body_part(stream, &mut need_size, &mut buffs[0..]) {
                                         Ok(size) => {
                                             if size == 0 {
                                                 let info = format!(
@@ -1266,18 +3004,13 @@ fn read_multiple_form_body<'a>(
                                                     io::ErrorKind::InvalidInput,
                                                     info,
                                                 );
-                                                drop(file_handle);
-                                                let _ = std::fs::remove_file(file_path);
                                                 return io::Result::Err(e);
                                             }
-                                            need_size -= size;
                                             buffs.resize(size, b'\0');
                                             //buffs.clear();
                                             //buffs.extend_from_slice(&file_buff[..size]);
                                         }
                                         Err(e) => {
-                                            drop(file_handle);
-                                            let _ = std::fs::remove_file(file_path);
                                             return io::Result::Err(e);
                                         }
                                     }
@@ -1298,18 +3031,15 @@ fn read_multiple_form_body<'a>(
                                                 );
                                                 if find_test.find_pos != -1 {
                                                     //如果\r\n是分隔符
-                                                    file_handle.write(&buffs[0..pos]).unwrap();
+                                                    file_handle.write_all(&buffs[0..pos])?;
+                                                    file_handle.flush()?;
                                                     state = 0;
-                                                    let mut temp = Vec::new();
-                                                    temp.extend_from_slice(&buffs[pos + 2..]); //找\r\n--Boundary, 跳过\r\n
-                                                    buffs = temp;
+                                                    buffs.drain(..pos + 2); //找\r\n--Boundary, 跳过\r\n
                                                     continue 'Outer;
                                                 } else {
                                                     //\r\n不是形成分隔符的关键字，那么他们就是文件内容的一部分
-                                                    file_handle.write(&buffs[0..=pos + 1]).unwrap();
-                                                    let mut temp = Vec::new();
-                                                    temp.extend_from_slice(&buffs[pos + 2..]);
-                                                    buffs = temp;
+                                                    file_handle.write_all(&buffs[0..=pos + 1])?;
+                                                    buffs.drain(..pos + 2);
                                                     continue;
                                                 }
                                             } else {
@@ -1322,7 +3052,11 @@ fn read_multiple_form_body<'a>(
                                                         + server_config.read_buff_increase_size,
                                                     b'\0',
                                                 );
-                                                match stream.read(&mut buffs[start_read_pos..]) {
+                                                match read_body_part(
+                                                    stream,
+                                                    &mut need_size,
+                                                    &mut buffs[start_read_pos..],
+                                                ) {
                                                     //继续读一部分内容以进行拼凑比较
                                                     Ok(size) => {
                                                         if size == 0 {
@@ -1331,11 +3065,8 @@ fn read_multiple_form_body<'a>(
                                                                 io::ErrorKind::InvalidInput,
                                                                 info,
                                                             );
-                                                            drop(file_handle);
-                                                            let _ = std::fs::remove_file(file_path);
                                                             return io::Result::Err(e);
                                                         }
-                                                        need_size -= size;
                                                         buffs.resize(start_read_pos + size, b'\0');
                                                         //buffs.extend_from_slice(&need_buff[..size]);
                                                         let r = find_substr_once(
@@ -1347,53 +3078,44 @@ fn read_multiple_form_body<'a>(
                                                             //拼凑后\r\n形成了分隔符
                                                             let pos = r.find_pos as usize;
                                                             file_handle
-                                                                .write(&buffs[0..pos])
-                                                                .unwrap();
+                                                                .write_all(&buffs[0..pos])?;
+                                                            file_handle.flush()?;
                                                             state = 0;
-                                                            let mut temp = Vec::new();
-                                                            temp.extend_from_slice(
-                                                                &buffs[pos + 2..],
-                                                            ); //找\r\n--Boundary, 跳过\r\n
-                                                            buffs = temp;
+                                                            buffs.drain(..pos + 2); //找\r\n--Boundary, 跳过\r\n
                                                             continue 'Outer;
                                                         } else {
                                                             //拼凑后发现\r\n不是形成分隔符的关键字，那么\r\n就是文件内容的一部分
                                                             file_handle
-                                                                .write(&buffs[0..=pos + 1])
-                                                                .unwrap();
-                                                            let mut temp = Vec::new();
+                                                                .write_all(&buffs[0..=pos + 1])?;
                                                             //\r\n是文件内容，所以从\n后面开始
-                                                            temp.extend_from_slice(
-                                                                &buffs[pos + 2..],
-                                                            );
-                                                            buffs = temp;
+                                                            buffs.drain(..pos + 2);
                                                             continue;
                                                         }
                                                     }
                                                     Err(e) => {
-                                                        drop(file_handle);
-                                                        let _ = std::fs::remove_file(file_path);
                                                         return io::Result::Err(e);
                                                     }
                                                 }
                                             }
                                         } else {
                                             //\r的下一个字节不是\n, 那么可以肯定\r是文件的内容
-                                            file_handle.write(&buffs[0..=pos]).unwrap();
-                                            let mut temp = Vec::new();
-                                            temp.extend_from_slice(&buffs[pos + 1..]); //从\r的下一个字节开始
-                                            buffs = temp;
+                                            file_handle.write_all(&buffs[0..=pos])?;
+                                            buffs.drain(..pos + 1); //从\r的下一个字节开始
                                             continue;
                                         }
                                     } else {
                                         // \r正好是buffs里面的最后一个字节，那么只能确定0~前一个字节是文件内容
-                                        file_handle.write(&buffs[0..pos]).unwrap();
+                                        file_handle.write_all(&buffs[0..pos])?;
                                         //buffs.clear();
                                         buffs.resize(server_config.read_buff_increase_size, b'\0');
                                         buffs[0] = b'\r';
                                         //println!("{},{}",buffs.len(),pos);
                                         //let mut temp_buff = [b'\0'; 1024];
-                                        match stream.read(&mut buffs[1..]) {
+                                        match read_body_part(
+                                            stream,
+                                            &mut need_size,
+                                            &mut buffs[1..],
+                                        ) {
                                             Ok(size) => {
                                                 if size == 0 {
                                                     let info = format!(
@@ -1405,21 +3127,16 @@ fn read_multiple_form_body<'a>(
                                                         io::ErrorKind::InvalidInput,
                                                         info,
                                                     );
-                                                    drop(file_handle);
-                                                    let _ = std::fs::remove_file(file_path);
                                                     return io::Result::Err(e);
                                                 }
                                                 //let mut temp = Vec::new();
                                                 //temp.extend_from_slice(&buffs[pos..]);
-                                                need_size -= size;
                                                 buffs.resize(1 + size, b'\0');
                                                 //temp.extend_from_slice(&temp_buff[..size]);
                                                 //buffs = temp;
                                                 continue;
                                             }
                                             Err(e) => {
-                                                drop(file_handle);
-                                                let _ = std::fs::remove_file(file_path);
                                                 return io::Result::Err(e);
                                             }
                                         }
@@ -1434,35 +3151,45 @@ fn read_multiple_form_body<'a>(
         }
     }
     if need_size != 0 {
-        let mut buff = [b'\0'; 10]; //充其量没有之前的循环中没有读 --end_boundary--?? ??两个字节
-        match stream.read(&mut buff) {
-            Ok(_) => {}
-            Err(e) => return io::Result::Err(e),
-        }
+        //充其量没有之前的循环中没有读 --end_boundary--?? ??两个字节
+        // whatever is left of the declared body is consumed here, not just
+        // what a single read returns, or the rest would be taken for the
+        // next request on a kept-alive connection
+        io::copy(&mut (&mut *stream).take(need_size as u64), &mut io::sink())?;
     }
 
     body.clear();
     body.extend_from_slice(&text_only_sequence);
     let mut pat = Vec::new();
-    pat.extend_from_slice(&boundary_sequence);
+    pat.extend_from_slice(boundary_sequence);
     pat.extend_from_slice(b"\r\n");
 
     match std::str::from_utf8(&pat) {
         Ok(pat) => match std::str::from_utf8(body) {
             Ok(s) => {
                 for el in s.split(pat) {
-                    if el == "" {
+                    if el.is_empty() {
                         continue;
                     }
                     let r = el.split_once("\r\n\r\n");
                     //let r = r.unwrap();
                     match r {
                         Some(r) => {
-                            let name = get_config_from_disposition(r.0, false);
-                            let text_len = r.1.len();
-                            multiple_data_collection
-                                .insert(name.0, MultipleFormData::Text(&r.1[0..text_len - 2]));
                             //处理文本时, 包含了分隔符的\r\n，在这里去除
+                            let text = r.1.strip_suffix("\r\n");
+                            match (get_config_from_disposition(r.0, false), text) {
+                                (Some(name), Some(text)) => {
+                                    multiple_data_collection
+                                        .insert(name.0, MultipleFormData::Text(text));
+                                }
+                                _ => {
+                                    let e = io::Error::new(
+                                        ErrorKind::InvalidData,
+                                        "bad body with unknown format multipart form",
+                                    );
+                                    return io::Result::Err(e);
+                                }
+                            }
                         }
                         None => {
                             let e = io::Error::new(
@@ -1473,16 +3200,16 @@ fn read_multiple_form_body<'a>(
                         }
                     }
                 }
-                return io::Result::Ok(multiple_data_collection);
+                io::Result::Ok(multiple_data_collection)
             }
             Err(_) => {
                 let e = io::Error::new(ErrorKind::InvalidData, "bad body with invalid utf8");
-                return io::Result::Err(e);
+                io::Result::Err(e)
             }
         },
         Err(_) => {
             let e = io::Error::new(ErrorKind::InvalidData, "bad body with invalid utf8");
-            return io::Result::Err(e);
+            io::Result::Err(e)
         }
     }
 }
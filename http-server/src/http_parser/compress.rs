@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use flate2::read::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+use super::connection::{BodyType, HttpVersion, Response, ResponseRangeMeta};
+use super::{accepts_coding, vary_on_accept_encoding};
+
+/// Which bodies get compressed, see `HttpServer::enable_compression`. The
+/// default takes any size and type, as `ResponseConfig::compress` does for a
+/// single response.
+#[derive(Clone, Default)]
+pub struct CompressionConfig {
+    pub(super) min_size: usize,
+    // lowercase media types without parameters, empty for any type
+    pub(super) content_types: Vec<String>,
+}
+
+impl CompressionConfig {
+    pub(crate) fn new(min_size: usize, content_types: &[&str]) -> Self {
+        CompressionConfig {
+            min_size,
+            content_types: content_types
+                .iter()
+                .map(|t| t.trim().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    fn allows(&self, content_type: Option<&str>) -> bool {
+        let media_type = content_type
+            .map(|v| {
+                v.split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .to_ascii_lowercase()
+            })
+            .unwrap_or_default();
+        if already_compressed(&media_type) {
+            return false;
+        }
+        self.content_types.is_empty() || self.content_types.contains(&media_type)
+    }
+}
+
+/// Types whose payload is compressed already, so another pass only costs
+/// CPU. `image/svg+xml` is text and stays eligible.
+fn already_compressed(media_type: &str) -> bool {
+    if media_type == "image/svg+xml" {
+        return false;
+    }
+    media_type.starts_with("image/")
+        || media_type.starts_with("video/")
+        || media_type.starts_with("audio/")
+        || matches!(
+            media_type,
+            "application/zip"
+                | "application/gzip"
+                | "application/x-gzip"
+                | "application/x-7z-compressed"
+                | "application/x-rar-compressed"
+                | "application/x-bzip2"
+                | "application/x-xz"
+                | "application/zstd"
+                | "application/pdf"
+                | "font/woff"
+                | "font/woff2"
+        )
+}
+
+#[derive(Clone, Copy)]
+enum Coding {
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    fn name(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    // `deflate` in HTTP means the zlib format, not a raw deflate stream
+    fn encode(self, reader: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        match self {
+            Coding::Gzip => Box::new(GzEncoder::new(reader, Compression::default())),
+            Coding::Deflate => Box::new(ZlibEncoder::new(reader, Compression::default())),
+        }
+    }
+}
+
+fn header_value<'h>(map: &'h HashMap<String, String>, key: &str) -> Option<&'h str> {
+    map.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Picks gzip, then deflate, from what the client accepts.
+fn negotiate(request_header: &HashMap<&str, &str>) -> Option<Coding> {
+    if accepts_coding(request_header, "gzip") {
+        Some(Coding::Gzip)
+    } else if accepts_coding(request_header, "deflate") {
+        Some(Coding::Deflate)
+    } else {
+        None
+    }
+}
+
+/// File extensions of formats that are compressed already, checked besides
+/// the `Content-Type` since that may be missing or set by hand for a file.
+fn compressed_extension(path: &str) -> bool {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    matches!(
+        extension.as_str(),
+        "gz" | "tgz"
+            | "zip"
+            | "7z"
+            | "rar"
+            | "bz2"
+            | "xz"
+            | "zst"
+            | "br"
+            | "png"
+            | "jpg"
+            | "jpeg"
+            | "gif"
+            | "webp"
+            | "avif"
+            | "mp3"
+            | "mp4"
+            | "ogg"
+            | "webm"
+            | "pdf"
+            | "woff"
+            | "woff2"
+    )
+}
+
+/// Compresses the body of `response` when the client accepts gzip or
+/// deflate and `config` allows it. In-memory bodies, files and chunked
+/// streams qualify; a range has to keep its byte offsets, and a stream with
+/// a fixed length can't be resized without buffering it. A file is encoded
+/// while it is read out, so it goes chunked, and an HTTP/1.0 client that
+/// can't read chunks gets it as is.
+pub(super) fn compress_response(response: &mut Response, config: &CompressionConfig) {
+    if !matches!(response.range, ResponseRangeMeta::None)
+        || matches!(response.http_state, 204 | 206 | 304)
+        || header_value(&response.header_pair, "Content-Encoding").is_some()
+        || !config.allows(header_value(&response.header_pair, "Content-Type"))
+    {
+        return;
+    }
+    let known_len = match &response.body {
+        BodyType::Memory(buff) => Some(buff.len() as u64),
+        BodyType::File(path)
+            if !compressed_extension(path)
+                && HttpVersion::parse(response.version) != HttpVersion::Http10 =>
+        {
+            match std::fs::metadata(path) {
+                Ok(meta) => Some(meta.len()),
+                Err(_) => return,
+            }
+        }
+        BodyType::Stream(_) if response.chunked.enable => None,
+        _ => return,
+    };
+    if let Some(len) = known_len {
+        if len < config.min_size as u64 {
+            return;
+        }
+    }
+    // varies even when this client gets no coding, for caches
+    vary_on_accept_encoding(response);
+    let coding = match negotiate(&response.request_header) {
+        Some(coding) => coding,
+        None => return,
+    };
+    let body = std::mem::replace(&mut response.body, BodyType::None);
+    response.body = match body {
+        BodyType::Memory(buff) if response.chunked.enable => {
+            // compressed as the chunks are read out, see `write_stream`
+            BodyType::Stream(coding.encode(Box::new(Cursor::new(buff))))
+        }
+        BodyType::Memory(buff) => {
+            let mut compressed = Vec::new();
+            // nothing but the in-memory source can fail here
+            coding
+                .encode(Box::new(Cursor::new(buff)))
+                .read_to_end(&mut compressed)
+                .expect("compressing an in-memory body");
+            response.remove_header(String::from("Content-length"));
+            response.add_header(String::from("Content-length"), compressed.len().to_string());
+            BodyType::Memory(compressed)
+        }
+        BodyType::File(path) => match File::open(&path) {
+            Ok(file) => {
+                response.remove_header(String::from("Content-length"));
+                // as `ResponseConfig::chunked` does, a HEAD answer has no framing
+                if response.method != "HEAD" && !response.chunked.enable {
+                    response.add_header(String::from("Transfer-Encoding"), String::from("chunked"));
+                    response.chunked.enable = true;
+                }
+                BodyType::Stream(coding.encode(Box::new(file)))
+            }
+            Err(_) => {
+                response.body = BodyType::File(path);
+                return;
+            }
+        },
+        BodyType::Stream(reader) => BodyType::Stream(coding.encode(reader)),
+        other => other,
+    };
+    response.add_header(
+        String::from("Content-Encoding"),
+        String::from(coding.name()),
+    );
+}
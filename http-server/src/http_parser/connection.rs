@@ -1,20 +1,32 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use super::stream::ConnStream;
+use super::{find_header, LogLevel, PathParams, ServerLogger};
+use conditional::PreconditionResult;
+use std::any::{Any, TypeId};
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::io::Read;
-use std::net::TcpStream;
+use websocket::WebSocket;
 
-use std::ops::{Deref, DerefMut, Index, IndexMut, Range};
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use std::ffi::OsStr;
 use std::io;
 use std::io::prelude::*;
 
+pub mod conditional;
+pub mod cookie;
+pub(crate) mod date;
 pub mod mime;
+pub mod websocket;
 
 pub mod http_response_table {
-    const STATE_TABLE: [(u16, &str); 20] = [
-        (101, "101 Switching Protocals\r\n"),
+    use std::borrow::Cow;
+
+    const STATE_TABLE: [(u16, &str); 42] = [
+        (100, "100 Continue\r\n"),
+        (101, "101 Switching Protocols\r\n"),
         (200, "200 OK\r\n"),
         (201, "201 Created\r\n"),
         (202, "202 Accepted\r\n"),
@@ -23,23 +35,46 @@ pub mod http_response_table {
         (300, "300 Multiple Choices\r\n"),
         (301, "301 Moved Permanently\r\n"),
         (302, "302 Moved Temporarily\r\n"),
+        (303, "303 See Other\r\n"),
         (304, "304 Not Modified\r\n"),
+        (307, "307 Temporary Redirect\r\n"),
+        (308, "308 Permanent Redirect\r\n"),
         (400, "400 Bad Request\r\n"),
         (401, "401 Unauthorized\r\n"),
         (403, "403 Forbidden\r\n"),
         (404, "404 Not Found\r\n"),
+        (405, "405 Method Not Allowed\r\n"),
+        (406, "406 Not Acceptable\r\n"),
+        (408, "408 Request Timeout\r\n"),
+        (409, "409 Conflict\r\n"),
+        (410, "410 Gone\r\n"),
+        (411, "411 Length Required\r\n"),
+        (412, "412 Precondition Failed\r\n"),
         (413, "413 Request Entity Too Large\r\n"),
+        (414, "414 URI Too Long\r\n"),
+        (415, "415 Unsupported Media Type\r\n"),
         (416, "416 Requested Range Not Satisfiable\r\n"),
+        (417, "417 Expectation Failed\r\n"),
+        (418, "418 I'm a teapot\r\n"),
+        (422, "422 Unprocessable Entity\r\n"),
+        (426, "426 Upgrade Required\r\n"),
+        (429, "429 Too Many Requests\r\n"),
+        (431, "431 Request Header Fields Too Large\r\n"),
+        (451, "451 Unavailable For Legal Reasons\r\n"),
         (500, "500 Internal Server Error\r\n"),
         (501, "501 Not Implemented\r\n"),
         (502, "502 Bad Gateway\r\n"),
         (503, "503 Service Unavailable\r\n"),
+        (504, "504 Gateway Timeout\r\n"),
+        (505, "505 HTTP Version Not Supported\r\n"),
     ];
 
-    pub(super) fn get_httpstatus_from_code(code: u16) -> &'static str {
+    pub(crate) fn get_httpstatus_from_code(code: u16) -> Cow<'static, str> {
         match STATE_TABLE.binary_search_by_key(&code, |&(k, _)| k) {
-            Ok(index) => STATE_TABLE[index].1,
-            Err(_) => panic!("not supporting such a http state code"),
+            Ok(index) => Cow::Borrowed(STATE_TABLE[index].1),
+            // the reason phrase is optional, the code alone is still a valid
+            // status line
+            Err(_) => Cow::Owned(format!("{} \r\n", code)),
         }
     }
 
@@ -70,31 +105,41 @@ pub mod http_response_table {
         }
     }
 }
+/// Values shared by every handler, one per type.
+pub(crate) type AppState = HashMap<TypeId, Arc<dyn Any + Send + Sync>>;
+
 pub struct Request<'a> {
-    pub(super) header_pair: HashMap<&'a str, &'a str>,
+    // shared with the response, see `Response::request_header`
+    pub(super) header_pair: Rc<HashMap<&'a str, &'a str>>,
     pub(super) url: &'a str,
     pub(super) method: &'a str,
     pub(super) version: &'a str,
+    // the head as received, without the blank line ending it
+    pub(super) raw_head: &'a str,
     pub(super) body: BodyContent<'a>,
-    pub(super) conn_: Rc<RefCell<&'a mut TcpStream>>,
+    pub(super) raw_body: &'a [u8],
+    pub(super) body_truncated: bool,
+    // left to the handler, see `Router::reads_body`
+    pub(super) body_stream: Option<StreamedBody<'a>>,
+    // 1 for the first request on the connection, 2 for the next kept-alive one
+    pub(super) request_index: u32,
+    pub(super) path_params: PathParams<'a>,
+    // values middlewares hand to the router, one per type
+    pub(super) extensions: RefCell<HashMap<TypeId, Box<dyn Any>>>,
+    // the server's, see `HttpServer::set_state`
+    pub(super) state: &'a AppState,
+    pub(super) conn_: Rc<RefCell<&'a mut (dyn ConnStream + 'static)>>,
 }
 
 impl<'a> Request<'a> {
     pub fn get_header(&self, key: &str) -> Option<&str> {
-        let r = self.header_pair.keys().find(|&&ik| {
-            if ik.to_lowercase() == key.to_lowercase() {
-                true
-            } else {
-                false
-            }
-        });
+        let r = self
+            .header_pair
+            .keys()
+            .find(|&&ik| ik.to_lowercase() == key.to_lowercase());
         match r {
-            Some(r) => {
-                return Some(self.header_pair.get(*r).unwrap());
-            }
-            None => {
-                return None;
-            }
+            Some(r) => Some(self.header_pair.get(*r).unwrap()),
+            None => None,
         }
     }
     pub fn get_param(&self, k: &str) -> Option<&str> {
@@ -102,13 +147,10 @@ impl<'a> Request<'a> {
             Some((_, v)) => {
                 let r = v.split("&");
                 for e in r {
-                    match e.split_once("=") {
-                        Some((ik, iv)) => {
-                            if ik == k {
-                                return Some(iv);
-                            }
+                    if let Some((ik, iv)) = e.split_once("=") {
+                        if ik == k {
+                            return Some(iv);
                         }
-                        None => {}
                     }
                 }
                 None
@@ -117,73 +159,145 @@ impl<'a> Request<'a> {
         }
     }
 
-    pub fn get_params(&self)->Option<HashMap<&str,&str>> {
+    /// The user name and password of an `Authorization: Basic` header.
+    /// Credentials that don't decode, or have no `:` between the two, are
+    /// the same as none.
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let credentials = self.auth_param("Basic")?;
+        let decoded = crate::auth::decode_base64(credentials)?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some((user.to_string(), password.to_string()))
+    }
+
+    /// The token of an `Authorization: Bearer` header.
+    pub fn bearer_token(&self) -> Option<&str> {
+        self.auth_param("Bearer")
+    }
+
+    // the scheme is case-insensitive
+    fn auth_param(&self, scheme: &str) -> Option<&str> {
+        let (name, param) = self.get_header("Authorization")?.trim().split_once(' ')?;
+        if !name.eq_ignore_ascii_case(scheme) {
+            return None;
+        }
+        let param = param.trim();
+        if param.is_empty() {
+            None
+        } else {
+            Some(param)
+        }
+    }
+
+    /// The segment of the path that a `:name` in the route stood for, e.g.
+    /// `id` for a route `/user/:id`. It is taken as is, not percent-decoded.
+    pub fn get_path_param(&self, name: &str) -> Option<&str> {
+        self.path_params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| *v)
+    }
+
+    /// Stores `value` for the middlewares and the router that run after,
+    /// e.g. the user an auth middleware found. A value of the same type set
+    /// earlier is replaced.
+    pub fn set_ext<T: 'static>(&self, value: T) {
+        self.extensions
+            .borrow_mut()
+            .insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    /// The value of type `T` stored with `set_ext`. Drop the returned guard
+    /// before calling `set_ext` again.
+    pub fn get_ext<T: 'static>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.extensions.borrow(), |map| {
+            map.get(&TypeId::of::<T>())
+                .and_then(|value| value.downcast_ref::<T>())
+        })
+        .ok()
+    }
+
+    /// The value of type `S` given to `HttpServer::set_state`, e.g. a
+    /// database pool or the configuration.
+    pub fn state<S: Send + Sync + 'static>(&self) -> Option<&S> {
+        self.state
+            .get(&TypeId::of::<S>())
+            .and_then(|value| value.downcast_ref::<S>())
+    }
+
+    pub fn get_params(&self) -> Option<HashMap<&str, &str>> {
         match self.url.split_once("?") {
             Some((_, v)) => {
                 let r = v.split("&");
-				let mut map = HashMap::new();
+                let mut map = HashMap::new();
                 for e in r {
-                    match e.split_once("=") {
-                        Some((ik, iv)) => {
-							map.insert(ik, iv);
-                        }
-                        None => {}
+                    if let Some((ik, iv)) = e.split_once("=") {
+                        map.insert(ik, iv);
                     }
                 }
-                if map.len() == 0{
-					None
-				}else{
-					Some(map)
-				}
+                if map.is_empty() {
+                    None
+                } else {
+                    Some(map)
+                }
             }
             None => None,
         }
     }
 
     pub fn get_headers(&self) -> HashMap<&str, &str> {
-        self.header_pair.clone()
+        (*self.header_pair).clone()
+    }
+
+    /// The headers worth forwarding to an upstream server, with lowercase
+    /// names. Hop-by-hop headers are left out: the standard ones and any
+    /// named by the request's `Connection` header.
+    pub fn header_map_lowercased(&self) -> HashMap<String, String> {
+        const HOP_BY_HOP: [&str; 9] = [
+            "connection",
+            "keep-alive",
+            "proxy-connection",
+            "proxy-authenticate",
+            "proxy-authorization",
+            "te",
+            "trailer",
+            "transfer-encoding",
+            "upgrade",
+        ];
+        let listed: Vec<String> = self
+            .get_header("Connection")
+            .map(|v| v.split(',').map(|t| t.trim().to_lowercase()).collect())
+            .unwrap_or_default();
+        self.header_pair
+            .iter()
+            .map(|(k, v)| (k.to_lowercase(), v.to_string()))
+            .filter(|(k, _)| !HOP_BY_HOP.contains(&k.as_str()) && !listed.contains(k))
+            .collect()
     }
     pub fn get_version(&self) -> &str {
         self.version
     }
+    pub fn http_version(&self) -> HttpVersion {
+        HttpVersion::parse(self.version)
+    }
     pub fn get_query(&self, k: &str) -> Option<&str> {
         if let BodyContent::UrlForm(x) = &self.body {
-            let r = x.keys().find(|&&ik| {
-                if ik.to_lowercase() == k.to_lowercase() {
-                    true
-                } else {
-                    false
-                }
-            });
+            let r = x.keys().find(|&&ik| ik.to_lowercase() == k.to_lowercase());
             match r {
-                Some(r) => {
-                    return Some(x.get(*r).unwrap());
-                }
-                None => {
-                    return None;
-                }
+                Some(r) => Some(x.get(*r).unwrap()),
+                None => None,
             }
         } else if let BodyContent::Multi(x) = &self.body {
-            let r = x.keys().find(|&ik| {
-                if ik.to_lowercase() == k.to_lowercase() {
-                    true
-                } else {
-                    false
-                }
-            });
+            let r = x.keys().find(|&ik| ik.to_lowercase() == k.to_lowercase());
             match r {
                 Some(s) => {
                     let v = x.get(s).unwrap();
                     match v {
-                        MultipleFormData::Text(v) => {
-                            return Some(*v);
-                        }
-                        MultipleFormData::File(_) => return None,
+                        MultipleFormData::Text(v) => Some(*v),
+                        MultipleFormData::File(_) => None,
                     }
                 }
-                None => {
-                    return None;
-                }
+                None => None,
             }
         } else {
             None
@@ -192,23 +306,17 @@ impl<'a> Request<'a> {
 
     pub fn get_file(&self, k: &str) -> Option<&'_ MultipleFormFile> {
         if let BodyContent::Multi(x) = &self.body {
-            let r = x.keys().find(|&ik| {
-                if k.to_lowercase() == ik.to_lowercase() {
-                    true
-                } else {
-                    false
-                }
-            });
+            let r = x.keys().find(|&ik| k.to_lowercase() == ik.to_lowercase());
             match r {
                 Some(s) => {
                     let item = x.get(s).unwrap();
                     if let MultipleFormData::File(file) = item {
-                        return Some(file);
+                        Some(file)
                     } else {
-                        return None;
+                        None
                     }
                 }
-                None => return None,
+                None => None,
             }
         } else {
             None
@@ -227,10 +335,10 @@ impl<'a> Request<'a> {
                     MultipleFormData::File(_) => {}
                 }
             }
-            if v.len() != 0 {
-                return Some(v);
+            if !v.is_empty() {
+                Some(v)
             } else {
-                return None;
+                None
             }
         } else {
             None
@@ -239,7 +347,7 @@ impl<'a> Request<'a> {
     pub fn get_files(&self) -> Option<Vec<&MultipleFormFile>> {
         if let BodyContent::Multi(x) = &self.body {
             let mut vec = Vec::new();
-            for (_k, v) in x {
+            for v in x.values() {
                 match v {
                     MultipleFormData::Text(_) => {}
                     MultipleFormData::File(file) => {
@@ -247,10 +355,10 @@ impl<'a> Request<'a> {
                     }
                 }
             }
-            if vec.len() != 0 {
-                return Some(vec);
+            if !vec.is_empty() {
+                Some(vec)
             } else {
-                return None;
+                None
             }
         } else {
             None
@@ -264,49 +372,85 @@ impl<'a> Request<'a> {
         }
     }
 
-    pub fn has_body(&self) -> bool {
-        if let BodyContent::None = self.body {
-            false
+    /// Copies the body out as it arrived, for handlers that need to keep it
+    /// past the request. `None` for a multipart body, whose files are already
+    /// on disk, or when there is no body.
+    pub fn body_bytes(&self) -> Option<Vec<u8>> {
+        if self.raw_body.is_empty() {
+            None
         } else {
-            true
+            Some(self.raw_body.to_vec())
         }
     }
 
-    pub fn get_conn(&self) -> Rc<RefCell<&'a mut TcpStream>> {
+    /// Whether the body was cut off at `max_body_size`, see
+    /// `HttpServer::set_truncate_oversized_body`. A cut off body is only
+    /// available as `plain_body` or `body_bytes`, forms aren't parsed.
+    pub fn body_is_truncated(&self) -> bool {
+        self.body_truncated
+    }
+
+    pub fn has_body(&self) -> bool {
+        !matches!(self.body, BodyContent::None) || self.body_stream.is_some()
+    }
+
+    /// The body as it arrives, for a router whose `reads_body` is true,
+    /// `None` for any other. Whatever the handler leaves unread is skipped
+    /// before the next request on the connection.
+    pub fn body_reader(&self) -> Option<BodyReader<'_, 'a>> {
+        self.body_stream.as_ref().map(|body| BodyReader {
+            body,
+            conn_: &self.conn_,
+        })
+    }
+
+    pub fn get_conn(&self) -> Rc<RefCell<&'a mut (dyn ConnStream + 'static)>> {
         Rc::clone(&self.conn_)
     }
 
     pub fn get_method(&self) -> &str {
         self.method
     }
+
+    /// Which request this is on its connection, starting at 1.
+    pub fn connection_request_index(&self) -> u32 {
+        self.request_index
+    }
     pub fn get_url(&self) -> &str {
         self.url
     }
+
+    /// The request line and header fields exactly as they arrived, e.g. to
+    /// log what a client really sent. The blank line ending the head is not
+    /// included.
+    pub fn raw_head(&self) -> &str {
+        self.raw_head
+    }
 }
 
 pub struct ResponseConfig<'b, 'a> {
     res: &'b mut Response<'a>,
-    has_failure:bool
+    has_failure: bool,
 }
 
 impl<'b, 'a> ResponseConfig<'b, 'a> {
     fn get_map_key(map: &HashMap<String, String>, key: &str) -> Option<String> {
-        let r = map.keys().find(|&ik| {
-            if ik.to_lowercase() == key.to_lowercase() {
-                true
-            } else {
-                false
-            }
-        });
+        let r = map
+            .keys()
+            .find(|&ik| ik.to_lowercase() == key.to_lowercase());
         Some((r?).clone())
     }
     pub fn chunked(&mut self) -> &mut Self {
-        if self.has_failure{
+        if self.has_failure {
             return self;
         }
         if self.res.method == "HEAD" {
             return self;
         }
+        // an HTTP/1.0 client can't read chunks, it keeps the Content-length body
+        if HttpVersion::parse(self.res.version) == HttpVersion::Http10 {
+            return self;
+        }
         self.res
             .add_header(String::from("Transfer-Encoding"), String::from("chunked"));
         if let Some(key) = Self::get_map_key(&self.res.header_pair, "content-length") {
@@ -316,20 +460,62 @@ impl<'b, 'a> ResponseConfig<'b, 'a> {
         self
     }
 
-    pub fn status(&mut self,code:u16)-> &mut Self{
-        if self.has_failure{
+    // frames a body whose size isn't known up front
+    fn unknown_length(&mut self) {
+        self.chunked();
+        if !self.res.chunked.enable && self.res.method != "HEAD" {
+            self.res
+                .add_header(String::from("Connection"), String::from("close"));
+        }
+    }
+
+    pub fn status(&mut self, code: u16) -> &mut Self {
+        if self.has_failure {
             return self;
         }
         self.res.http_state = code;
         self
     }
 
+    /// Sets a header, replacing any earlier value.
+    pub fn header(&mut self, key: &str, value: &str) -> &mut Self {
+        if self.has_failure {
+            return self;
+        }
+        self.res.add_header(key.to_string(), value.to_string());
+        self
+    }
+
+    /// `Response::write_string` keeping the status set so far, e.g. after
+    /// `Response::with_status`.
+    pub fn write_string(&mut self, v: &str) -> &mut Self {
+        self.write_binary(v.into())
+    }
+
+    /// `Response::write_binary` keeping the status set so far.
+    pub fn write_binary(&mut self, v: Vec<u8>) -> &mut Self {
+        if self.has_failure {
+            return self;
+        }
+        self.has_failure = self.res.write_binary(v).has_failure;
+        self
+    }
+
+    /// `Response::write_json` keeping the status set so far.
+    pub fn write_json<T: serde::Serialize>(&mut self, value: &T) -> &mut Self {
+        if self.has_failure {
+            return self;
+        }
+        self.has_failure = self.res.write_json(value).has_failure;
+        self
+    }
+
     pub fn specify_file_name(&mut self, name: &str) -> &mut Self {
-        if self.has_failure{
+        if self.has_failure {
             return self;
         }
         match &self.res.body {
-            BodyType::Memory(_) => {}
+            BodyType::Memory(_) | BodyType::Stream(_) => {}
             BodyType::File(_) => {
                 if !self.res.header_exist("Content-Disposition") {
                     self.res.add_header(
@@ -343,13 +529,130 @@ impl<'b, 'a> ResponseConfig<'b, 'a> {
         self
     }
 
+    /// Compress this body with gzip or deflate if the client accepts one,
+    /// whatever `HttpServer::enable_compression` says. Already compressed
+    /// types such as images and video are left alone.
+    #[cfg(feature = "compression")]
+    pub fn compress(&mut self) -> &mut Self {
+        if self.has_failure {
+            return self;
+        }
+        self.res.compress = true;
+        self
+    }
+
+    /// For a file body, send `<path>.br` or `<path>.gz` instead when it
+    /// exists and the client accepts that coding. The `Content-Type` still
+    /// follows the original file; a range request gets the original file.
+    pub fn precompressed(&mut self) -> &mut Self {
+        if self.has_failure {
+            return self;
+        }
+        self.res.precompressed = true;
+        self
+    }
+
+    /// For a file body, answer 304 Not Modified without reading the file
+    /// when `If-None-Match` names the file's current `ETag`, or, without
+    /// `If-None-Match`, when `If-Modified-Since` is no older than the file;
+    /// and 412 when `If-Match` or `If-Unmodified-Since` fails. See
+    /// `evaluate_preconditions`. `write_file` already answers the 304s, the
+    /// 412s need this call, after changing the validators if at all.
+    pub fn enable_cache(&mut self) -> &mut Self {
+        self.check_preconditions(true)
+    }
+
+    // `write_file` checks with `may_fail` false: its weak `ETag` never
+    // passes `If-Match`, and the handler may still set one that does
+    fn check_preconditions(&mut self, may_fail: bool) -> &mut Self {
+        if self.has_failure {
+            return self;
+        }
+        if !matches!(self.res.body, BodyType::File(_)) || !matches!(self.res.method, "GET" | "HEAD")
+        {
+            return self;
+        }
+        let (etag, last_modified) = self.validators();
+        match conditional::evaluate(
+            self.res.method,
+            &self.res.request_header,
+            etag.as_deref(),
+            last_modified,
+        ) {
+            PreconditionResult::NotModified => {
+                let length = Self::get_map_key(&self.res.header_pair, "content-length")
+                    .map(|key| self.res.header_pair[&key].clone());
+                if let (BodyType::File(path), Some(length)) = (
+                    std::mem::replace(&mut self.res.body, BodyType::None),
+                    length,
+                ) {
+                    self.res.not_modified = Some((path, length));
+                }
+                self.res.http_state = 304;
+                self.res.range = ResponseRangeMeta::None;
+                self.res.remove_header(String::from("Content-length"));
+            }
+            PreconditionResult::PreconditionFailed if may_fail => {
+                self.res.write_state(412);
+                self.res.range = ResponseRangeMeta::None;
+            }
+            PreconditionResult::PreconditionFailed | PreconditionResult::Proceed => {}
+        }
+        self
+    }
+
+    /// The response's `ETag` and `Last-Modified`, as far as they are set.
+    fn validators(&self) -> (Option<String>, Option<SystemTime>) {
+        let header = |name: &str| {
+            Self::get_map_key(&self.res.header_pair, name).map(|key| &self.res.header_pair[&key])
+        };
+        (
+            header("etag").cloned(),
+            header("last-modified").and_then(|v| date::parse_http_date(v)),
+        )
+    }
+
+    /// Replaces the `ETag` derived from size and mtime, e.g. with a hash of
+    /// the content, and checks `If-None-Match` against it. An unquoted tag
+    /// is quoted.
+    pub fn etag(&mut self, custom: &str) -> &mut Self {
+        if self.has_failure {
+            return self;
+        }
+        let tag = if custom.starts_with('"') || custom.starts_with("W/\"") {
+            custom.to_string()
+        } else {
+            format!("\"{}\"", custom)
+        };
+        self.res.remove_header(String::from("ETag"));
+        self.res.add_header(String::from("ETag"), tag);
+        // a 304 for the tag this replaces is decided again, unless the
+        // handler has since put another body in its place
+        match self.res.not_modified.take() {
+            Some((path, length))
+                if self.res.http_state == 304 && matches!(self.res.body, BodyType::None) =>
+            {
+                self.res.http_state = 200;
+                self.res.body = BodyType::File(path);
+                self.res.add_header(String::from("Content-length"), length);
+            }
+            _ => {}
+        }
+        self.enable_cache()
+    }
+
     pub fn enable_range(&mut self) -> &mut Self {
-        if self.has_failure{
+        if self.has_failure {
+            return self;
+        }
+        // a stream can't seek, so it is always sent whole, and a body that
+        // isn't there (e.g. after a 304) has nothing to slice
+        if let BodyType::Stream(_) | BodyType::None = self.res.body {
             return self;
         }
+        self.res
+            .add_header(String::from("Accept-Ranges"), String::from("bytes"));
         if self.res.method == "HEAD" {
-            self.res
-                .add_header(String::from("Accept-Ranges"), String::from("bytes"));
             match &self.res.body {
                 BodyType::Memory(buffs) => {
                     self.res
@@ -367,14 +670,19 @@ impl<'b, 'a> ResponseConfig<'b, 'a> {
                         self.res.write_state(404);
                     }
                 },
-                BodyType::None => {}
+                BodyType::Stream(_) | BodyType::None => {}
             }
         } else {
+            // a range of something other than what the client has is
+            // useless to it, it gets the whole body instead
+            let (etag, last_modified) = self.validators();
+            let if_range = find_header(&self.res.request_header, "If-Range")
+                .map(|v| conditional::if_range_holds(v, etag.as_deref(), last_modified));
             match self.res.get_request_header_value("Range") {
-                Some(v) => {
+                Some(v) if if_range != Some(false) => {
                     self.res.range = parse_range_content(v);
                 }
-                None => {
+                _ => {
                     self.res.range = ResponseRangeMeta::None;
                 }
             }
@@ -383,73 +691,120 @@ impl<'b, 'a> ResponseConfig<'b, 'a> {
     }
 }
 
+/// The first and last byte a `Range` asks for out of `size` bytes, or
+/// `None` when it can't be satisfied. `bytes=-n` is the last `n` bytes and
+/// an end past the body is cut to its last byte.
+fn range_bounds(start: Option<u64>, end: Option<u64>, size: u64) -> Option<(u64, u64)> {
+    match (start, end) {
+        (Some(start), end) if start < size => {
+            let end = end.map_or(size - 1, |end| end.min(size - 1));
+            if start <= end {
+                Some((start, end))
+            } else {
+                None
+            }
+        }
+        (None, Some(suffix)) if suffix > 0 && size > 0 => {
+            Some((size.saturating_sub(suffix), size - 1))
+        }
+        _ => None,
+    }
+}
+
+/// A `Range` in another unit than bytes is ignored and the whole body is
+/// sent, as RFC 7233 requires; a malformed bytes range is `Invalid` and
+/// answered with a 416.
 fn parse_range_content(v: &str) -> ResponseRangeMeta {
-    match v.trim().split_once("=") {
-        Some(v) => {
-            let v = v.1;
-            match v.trim().split_once("-") {
-                Some(v) => {
-                    let start;
-                    let end;
-                    if v.0 != "" {
-                        let mut exception = false;
-                        let r: u64 = v.0.parse().unwrap_or_else(|_| {
-                            exception = true;
-                            0
-                        });
-                        if r == 0 && exception == true {
-                            start = None;
-                        } else {
-                            start = Some(r);
-                        }
-                    } else {
-                        start = None;
-                    }
-                    if v.1 != "" {
-                        let mut exception = false;
-                        let r: u64 = v.1.parse().unwrap_or_else(|_| {
-                            exception = true;
-                            0
-                        });
-                        if r == 0 && exception == true {
-                            end = None;
-                        } else {
-                            end = Some(r);
-                        }
-                    } else {
-                        end = None;
-                    }
-                    ResponseRangeMeta::Range(start, end)
-                }
-                None => ResponseRangeMeta::Range(None, None),
+    let spec = match v.trim().split_once('=') {
+        Some((unit, spec)) if unit.trim().eq_ignore_ascii_case("bytes") => spec.trim(),
+        _ => return ResponseRangeMeta::None,
+    };
+    let bound = |v: &str| -> Option<Option<u64>> {
+        let v = v.trim();
+        if v.is_empty() {
+            Some(None)
+        } else if v.bytes().all(|b| b.is_ascii_digit()) {
+            v.parse().ok().map(Some)
+        } else {
+            None
+        }
+    };
+    let mut ranges = Vec::new();
+    // empty list elements, as in `bytes=0-9, ,20-29`, are allowed and skipped
+    for range in spec.split(',').filter(|v| !v.trim().is_empty()) {
+        let (start, end) = match range.split_once('-') {
+            Some(v) => v,
+            None => return ResponseRangeMeta::Invalid,
+        };
+        match (bound(start), bound(end)) {
+            (Some(None), Some(None)) | (None, _) | (_, None) => return ResponseRangeMeta::Invalid,
+            (Some(Some(start)), Some(Some(end))) if end < start => {
+                return ResponseRangeMeta::Invalid
             }
+            (Some(start), Some(end)) => ranges.push((start, end)),
         }
-        None => ResponseRangeMeta::Range(None, None),
+    }
+    match ranges.as_slice() {
+        [] => ResponseRangeMeta::Invalid,
+        &[(start, end)] => ResponseRangeMeta::Range(start, end),
+        _ => ResponseRangeMeta::Multiple(ranges),
     }
 }
 
 pub struct ResponseChunkMeta {
     pub(super) enable: bool,
     pub(super) chunk_size: usize,
+    // flush after each chunk rather than once the body is out
+    pub(super) flush_each: bool,
 }
 
 impl ResponseChunkMeta {
-    pub(super) fn new(chunk_size: u32) -> Self {
+    pub(super) fn new(chunk_size: u32, flush_each: bool) -> Self {
         ResponseChunkMeta {
             enable: false,
             chunk_size: chunk_size as usize,
+            flush_each,
         }
     }
 }
 
+/// Records which middlewares of the matched route ran, by their index in
+/// the registered vector, and which one (if any) stopped the chain.
+#[derive(Debug, Default)]
+pub struct MiddleWareTrace {
+    pub(super) executed: Vec<usize>,
+    pub(super) short_circuited: Option<usize>,
+}
+
+impl MiddleWareTrace {
+    pub fn executed(&self) -> &[usize] {
+        &self.executed
+    }
+
+    pub fn short_circuited(&self) -> Option<usize> {
+        self.short_circuited
+    }
+
+    pub fn router_reached(&self) -> bool {
+        self.short_circuited.is_none()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum ResponseRangeMeta {
     Range(Option<u64>, Option<u64>),
+    // `bytes=0-99,200-299`, sent as multipart/byteranges
+    Multiple(Vec<(Option<u64>, Option<u64>)>),
+    // a bytes range that doesn't parse, e.g. `bytes=5` or `bytes=9-2`
+    Invalid,
     None,
 }
 
 pub enum BodyType {
     Memory(Vec<u8>),
     File(String),
+    // read to the end while sending, the length is not known up front
+    Stream(Box<dyn Read + Send>),
     None,
 }
 
@@ -461,47 +816,94 @@ pub struct Response<'a> {
     pub(super) http_state: u16,
     pub(super) body: BodyType,
     pub(super) chunked: ResponseChunkMeta,
-    pub(super) conn_: Rc<RefCell<&'a mut TcpStream>>,
+    pub(super) conn_: Rc<RefCell<&'a mut (dyn ConnStream + 'static)>>,
     pub(super) range: ResponseRangeMeta,
-    pub(super) request_header: HashMap<&'a str, &'a str>,
+    pub(super) request_header: Rc<HashMap<&'a str, &'a str>>,
+    pub(super) middleware_trace: MiddleWareTrace,
+    // headers that may repeat, kept in the order they were added
+    pub(super) multi_header_pair: Vec<(String, String)>,
+    // the head (and maybe part of the body) already went out through another
+    // path, e.g. `write_sse`, so nothing is left to send after the router
+    pub(super) sent: bool,
+    // largest in-memory body a handler may build, 0 for no limit
+    pub(super) max_memory: usize,
+    // once over `max_memory` the 500 sticks, later writes are dropped
+    pub(super) memory_exceeded: bool,
+    // the server's logger, `None` while its log is off
+    pub(super) logger: Option<Arc<dyn ServerLogger + Send + Sync>>,
+    #[cfg(feature = "compression")]
+    pub(super) compress: bool,
+    pub(super) precompressed: bool,
+    // the file and length a 304 from `write_file` stands for, see
+    // `ResponseConfig::etag`
+    pub(super) not_modified: Option<(String, String)>,
 }
 
 impl<'a> Response<'a> {
     fn get_request_header_value(&mut self, k: &str) -> Option<&str> {
-        match self.request_header.keys().find(|&&ik| {
-            if k.to_lowercase() == ik.to_lowercase() {
-                true
-            } else {
-                false
-            }
-        }) {
+        match self
+            .request_header
+            .keys()
+            .find(|&&ik| k.to_lowercase() == ik.to_lowercase())
+        {
             Some(k) => Some(self.request_header.get(*k).unwrap()),
             None => None,
         }
     }
 
     pub fn remove_header(&mut self, key: String) {
-        let r = self.header_pair.keys().find(|&ik| {
-            if key.to_lowercase() == ik.to_lowercase() {
-                true
-            } else {
-                false
-            }
-        });
-        match r {
-            Some(k) => {
-                let s = k.clone();
-                let map = &mut self.header_pair;
-                map.remove(&s);
-            }
-            None => {}
+        let r = self
+            .header_pair
+            .keys()
+            .find(|&ik| key.to_lowercase() == ik.to_lowercase());
+        if let Some(k) = r {
+            let s = k.clone();
+            let map = &mut self.header_pair;
+            map.remove(&s);
         }
+        self.multi_header_pair
+            .retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
     }
 
+    /// Sets a header, replacing any earlier value of it whatever the case
+    /// of its name.
     pub fn add_header(&mut self, key: String, value: String) {
+        self.remove_header(key.clone());
         self.header_pair.insert(key, value);
     }
 
+    /// Like `add_header`, but keeps any earlier value of the same header, so
+    /// e.g. `Vary` or `Link` go out once per call. All of them go out with
+    /// the name as first given.
+    pub fn add_header_multi(&mut self, key: String, value: String) {
+        let single = self
+            .header_pair
+            .keys()
+            .find(|&k| key.eq_ignore_ascii_case(k))
+            .cloned();
+        let key = match single {
+            Some(k) => {
+                // the earlier value goes along with the new one
+                let v = self.header_pair.remove(&k).unwrap();
+                self.multi_header_pair.push((k.clone(), v));
+                k
+            }
+            None => match self
+                .multi_header_pair
+                .iter()
+                .find(|(k, _)| key.eq_ignore_ascii_case(k))
+            {
+                Some((k, _)) => k.clone(),
+                None => key,
+            },
+        };
+        self.multi_header_pair.push((key, value));
+    }
+
+    pub fn set_cookie(&mut self, cookie: cookie::Cookie) {
+        self.add_header_multi(String::from("Set-Cookie"), cookie.to_string());
+    }
+
     pub(super) fn header_to_string(&self) -> Vec<u8> {
         //println!("header pairs: {:#?}",self.header_pair);
         let mut buffs = Vec::new();
@@ -510,6 +912,9 @@ impl<'a> Response<'a> {
         for (k, v) in &self.header_pair {
             buffs.extend_from_slice(format!("{}: {}\r\n", k, v).as_bytes());
         }
+        for (k, v) in &self.multi_header_pair {
+            buffs.extend_from_slice(format!("{}: {}\r\n", k, v).as_bytes());
+        }
         buffs.extend_from_slice(b"\r\n");
         buffs
     }
@@ -521,43 +926,152 @@ impl<'a> Response<'a> {
                 Ok(file) => Ok(file.metadata()?.len()),
                 Err(e) => Err(e),
             },
+            BodyType::Stream(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a streamed body has no size",
+            )),
             BodyType::None => Ok(0),
         }
     }
 
+    // answered with a 416 and no body instead of the file
+    fn unsatisfiable_range(&mut self, body_size: u64) -> LayzyBuffers {
+        self.write_state(416);
+        self.add_header(
+            String::from("Content-Range"),
+            format!("bytes */{}", body_size),
+        );
+        if self.chunked.enable {
+            // the empty body still goes out as a last chunk
+            self.remove_header(String::from("Content-length"));
+        }
+        LayzyBuffers {
+            buffs: LayzyBuffersType::None,
+            len: 0,
+            taken: 0,
+        }
+    }
+
+    /// Several ranges go out as a multipart/byteranges body, each part with
+    /// its own `Content-Type` and `Content-Range`. The ones that can't be
+    /// satisfied are dropped and overlapping or adjacent ones merged, so
+    /// what is left may be a single range, which is sent as usual. Past
+    /// `MAX_RANGES` the `Range` is ignored and the whole body sent.
+    fn take_multipart_body(
+        &mut self,
+        specs: Vec<(Option<u64>, Option<u64>)>,
+        body_size: u64,
+    ) -> io::Result<LayzyBuffers> {
+        if specs.len() > MAX_RANGES {
+            self.range = ResponseRangeMeta::None;
+            return self.take_body_buff();
+        }
+        let mut ranges: Vec<(u64, u64)> = specs
+            .into_iter()
+            .filter_map(|(start, end)| range_bounds(start, end, body_size))
+            .collect();
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (beg, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if beg <= last.1 + 1 => last.1 = last.1.max(end),
+                _ => merged.push((beg, end)),
+            }
+        }
+        match merged.as_slice() {
+            [] => return Ok(self.unsatisfiable_range(body_size)),
+            &[(beg, end)] => {
+                self.range = ResponseRangeMeta::Range(Some(beg), Some(end));
+                return self.take_body_buff();
+            }
+            _ => {}
+        }
+        let boundary = uuid::Uuid::new_v4().simple().to_string();
+        let part_type = match ResponseConfig::get_map_key(&self.header_pair, "content-type") {
+            Some(key) => {
+                let value = self.header_pair.remove(&key).unwrap_or_default();
+                format!("Content-Type: {}\r\n", value)
+            }
+            None => String::new(),
+        };
+        let mut parts: Vec<(Vec<u8>, u64, u64)> = Vec::with_capacity(merged.len());
+        let mut len = 0;
+        for (index, &(beg, end)) in merged.iter().enumerate() {
+            let head = format!(
+                "{}--{}\r\n{}Content-Range: bytes {}-{}/{}\r\n\r\n",
+                if index == 0 { "" } else { "\r\n" },
+                boundary,
+                part_type,
+                beg,
+                end,
+                body_size
+            );
+            len += head.len() as u64 + end - beg + 1;
+            parts.push((head.into_bytes(), beg, end));
+        }
+        let tail = format!("\r\n--{}--\r\n", boundary).into_bytes();
+        len += tail.len() as u64;
+        let buffs = match &self.body {
+            BodyType::Memory(buffs) => {
+                let mut body = Vec::with_capacity(len as usize);
+                for (head, beg, end) in &parts {
+                    body.extend_from_slice(head);
+                    body.extend_from_slice(&buffs[*beg as usize..=*end as usize]);
+                }
+                body.extend_from_slice(&tail);
+                LayzyBuffersType::Memory(body)
+            }
+            BodyType::File(path) => {
+                // read part by part as it is sent
+                let mut segments = VecDeque::with_capacity(parts.len() * 2 + 1);
+                for (head, beg, end) in parts {
+                    segments.push_back(Segment::Bytes(io::Cursor::new(head)));
+                    segments.push_back(Segment::File {
+                        start: beg,
+                        left: end - beg + 1,
+                        seeked: false,
+                    });
+                }
+                segments.push_back(Segment::Bytes(io::Cursor::new(tail)));
+                LayzyBuffersType::File(FileType {
+                    file: Box::new(FileRanges {
+                        file: std::fs::OpenOptions::new().read(true).open(path)?,
+                        segments,
+                    }),
+                    buffs: Vec::new(),
+                })
+            }
+            BodyType::Stream(_) | BodyType::None => LayzyBuffersType::None,
+        };
+        self.add_header(
+            String::from("Content-Type"),
+            format!("multipart/byteranges; boundary={}", boundary),
+        );
+        self.remove_header(String::from("Content-length"));
+        if !self.chunked.enable {
+            self.add_header(String::from("Content-length"), len.to_string());
+        }
+        self.http_state = 206;
+        Ok(LayzyBuffers {
+            buffs,
+            len,
+            taken: 0,
+        })
+    }
+
     pub(super) fn take_body_buff(&mut self) -> io::Result<LayzyBuffers> {
         let body_size = self.take_body_size()?;
+        if let ResponseRangeMeta::Multiple(specs) = &mut self.range {
+            let specs = std::mem::take(specs);
+            return self.take_multipart_body(specs, body_size);
+        }
         match self.range {
+            ResponseRangeMeta::Invalid => Ok(self.unsatisfiable_range(body_size)),
             ResponseRangeMeta::Range(start, end) => {
-                let mut beg_pos;
-                let end_pos;
-                let mut lack_beg = false;
-                if let Some(x) = start {
-                    beg_pos = x;
-                } else {
-                    beg_pos = 0;
-                    lack_beg = true;
-                }
-                if let Some(x) = end {
-                    if lack_beg {
-                        end_pos = body_size - 1;
-                        beg_pos = body_size - x;
-                    } else {
-                        end_pos = x;
-                    }
-                } else {
-                    if lack_beg {
-                        todo!()
-                    }
-                    end_pos = body_size - 1;
-                }
-                if beg_pos > end_pos || (beg_pos >= (body_size - 1)) || end_pos >= body_size {
-                    self.write_state(416);
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "bad range values",
-                    ));
-                }
+                let (beg_pos, end_pos) = match range_bounds(start, end, body_size) {
+                    Some(bounds) => bounds,
+                    None => return Ok(self.unsatisfiable_range(body_size)),
+                };
 
                 let v = format!("bytes {}-{}/{}", beg_pos, end_pos, body_size);
                 let len = (end_pos - beg_pos + 1).to_string();
@@ -570,81 +1084,322 @@ impl<'a> Response<'a> {
                 }
                 self.http_state = 206;
 
-                match &self.body {
-                    BodyType::Memory(buffs) => {
-                        let slice = &buffs[beg_pos as usize..=end_pos as usize];
-                        let mut ret_buff = Vec::new();
-                        ret_buff.extend_from_slice(slice);
-                        return Ok(LayzyBuffers {
-                            buffs: LayzyBuffersType::Memory(ret_buff),
-                            len: slice.len() as u64,
-                        });
-                    }
+                match &mut self.body {
+                    // nothing reads the body after this, so it is moved out
+                    // and sent from `beg_pos` on rather than copied
+                    BodyType::Memory(buffs) => Ok(LayzyBuffers {
+                        buffs: LayzyBuffersType::Memory(std::mem::take(buffs)),
+                        len: end_pos + 1,
+                        taken: beg_pos,
+                    }),
                     BodyType::File(path) => {
                         let mut file = std::fs::OpenOptions::new().read(true).open(path)?;
                         let need_size = end_pos - beg_pos + 1;
                         file.seek(std::io::SeekFrom::Start(beg_pos))?;
-                        return Ok(LayzyBuffers {
+                        Ok(LayzyBuffers {
                             buffs: LayzyBuffersType::File(FileType {
                                 file: Box::new(file),
                                 buffs: Vec::new(),
                             }),
                             len: need_size,
-                        });
-                    }
-                    BodyType::None => {
-                        return Ok(LayzyBuffers {
-                            buffs: LayzyBuffersType::None,
-                            len: 0,
-                        });
+                            taken: 0,
+                        })
                     }
-                };
-            }
-            ResponseRangeMeta::None => match &self.body {
-                BodyType::Memory(buffs) => {
-                    return Ok(LayzyBuffers {
-                        buffs: LayzyBuffersType::Memory(buffs.clone()),
-                        len: buffs.len() as u64,
-                    });
+                    BodyType::Stream(_) | BodyType::None => Ok(LayzyBuffers {
+                        buffs: LayzyBuffersType::None,
+                        len: 0,
+                        taken: 0,
+                    }),
                 }
+            }
+            ResponseRangeMeta::None | ResponseRangeMeta::Multiple(_) => match &mut self.body {
+                BodyType::Memory(buffs) => Ok(LayzyBuffers {
+                    len: buffs.len() as u64,
+                    buffs: LayzyBuffersType::Memory(std::mem::take(buffs)),
+                    taken: 0,
+                }),
                 BodyType::File(path) => {
                     let file = std::fs::OpenOptions::new().read(true).open(path)?;
-                    return Ok(LayzyBuffers {
+                    Ok(LayzyBuffers {
                         buffs: LayzyBuffersType::File(FileType {
                             file: Box::new(file),
                             buffs: Vec::new(),
                         }),
-                        len: body_size as u64,
-                    });
-                }
-                BodyType::None => {
-                    return Ok(LayzyBuffers {
-                        buffs: LayzyBuffersType::None,
-                        len: 0,
-                    });
+                        len: body_size,
+                        taken: 0,
+                    })
                 }
+                BodyType::Stream(_) | BodyType::None => Ok(LayzyBuffers {
+                    buffs: LayzyBuffersType::None,
+                    len: 0,
+                    taken: 0,
+                }),
             },
         }
     }
 
     pub fn header_exist(&self, s: &str) -> bool {
-        let r = self
-            .header_pair
-            .keys()
-            .find(|&k| if k == s { true } else { false });
-        match r {
-            Some(_) => true,
-            None => false,
-        }
+        let r = self.header_pair.keys().find(|&k| k.eq_ignore_ascii_case(s));
+        r.is_some()
+            || self
+                .multi_header_pair
+                .iter()
+                .any(|(k, _)| k.eq_ignore_ascii_case(s))
     }
     pub fn write_string(&mut self, v: &str) -> ResponseConfig<'_, 'a> {
         self.write_binary(v.into())
     }
 
     pub fn write_binary(&mut self, v: Vec<u8>) -> ResponseConfig<'_, 'a> {
+        if self.exceeds_max_memory(v.len()) {
+            return self.reject_oversized_body();
+        }
         self.add_header(String::from("Content-length"), v.len().to_string());
         self.body = BodyType::Memory(v);
-        ResponseConfig { res: self ,has_failure:false}
+        ResponseConfig {
+            res: self,
+            has_failure: false,
+        }
+    }
+
+    /// Appends to the in-memory body, starting one if the body is anything
+    /// else.
+    pub fn append_to_body(&mut self, v: &[u8]) -> ResponseConfig<'_, 'a> {
+        if self.memory_exceeded {
+            return self.reject_oversized_body();
+        }
+        let mut buff = match std::mem::replace(&mut self.body, BodyType::None) {
+            BodyType::Memory(buff) => buff,
+            _ => Vec::new(),
+        };
+        if self.exceeds_max_memory(buff.len() + v.len()) {
+            return self.reject_oversized_body();
+        }
+        buff.extend_from_slice(v);
+        self.write_binary(buff)
+    }
+
+    fn exceeds_max_memory(&self, len: usize) -> bool {
+        self.memory_exceeded || (self.max_memory != 0 && len > self.max_memory)
+    }
+
+    fn reject_oversized_body(&mut self) -> ResponseConfig<'_, 'a> {
+        if self.memory_exceeded {
+            return ResponseConfig {
+                res: self,
+                has_failure: true,
+            };
+        }
+        self.memory_exceeded = true;
+        if let Some(logger) = &self.logger {
+            logger.log(
+                LogLevel::Warn,
+                &format!(
+                    "response body exceeds max_response_memory of {} bytes",
+                    self.max_memory
+                ),
+            );
+        }
+        // set directly, the message itself may be over a tiny limit
+        let msg = b"response body too large".to_vec();
+        self.remove_header(String::from("Content-Type"));
+        self.add_header(String::from("Content-length"), msg.len().to_string());
+        self.body = BodyType::Memory(msg);
+        self.http_state = 500;
+        ResponseConfig {
+            res: self,
+            has_failure: true,
+        }
+    }
+
+    pub fn write_json<T: serde::Serialize>(&mut self, value: &T) -> ResponseConfig<'_, 'a> {
+        match serde_json::to_vec(value) {
+            Ok(v) => {
+                if !self.header_exist("Content-Type") {
+                    self.add_header(
+                        String::from("Content-Type"),
+                        String::from("application/json; charset=utf-8"),
+                    );
+                }
+                self.write_binary(v)
+            }
+            Err(e) => {
+                self.write_string(&format!("json serialization failed: {}", e))
+                    .status(500);
+                ResponseConfig {
+                    res: self,
+                    has_failure: true,
+                }
+            }
+        }
+    }
+
+    /// Answers with `code` and `{"error": message, "status": code}` as an
+    /// `application/json` body, the shape the server's own errors take with
+    /// `HttpServer::set_json_errors`.
+    pub fn json_error(&mut self, code: u16, message: &str) -> ResponseConfig<'_, 'a> {
+        self.remove_header(String::from("Content-Type"));
+        let body = serde_json::json!({ "error": message, "status": code });
+        let mut config = self.write_json(&body);
+        config.status(code);
+        config
+    }
+
+    /// Sends `reader` as the body under the given status and headers, e.g. a
+    /// response already fetched from an upstream server. Without a
+    /// `Content-Length` among `headers` the body goes out chunked, or for an
+    /// HTTP/1.0 client delimited by closing the connection.
+    pub fn pipe_from<R: Read + Send + 'static>(
+        &mut self,
+        reader: R,
+        headers: HashMap<String, String>,
+        status: u16,
+    ) -> ResponseConfig<'_, 'a> {
+        let mut has_length = false;
+        for (k, v) in headers {
+            // the framing is ours to decide, not the upstream's
+            if k.eq_ignore_ascii_case("transfer-encoding") || k.eq_ignore_ascii_case("connection") {
+                continue;
+            }
+            has_length |= k.eq_ignore_ascii_case("content-length");
+            self.add_header(k, v);
+        }
+        self.http_state = status;
+        self.body = BodyType::Stream(Box::new(reader));
+        let mut config = ResponseConfig {
+            res: self,
+            has_failure: false,
+        };
+        if !has_length {
+            config.unknown_length();
+        }
+        config
+    }
+
+    /// Sends whatever `reader` yields as the body. With `known_len` it goes
+    /// out under a `Content-Length` and the reader is cut off there;
+    /// otherwise chunked, or for an HTTP/1.0 client delimited by closing the
+    /// connection. A read error, or a reader ending short of `known_len`,
+    /// closes the connection mid-body; a chunked body then lacks its last
+    /// chunk, so the client can tell.
+    pub fn write_stream<R: Read + Send + 'static>(
+        &mut self,
+        reader: R,
+        known_len: Option<u64>,
+    ) -> ResponseConfig<'_, 'a> {
+        self.remove_header(String::from("Content-length"));
+        self.body = match known_len {
+            Some(len) => {
+                self.add_header(String::from("Content-length"), len.to_string());
+                BodyType::Stream(Box::new(KnownLength(reader.take(len))))
+            }
+            None => BodyType::Stream(Box::new(reader)),
+        };
+        let mut config = ResponseConfig {
+            res: self,
+            has_failure: false,
+        };
+        if known_len.is_none() {
+            config.unknown_length();
+        }
+        config
+    }
+
+    /// Sends a body of `len` bytes read from `reader`, answering a `Range`
+    /// request with just that part. `reader` has to be at the start of the
+    /// body; it is read up to the start of the range and those bytes are
+    /// dropped, see `write_seekable_stream_with_length` to seek instead.
+    pub fn write_stream_with_length<R: Read + Send + 'static>(
+        &mut self,
+        mut reader: R,
+        len: u64,
+    ) -> ResponseConfig<'_, 'a> {
+        self.ranged_stream(len, move |start| {
+            io::copy(&mut (&mut reader).take(start), &mut io::sink())?;
+            Ok(Box::new(reader))
+        })
+    }
+
+    /// `write_stream_with_length` for a reader that can seek to the start of
+    /// a range.
+    pub fn write_seekable_stream_with_length<R: Read + Seek + Send + 'static>(
+        &mut self,
+        mut reader: R,
+        len: u64,
+    ) -> ResponseConfig<'_, 'a> {
+        self.ranged_stream(len, move |start| {
+            reader.seek(io::SeekFrom::Start(start))?;
+            Ok(Box::new(reader))
+        })
+    }
+
+    fn ranged_stream<F>(&mut self, len: u64, position: F) -> ResponseConfig<'_, 'a>
+    where
+        F: FnOnce(u64) -> io::Result<Box<dyn Read + Send>>,
+    {
+        self.remove_header(String::from("Content-length"));
+        self.add_header(String::from("Accept-Ranges"), String::from("bytes"));
+        let is_get = self.method == "GET";
+        let range = match self.get_request_header_value("Range") {
+            Some(v) if is_get => parse_range_content(v),
+            _ => ResponseRangeMeta::None,
+        };
+        let bounds = match range {
+            ResponseRangeMeta::Range(start, end) => range_bounds(start, end, len),
+            ResponseRangeMeta::Invalid => None,
+            // a stream is read front to back once, so several ranges can't
+            // be taken out of it
+            ResponseRangeMeta::None | ResponseRangeMeta::Multiple(_) => {
+                return match position(0) {
+                    Ok(reader) => self.write_stream(reader, Some(len)),
+                    Err(e) => self.stream_failed(e),
+                };
+            }
+        };
+        let (beg, end) = match bounds {
+            Some(bounds) => bounds,
+            None => {
+                self.write_state(416);
+                self.add_header(String::from("Content-Range"), format!("bytes */{}", len));
+                return ResponseConfig {
+                    res: self,
+                    has_failure: true,
+                };
+            }
+        };
+        match position(beg) {
+            Ok(reader) => {
+                self.add_header(
+                    String::from("Content-Range"),
+                    format!("bytes {}-{}/{}", beg, end, len),
+                );
+                let mut config = self.write_stream(reader, Some(end - beg + 1));
+                config.status(206);
+                config
+            }
+            Err(e) => self.stream_failed(e),
+        }
+    }
+
+    fn stream_failed(&mut self, e: io::Error) -> ResponseConfig<'_, 'a> {
+        self.remove_header(String::from("Accept-Ranges"));
+        self.write_string(&format!("failed to position the body: {}", e))
+            .status(500);
+        ResponseConfig {
+            res: self,
+            has_failure: true,
+        }
+    }
+
+    /// Starts a response from its status, the body and headers follow on
+    /// the returned config in any order, e.g.
+    /// `res.with_status(201).header("Location", "/items/7").write_json(&item)`.
+    pub fn with_status(&mut self, code: u16) -> ResponseConfig<'_, 'a> {
+        self.http_state = code;
+        ResponseConfig {
+            res: self,
+            has_failure: false,
+        }
     }
 
     pub fn write_state(&mut self, code: u16) {
@@ -656,39 +1411,164 @@ impl<'a> Response<'a> {
     pub fn write_file(&mut self, path: String) -> ResponseConfig<'_, 'a> {
         match std::fs::OpenOptions::new().read(true).open(path.clone()) {
             Ok(file) => {
-                let len = file.metadata().unwrap().len();
+                let meta = file.metadata().unwrap();
+                let len = meta.len();
                 self.add_header(String::from("Content-length"), len.to_string());
+                // weak, size and mtime say the content is the same, not each byte
+                let mtime = meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_secs());
+                self.add_header(String::from("ETag"), format!("W/\"{:x}-{:x}\"", len, mtime));
+                // some filesystems keep no mtime, then there is nothing to say
+                if let Ok(modified) = meta.modified() {
+                    self.add_header(String::from("Last-Modified"), date::http_date(modified));
+                }
                 let extension = std::path::Path::new(&path)
                     .extension()
                     .and_then(OsStr::to_str);
 
-                match extension {
-                    Some(extension) => {
-                        let content_type = mime::extension_to_content_type(extension);
-                        if content_type != "" {
-                            if !self.header_exist("Content-Type") {
-                                self.add_header(
-                                    String::from("Content-Type"),
-                                    content_type.to_string(),
-                                );
-                            }
-                        }
+                if let Some(extension) = extension {
+                    let content_type = mime::extension_to_content_type(extension);
+                    if !content_type.is_empty() && !self.header_exist("Content-Type") {
+                        self.add_header(String::from("Content-Type"), content_type.to_string());
                     }
-                    None => {}
                 }
             }
             Err(_) => {
-                self.write_string(&format!("{} file not found", path)).status(404);
-                return ResponseConfig { res: self, has_failure:true };
+                self.write_string(&format!("{} file not found", path))
+                    .status(404);
+                return ResponseConfig {
+                    res: self,
+                    has_failure: true,
+                };
             }
         }
         self.body = BodyType::File(path);
-        ResponseConfig { res: self,has_failure:false }
+        let mut config = ResponseConfig {
+            res: self,
+            has_failure: false,
+        };
+        // a conditional request for an unchanged file gets its 304 right away
+        config.check_preconditions(false);
+        config
     }
 
-    pub fn get_conn(&self) -> Rc<RefCell<&'a mut TcpStream>> {
+    /// Redirects to `location` with one of 301, 302, 307 or 308. Any other
+    /// code is refused with a 500 and the returned config is a no-op.
+    pub fn redirect(&mut self, location: &str, code: u16) -> ResponseConfig<'_, 'a> {
+        if !matches!(code, 301 | 302 | 307 | 308) {
+            self.write_string(&format!("{} is not a redirect status code", code))
+                .status(500);
+            return ResponseConfig {
+                res: self,
+                has_failure: true,
+            };
+        }
+        self.add_header(String::from("Location"), location.to_string());
+        self.write_state(code);
+        ResponseConfig {
+            res: self,
+            has_failure: false,
+        }
+    }
+
+    pub fn redirect_found(&mut self, location: &str) -> ResponseConfig<'_, 'a> {
+        self.redirect(location, 302)
+    }
+
+    /// Starts a `text/event-stream` response: the head is written right away
+    /// and events are then pushed through the returned handle for as long as
+    /// the handler keeps it. The connection is closed once the handler
+    /// returns.
+    pub fn write_sse(&mut self) -> io::Result<SseStream<'a>> {
+        self.http_state = 200;
+        self.body = BodyType::None;
+        self.remove_header(String::from("Content-length"));
+        self.add_header(
+            String::from("Content-Type"),
+            String::from("text/event-stream"),
+        );
+        self.add_header(String::from("Cache-Control"), String::from("no-cache"));
+        self.add_header(String::from("Connection"), String::from("close"));
+        self.sent = true;
+        let head = self.header_to_string();
+        let mut conn = self.conn_.borrow_mut();
+        conn.write_all(&head)?;
+        conn.flush()?;
+        Ok(SseStream {
+            conn_: Rc::clone(&self.conn_),
+        })
+    }
+
+    /// Answers a WebSocket handshake with `101 Switching Protocols` and
+    /// hands over the connection, which closes once the handler returns.
+    /// Headers added before, e.g. `Sec-WebSocket-Protocol`, go out with the
+    /// 101. `None` when `req` is no valid handshake; the response is then a
+    /// 400, or a 426 for a version other than 13, unless the handler writes
+    /// something else.
+    pub fn upgrade_websocket(&mut self, req: &Request) -> Option<WebSocket<'a>> {
+        let accept = match websocket::accept_key(req) {
+            Ok(accept) => accept,
+            Err(status) => {
+                self.write_state(status);
+                if status == 426 {
+                    self.add_header(String::from("Sec-WebSocket-Version"), String::from("13"));
+                }
+                return None;
+            }
+        };
+        self.http_state = 101;
+        self.body = BodyType::None;
+        self.remove_header(String::from("Content-length"));
+        self.remove_header(String::from("Connection"));
+        self.add_header(String::from("Upgrade"), String::from("websocket"));
+        self.add_header(String::from("Connection"), String::from("Upgrade"));
+        self.add_header(String::from("Sec-WebSocket-Accept"), accept);
+        self.sent = true;
+        let head = self.header_to_string();
+        let mut conn = self.conn_.borrow_mut();
+        conn.write_all(&head).ok()?;
+        conn.flush().ok()?;
+        drop(conn);
+        Some(WebSocket::new(Rc::clone(&self.conn_)))
+    }
+
+    pub fn get_conn(&self) -> Rc<RefCell<&'a mut (dyn ConnStream + 'static)>> {
         Rc::clone(&self.conn_)
     }
+
+    pub fn middleware_trace(&self) -> &MiddleWareTrace {
+        &self.middleware_trace
+    }
+
+    pub fn get_status(&self) -> u16 {
+        self.http_state
+    }
+}
+
+/// Event sink returned by `Response::write_sse`. A failed send means the
+/// client has gone away and the handler should stop.
+pub struct SseStream<'a> {
+    conn_: Rc<RefCell<&'a mut (dyn ConnStream + 'static)>>,
+}
+
+impl<'a> SseStream<'a> {
+    pub fn send_event(&mut self, event: Option<&str>, data: &str) -> io::Result<()> {
+        let mut message = String::new();
+        if let Some(event) = event {
+            message.push_str(&format!("event: {}\n", event));
+        }
+        // every line of a multi-line payload needs its own `data:` field
+        for line in data.split('\n') {
+            message.push_str(&format!("data: {}\n", line));
+        }
+        message.push('\n');
+        let mut conn = self.conn_.borrow_mut();
+        conn.write_all(message.as_bytes())?;
+        conn.flush()
+    }
 }
 
 #[derive(Debug)]
@@ -698,7 +1578,28 @@ pub enum BodyContent<'a> {
     Multi(HashMap<String, MultipleFormData<'a>>),
     None,
     Bad,
-	TooLarge
+    TooLarge,
+}
+
+/// The protocol version of a request, see `Request::http_version`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+    // as received, the parser answers anything but 1.0 and 1.1 with a 505
+    Other(String),
+}
+
+impl HttpVersion {
+    pub(crate) fn parse(version: &str) -> Self {
+        if version.eq_ignore_ascii_case("HTTP/1.1") {
+            HttpVersion::Http11
+        } else if version.eq_ignore_ascii_case("HTTP/1.0") {
+            HttpVersion::Http10
+        } else {
+            HttpVersion::Other(version.to_string())
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -715,8 +1616,118 @@ pub enum MultipleFormData<'a> {
     File(MultipleFormFile),
 }
 
+pub(super) struct StreamedBody<'a> {
+    // read along with the head, and how much of that the handler took
+    pub(super) head: &'a [u8],
+    pub(super) head_taken: Cell<usize>,
+    // still on the connection
+    pub(super) left: &'a Cell<u64>,
+}
+
+/// See `Request::body_reader`. Fails with `UnexpectedEof` when the client
+/// stops short of the length it declared.
+pub struct BodyReader<'r, 'a> {
+    body: &'r StreamedBody<'a>,
+    conn_: &'r Rc<RefCell<&'a mut (dyn ConnStream + 'static)>>,
+}
+
+impl Read for BodyReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let head = &self.body.head[self.body.head_taken.get()..];
+        if !head.is_empty() {
+            let size = head.len().min(buf.len());
+            buf[..size].copy_from_slice(&head[..size]);
+            self.body.head_taken.set(self.body.head_taken.get() + size);
+            return Ok(size);
+        }
+        let left = self.body.left.get();
+        if left == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let want = left.min(buf.len() as u64) as usize;
+        let size = self.conn_.borrow_mut().read(&mut buf[..want])?;
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the body ended {} bytes short", left),
+            ));
+        }
+        self.body.left.set(left - size as u64);
+        Ok(size)
+    }
+}
+
+// ranges a request may ask for at once, more and it gets the whole body
+const MAX_RANGES: usize = 32;
+
+enum Segment {
+    Bytes(io::Cursor<Vec<u8>>),
+    // `left` bytes of the file from `start`, seeked to once it is reached
+    File { start: u64, left: u64, seeked: bool },
+}
+
+/// A multipart/byteranges body out of one file handle, the part heads in
+/// between the ranges of the file.
+struct FileRanges {
+    file: std::fs::File,
+    segments: VecDeque<Segment>,
+}
+
+impl Read for FileRanges {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(segment) = self.segments.front_mut() {
+            let size = match segment {
+                Segment::Bytes(bytes) => bytes.read(buf)?,
+                Segment::File { left: 0, .. } => 0,
+                Segment::File {
+                    start,
+                    left,
+                    seeked,
+                } => {
+                    if !*seeked {
+                        self.file.seek(io::SeekFrom::Start(*start))?;
+                        *seeked = true;
+                    }
+                    let want = (*left).min(buf.len() as u64) as usize;
+                    let size = self.file.read(&mut buf[..want])?;
+                    if size == 0 && want > 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "the file got shorter while it was sent",
+                        ));
+                    }
+                    *left -= size as u64;
+                    size
+                }
+            };
+            if size > 0 || buf.is_empty() {
+                return Ok(size);
+            }
+            self.segments.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+/// Fails a stream that ends before its `Content-Length`, so the connection
+/// is closed rather than left waiting for bytes that won't come.
+struct KnownLength<R>(io::Take<R>);
+
+impl<R: Read> Read for KnownLength<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.0.read(buf)?;
+        if size == 0 && !buf.is_empty() && self.0.limit() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the body ended {} bytes short", self.0.limit()),
+            ));
+        }
+        Ok(size)
+    }
+}
+
 pub(super) struct FileType {
-    file: Box<std::fs::File>,
+    file: Box<dyn Read + Send>,
     buffs: Vec<u8>,
 }
 
@@ -727,59 +1738,110 @@ pub(super) enum LayzyBuffersType {
 }
 pub(super) struct LayzyBuffers {
     buffs: LayzyBuffersType,
+    // where the body ends, and where the next chunk starts; a ranged memory
+    // body starts past 0
     len: u64,
+    taken: u64,
 }
 
 impl LayzyBuffers {
-    pub fn len(&self) -> usize {
-        self.len as usize
+    /// The next at most `max` bytes of the body, `None` once it is all out.
+    /// A file is read one chunk at a time, so a large one is never held in
+    /// memory whole.
+    pub(super) fn next_chunk(&mut self, max: usize) -> io::Result<Option<&[u8]>> {
+        let size = (self.len - self.taken).min(max as u64) as usize;
+        if size == 0 {
+            return Ok(None);
+        }
+        let start = self.taken as usize;
+        self.taken += size as u64;
+        match &mut self.buffs {
+            LayzyBuffersType::Memory(buffs) => Ok(Some(&buffs[start..start + size])),
+            LayzyBuffersType::File(file_v) => {
+                file_v.buffs.resize(size, b'\0');
+                // a file cut short while being sent fails here
+                file_v.file.read_exact(&mut file_v.buffs)?;
+                Ok(Some(&file_v.buffs))
+            }
+            LayzyBuffersType::None => Ok(None),
+        }
     }
 }
 
-impl Index<Range<usize>> for LayzyBuffers {
-    type Output = [u8];
+#[cfg(test)]
+mod tests {
+    use super::{parse_range_content, range_bounds, ResponseRangeMeta};
 
-    fn index(&self, _index: Range<usize>) -> &Self::Output {
-        unimplemented!()
+    #[test]
+    fn parses_single_ranges() {
+        use ResponseRangeMeta::Range;
+        assert_eq!(parse_range_content("bytes=0-9"), Range(Some(0), Some(9)));
+        assert_eq!(parse_range_content("bytes=5-"), Range(Some(5), None));
+        assert_eq!(parse_range_content("bytes=-3"), Range(None, Some(3)));
+        assert_eq!(parse_range_content("bytes=4-4"), Range(Some(4), Some(4)));
     }
-}
 
-impl IndexMut<Range<usize>> for LayzyBuffers {
-    fn index_mut(&mut self, index: Range<usize>) -> &mut Self::Output {
-        match &mut self.buffs {
-            LayzyBuffersType::Memory(buffs) => &mut buffs[index],
-            LayzyBuffersType::File(file_v) => {
-                let file = &mut file_v.file;
-                let need_size = index.end - index.start;
-                let buffs = &mut file_v.buffs;
-                buffs.resize(need_size, b'\0');
-                file.read(buffs).unwrap();
-                buffs
-            }
-            LayzyBuffersType::None => todo!(),
-        }
+    #[test]
+    fn parses_several_ranges() {
+        assert_eq!(
+            parse_range_content("bytes=0-1,5-,-2"),
+            ResponseRangeMeta::Multiple(vec![(Some(0), Some(1)), (Some(5), None), (None, Some(2))])
+        );
+        // empty list elements are skipped
+        assert_eq!(
+            parse_range_content("bytes=0-1, ,3-4,"),
+            ResponseRangeMeta::Multiple(vec![(Some(0), Some(1)), (Some(3), Some(4))])
+        );
     }
-}
 
-impl Deref for LayzyBuffers {
-    type Target = Vec<u8>;
+    #[test]
+    fn parses_spaced_and_cased_ranges() {
+        use ResponseRangeMeta::{Multiple, Range};
+        assert_eq!(
+            parse_range_content("Bytes = 0 - 99"),
+            Range(Some(0), Some(99))
+        );
+        assert_eq!(parse_range_content("BYTES=-5"), Range(None, Some(5)));
+        assert_eq!(parse_range_content(" bytes=7- "), Range(Some(7), None));
+        assert_eq!(
+            parse_range_content("bYtEs=0-99, 200 -299"),
+            Multiple(vec![(Some(0), Some(99)), (Some(200), Some(299))])
+        );
+        assert_eq!(
+            parse_range_content("bytes=0 1-2"),
+            ResponseRangeMeta::Invalid
+        );
+    }
 
-    fn deref(&self) -> &Self::Target {
-        unimplemented!()
+    #[test]
+    fn ignores_other_units() {
+        assert_eq!(parse_range_content("items=0-9"), ResponseRangeMeta::None);
+        assert_eq!(parse_range_content("0-9"), ResponseRangeMeta::None);
     }
-}
 
-impl DerefMut for LayzyBuffers {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        match &mut self.buffs {
-            LayzyBuffersType::Memory(buffs) => buffs,
-            LayzyBuffersType::File(file_v) => {
-                let file = &mut file_v.file;
-                let buffs = &mut file_v.buffs;
-                file.read_to_end(buffs).unwrap();
-                buffs
-            }
-            LayzyBuffersType::None => todo!(),
+    #[test]
+    fn refuses_malformed_ranges() {
+        for v in [
+            "bytes=",
+            "bytes=-",
+            "bytes=9-0",
+            "bytes=a-b",
+            "bytes=1",
+            "bytes=+1-2",
+            "bytes=0-1,x",
+        ] {
+            assert_eq!(parse_range_content(v), ResponseRangeMeta::Invalid, "{}", v);
         }
     }
+
+    #[test]
+    fn bounds_within_the_body() {
+        assert_eq!(range_bounds(Some(0), Some(9), 5), Some((0, 4)));
+        assert_eq!(range_bounds(Some(2), None, 5), Some((2, 4)));
+        assert_eq!(range_bounds(None, Some(2), 5), Some((3, 4)));
+        assert_eq!(range_bounds(None, Some(9), 5), Some((0, 4)));
+        assert_eq!(range_bounds(Some(5), None, 5), None);
+        assert_eq!(range_bounds(None, Some(0), 5), None);
+        assert_eq!(range_bounds(None, Some(1), 0), None);
+    }
 }
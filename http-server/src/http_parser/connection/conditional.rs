@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{date, Request};
+use crate::http_parser::find_header;
+
+/// What a request's preconditions say about the response, see
+/// `evaluate_preconditions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreconditionResult {
+    // none failed, answer as usual
+    Proceed,
+    // a GET or HEAD for what the client has already, answer 304
+    NotModified,
+    // answer 412
+    PreconditionFailed,
+}
+
+/// Checks `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since` against the current `ETag` (quoted, maybe `W/`) and
+/// modification time of what the request targets, in the order of RFC 7232
+/// section 6; e.g. with `If-None-Match` present `If-Modified-Since` is not
+/// looked at. A date that doesn't parse counts as no header at all, and
+/// `etag` `None` fails `If-Match: *` as if nothing were there.
+pub fn evaluate_preconditions(
+    req: &Request,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> PreconditionResult {
+    evaluate(req.method, &req.header_pair, etag, last_modified)
+}
+
+pub(crate) fn evaluate(
+    method: &str,
+    head_map: &HashMap<&str, &str>,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> PreconditionResult {
+    let last_modified = last_modified.map(whole_seconds);
+    let date_of = |name: &str| find_header(head_map, name).and_then(date::parse_http_date);
+    if let Some(list) = find_header(head_map, "If-Match") {
+        if !list_matches(list, etag, true) {
+            return PreconditionResult::PreconditionFailed;
+        }
+    } else if let (Some(since), Some(modified)) = (date_of("If-Unmodified-Since"), last_modified) {
+        if modified > since {
+            return PreconditionResult::PreconditionFailed;
+        }
+    }
+    let safe = matches!(method, "GET" | "HEAD");
+    if let Some(list) = find_header(head_map, "If-None-Match") {
+        if list_matches(list, etag, false) {
+            return if safe {
+                PreconditionResult::NotModified
+            } else {
+                PreconditionResult::PreconditionFailed
+            };
+        }
+    } else if let (true, Some(since), Some(modified)) =
+        (safe, date_of("If-Modified-Since"), last_modified)
+    {
+        if modified <= since {
+            return PreconditionResult::NotModified;
+        }
+    }
+    PreconditionResult::Proceed
+}
+
+/// Whether a `Range` still applies under `If-Range`, which needs a strong
+/// match of the `ETag` or exactly the modification time.
+pub(crate) fn if_range_holds(
+    if_range: &str,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return etag.is_some_and(|etag| same_tag(if_range, etag, true));
+    }
+    match (date::parse_http_date(if_range), last_modified) {
+        (Some(date), Some(modified)) => whole_seconds(modified) == date,
+        _ => false,
+    }
+}
+
+/// `*` matches whatever is there, and so nothing without an `ETag`; a
+/// list is compared tag by tag, strong comparison for `If-Match`, weak for
+/// `If-None-Match`.
+fn list_matches(list: &str, etag: Option<&str>, strong: bool) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    list.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || same_tag(tag, etag, strong))
+}
+
+fn same_tag(a: &str, b: &str, strong: bool) -> bool {
+    let is_weak = |tag: &str| tag.trim().starts_with("W/");
+    if strong && (is_weak(a) || is_weak(b)) {
+        return false;
+    }
+    a.trim().trim_start_matches("W/") == b.trim().trim_start_matches("W/")
+}
+
+// HTTP dates have no fractions, a file's mtime usually does
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate, PreconditionResult};
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn date() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(784111777)
+    }
+
+    fn check(method: &str, headers: &[(&str, &str)], etag: Option<&str>) -> PreconditionResult {
+        let head_map: HashMap<&str, &str> = headers.iter().copied().collect();
+        evaluate(method, &head_map, etag, Some(date()))
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        // the date alone would say not modified, the tag says otherwise
+        let headers = [("If-None-Match", "\"old\""), ("If-Modified-Since", DATE)];
+        assert_eq!(
+            check("GET", &headers, Some("\"new\"")),
+            PreconditionResult::Proceed
+        );
+        let headers = [("If-None-Match", "\"new\""), ("If-Modified-Since", "junk")];
+        assert_eq!(
+            check("GET", &headers, Some("\"new\"")),
+            PreconditionResult::NotModified
+        );
+    }
+
+    #[test]
+    fn if_match_wins_over_if_unmodified_since() {
+        // modified after the date, but the tag matches
+        let headers = [
+            ("If-Match", "\"v1\""),
+            ("If-Unmodified-Since", "Sat, 05 Nov 1994 08:49:37 GMT"),
+        ];
+        assert_eq!(
+            check("PUT", &headers, Some("\"v1\"")),
+            PreconditionResult::Proceed
+        );
+        let headers = [("If-Match", "\"v0\""), ("If-Unmodified-Since", DATE)];
+        assert_eq!(
+            check("PUT", &headers, Some("\"v1\"")),
+            PreconditionResult::PreconditionFailed
+        );
+        // without If-Match the date decides
+        let headers = [("If-Unmodified-Since", "Sat, 05 Nov 1994 08:49:37 GMT")];
+        assert_eq!(
+            check("PUT", &headers, Some("\"v1\"")),
+            PreconditionResult::PreconditionFailed
+        );
+    }
+
+    #[test]
+    fn unsafe_method_gets_412_for_a_matching_if_none_match() {
+        let headers = [("If-None-Match", "\"v1\"")];
+        for method in ["POST", "PUT", "DELETE"] {
+            assert_eq!(
+                check(method, &headers, Some("\"v1\"")),
+                PreconditionResult::PreconditionFailed,
+                "{}",
+                method
+            );
+        }
+        // If-Modified-Since is for GET and HEAD only
+        let headers = [("If-Modified-Since", DATE)];
+        assert_eq!(
+            check("PUT", &headers, Some("\"v1\"")),
+            PreconditionResult::Proceed
+        );
+    }
+
+    #[test]
+    fn star_needs_a_current_etag() {
+        let headers = [("If-Match", "*")];
+        assert_eq!(
+            check("PUT", &headers, Some("\"v1\"")),
+            PreconditionResult::Proceed
+        );
+        assert_eq!(
+            check("PUT", &headers, None),
+            PreconditionResult::PreconditionFailed
+        );
+        let headers = [("If-None-Match", "*")];
+        assert_eq!(check("PUT", &headers, None), PreconditionResult::Proceed);
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::time::SystemTime;
+
+use super::date::http_date;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// One `Set-Cookie` header, e.g.
+/// `Cookie::new("id", "42").path("/").max_age(3600).http_only(true)`.
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<i64>,
+    expires: Option<SystemTime>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Seconds until the cookie expires; 0 or less removes it right away.
+    pub fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    pub fn expires(mut self, at: SystemTime) -> Self {
+        self.expires = Some(at);
+        self
+    }
+
+    pub fn http_only(mut self, enable: bool) -> Self {
+        self.http_only = enable;
+        self
+    }
+
+    pub fn secure(mut self, enable: bool) -> Self {
+        self.secure = enable;
+        self
+    }
+
+    /// `SameSite::None` goes out with `Secure` whatever `secure` says,
+    /// browsers drop such a cookie without it.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if self.secure || self.same_site == Some(SameSite::None) {
+            write!(f, "; Secure")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::cell::RefCell;
+use std::io::{self, ErrorKind};
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::{HttpVersion, Request};
+use crate::auth::{decode_base64, encode_base64};
+use crate::http_parser::find_header;
+use crate::http_parser::stream::ConnStream;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// what `recv` puts together at most, a bigger message closes with 1009
+const MAX_MESSAGE: usize = 16 << 20;
+
+/// A message read by `WebSocket::recv`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// A connection taken over by `Response::upgrade_websocket`. `recv` answers
+/// pings and the client's close by itself; after an error, other than a
+/// read timing out before a frame began, the connection is closed.
+pub struct WebSocket<'a> {
+    conn_: Rc<RefCell<&'a mut (dyn ConnStream + 'static)>>,
+    closed: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    pub(super) fn new(conn_: Rc<RefCell<&'a mut (dyn ConnStream + 'static)>>) -> Self {
+        WebSocket {
+            conn_,
+            closed: false,
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_frame(0x1, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_frame(0x2, data)
+    }
+
+    /// Waits for the next text or binary message, `None` once the client
+    /// closed the connection. It fails with `WouldBlock` or `TimedOut`
+    /// when the server's read timeout, or `set_read_timeout`, runs out.
+    pub fn recv(&mut self) -> io::Result<Option<WebSocketMessage>> {
+        let r = self.read_message();
+        if let Err(e) = &r {
+            if !is_timeout(e) {
+                self.closed = true;
+            }
+        }
+        r
+    }
+
+    /// How long `recv` waits, `None` for as long as it takes.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.conn_.borrow().tcp().set_read_timeout(timeout)
+    }
+
+    /// Sends a close frame with `code`, e.g. 1000 for a normal closure;
+    /// the connection ends once the handler returns.
+    pub fn close(&mut self, code: u16) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.send_frame(0x8, &code.to_be_bytes())
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed && opcode != 0x8 {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "the websocket is closed",
+            ));
+        }
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        // a server's frames go out unmasked
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        let mut conn = self.conn_.borrow_mut();
+        conn.write_all(&frame)?;
+        conn.flush()
+    }
+
+    // closes with `code` and fails with `why`
+    fn fail(&mut self, code: u16, why: &str) -> io::Error {
+        let _ = self.close(code);
+        io::Error::new(ErrorKind::InvalidData, why.to_string())
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<WebSocketMessage>> {
+        if self.closed {
+            return Ok(None);
+        }
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let (fin, opcode, payload) = self.read_frame(message.is_some())?;
+            match opcode {
+                0x8 => {
+                    let code = match payload.get(..2) {
+                        Some(code) => u16::from_be_bytes([code[0], code[1]]),
+                        None => 1000,
+                    };
+                    let _ = self.close(code);
+                    return Ok(None);
+                }
+                0x9 => self.send_frame(0xA, &payload)?,
+                0xA => {}
+                0x0 => {
+                    let Some((_, data)) = message.as_mut() else {
+                        return Err(self.fail(1002, "continuation without a message"));
+                    };
+                    if data.len() + payload.len() > MAX_MESSAGE {
+                        return Err(self.fail(1009, "websocket message too big"));
+                    }
+                    data.extend_from_slice(&payload);
+                }
+                0x1 | 0x2 if message.is_none() => message = Some((opcode, payload)),
+                _ => return Err(self.fail(1002, "unexpected websocket opcode")),
+            }
+            if !fin || opcode >= 0x8 {
+                continue;
+            }
+            if let Some((opcode, data)) = message.take() {
+                if opcode == 0x2 {
+                    return Ok(Some(WebSocketMessage::Binary(data)));
+                }
+                return match String::from_utf8(data) {
+                    Ok(text) => Ok(Some(WebSocketMessage::Text(text))),
+                    Err(_) => Err(self.fail(1007, "websocket text is not UTF-8")),
+                };
+            }
+        }
+    }
+
+    fn read_frame(&mut self, in_message: bool) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0u8; 2];
+        // the first byte alone, so a timeout here leaves nothing half read
+        // and `recv` may be called again
+        if !in_message {
+            let n = self.conn_.borrow_mut().read(&mut head[..1])?;
+            if n == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.read_exact(&mut head[1..])?;
+        } else {
+            self.read_exact(&mut head)?;
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail(1002, "reserved websocket bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(1002, "unmasked websocket frame from a client"));
+        }
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0u8; 2];
+                self.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                self.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if opcode >= 0x8 && (!fin || len > 125) {
+            return Err(self.fail(1002, "invalid websocket control frame"));
+        }
+        if len > MAX_MESSAGE as u64 {
+            return Err(self.fail(1009, "websocket message too big"));
+        }
+        let mut mask = [0u8; 4];
+        self.read_exact(&mut mask)?;
+        let mut payload = vec![0u8; len as usize];
+        self.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        Ok((fin, opcode, payload))
+    }
+
+    // a frame cut off, even by a timeout, can't be picked up again
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let r = self.conn_.borrow_mut().read_exact(buf);
+        if r.is_err() {
+            self.closed = true;
+        }
+        r
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// The `Sec-WebSocket-Accept` for a valid handshake request, otherwise the
+/// status to refuse it with: 426 for a version other than 13, else 400.
+pub(super) fn accept_key(req: &Request) -> Result<String, u16> {
+    let has_token = |name: &str, token: &str| {
+        find_header(&req.header_pair, name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if req.method != "GET"
+        || req.http_version() != HttpVersion::Http11
+        || !has_token("Upgrade", "websocket")
+        || !has_token("Connection", "upgrade")
+    {
+        return Err(400);
+    }
+    if find_header(&req.header_pair, "Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(426);
+    }
+    let key = find_header(&req.header_pair, "Sec-WebSocket-Key")
+        .map(str::trim)
+        .ok_or(400u16)?;
+    if decode_base64(key).map(|k| k.len()) != Some(16) {
+        return Err(400);
+    }
+    Ok(encode_base64(&sha1(format!("{}{}", key, GUID).as_bytes())))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 20];
+    for (chunk, v) in out.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
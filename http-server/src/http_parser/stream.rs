@@ -0,0 +1,86 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+/// The transport a connection is served over. Parsing and writing only need
+/// `Read + Write`; `tcp` reaches the socket underneath for timeouts,
+/// shutdown and peer information.
+pub trait ConnStream: Read + Write {
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl ConnStream for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
+
+#[cfg(feature = "tls")]
+pub type TlsStream = rustls::StreamOwned<rustls::ServerConnection, TcpStream>;
+
+#[cfg(feature = "tls")]
+impl ConnStream for TlsStream {
+    fn tcp(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+#[cfg(feature = "tls")]
+fn invalid_pem(path: &str, e: impl ToString) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path, e.to_string()),
+    )
+}
+
+#[cfg(feature = "tls")]
+pub(crate) fn load_tls_config(
+    cert_pem_path: &str,
+    key_pem_path: &str,
+) -> io::Result<Arc<rustls::ServerConfig>> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let certs = CertificateDer::pem_file_iter(cert_pem_path)
+        .map_err(|e| invalid_pem(cert_pem_path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid_pem(cert_pem_path, e))?;
+    let key =
+        PrivateKeyDer::from_pem_file(key_pem_path).map_err(|e| invalid_pem(key_pem_path, e))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // only HTTP/1.1 is spoken, say so rather than let a client try h2
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Whether the first bytes on `sock` can start a TLS handshake, a record of
+/// type handshake (22). Waits for them as long as the read timeout allows.
+pub(crate) fn starts_tls(sock: &TcpStream) -> io::Result<bool> {
+    let mut first = [0u8; 1];
+    match sock.peek(&mut first)? {
+        0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        _ => Ok(first[0] == 0x16),
+    }
+}
+
+/// Runs the server side of the handshake on an accepted socket. The socket's
+/// read/write timeouts are already set, so a silent client fails here
+/// instead of pinning the worker.
+#[cfg(feature = "tls")]
+pub(crate) fn accept_tls(
+    config: Arc<rustls::ServerConfig>,
+    mut sock: TcpStream,
+) -> io::Result<TlsStream> {
+    let mut conn = rustls::ServerConnection::new(config)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock)?;
+    }
+    Ok(rustls::StreamOwned::new(conn, sock))
+}
@@ -1,89 +1,224 @@
+use std::any::TypeId;
 use std::collections::HashMap;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::TrySendError;
 use std::sync::Arc;
+use std::time::Duration;
 
 pub mod thread_pool;
 
+mod auth;
 mod http_parser;
+mod static_files;
+mod tus;
 
 pub use http_parser::{
-    ConnectionData, MiddleWare, Request, Response, Router, RouterMap, RouterValue, ServerConfig,
+    evaluate_preconditions, AccessLog, AccessLogEntry, AfterMiddleWare, AfterMiddleWareVec,
+    AfterWare, AfterWareVec, AfterWrite, BodyReader, ConnStream, ConnectHandler, ConnectionData,
+    Cookie, HttpVersion, LogLevel, MiddleWare, MiddleWareTrace, MiddleWareVec, PreconditionResult,
+    Request, Response, RouteTable, Router, RouterMap, RouterValue, SameSite, ServerConfig,
+    ServerLogger, SseStream, StdoutLogger, WebSocket, WebSocketMessage,
 };
 
+#[cfg(feature = "log")]
+pub use http_parser::LogCrateLogger;
+
+pub use auth::BasicAuth;
 pub use macro_utilities::end_point;
+pub use static_files::StaticRegister;
 
 pub use http_parser::connection::http_response_table::{
     CONNECT, DELETE, GET, HEAD, OPTIONS, PATCH, POST, PUT, TRACE,
 };
 
 use http_parser::connection::http_response_table::get_httpmethod_from_code;
+use http_parser::DEFAULT_HOST;
 
 pub trait SerializationMethods {
-    fn serialize(&self) -> Vec<&'static str>;
+    fn serialize(&self) -> Vec<String>;
 }
 
 impl SerializationMethods for u8 {
-    fn serialize(&self) -> Vec<&'static str> {
+    fn serialize(&self) -> Vec<String> {
         let m = get_httpmethod_from_code(*self);
-        let mut r = Vec::new();
-        r.push(m);
-        r
+        vec![m.to_string()]
     }
 }
 
 impl SerializationMethods for &[u8] {
-    fn serialize(&self) -> Vec<&'static str> {
+    fn serialize(&self) -> Vec<String> {
         let mut r = Vec::new();
         for e in *self {
             let m = get_httpmethod_from_code(*e);
-            r.push(m);
+            r.push(m.to_string());
         }
         r
     }
 }
 
 impl<const I: usize> SerializationMethods for [u8; I] {
-    fn serialize(&self) -> Vec<&'static str> {
+    fn serialize(&self) -> Vec<String> {
         let mut r = Vec::new();
         for e in *self {
             let m = get_httpmethod_from_code(e);
-            r.push(m);
+            r.push(m.to_string());
         }
         r
     }
 }
 
+// method tokens outside the table above, e.g. the WebDAV `PROPFIND` or
+// `MKCOL`; they are matched against the request line as written
+impl SerializationMethods for &str {
+    fn serialize(&self) -> Vec<String> {
+        vec![self.to_string()]
+    }
+}
+
+impl SerializationMethods for &[&str] {
+    fn serialize(&self) -> Vec<String> {
+        self.iter().map(|m| m.to_string()).collect()
+    }
+}
+
+impl<const I: usize> SerializationMethods for [&str; I] {
+    fn serialize(&self) -> Vec<String> {
+        self.iter().map(|m| m.to_string()).collect()
+    }
+}
+
+/// Where the server listens, usually written with `end_point!`. An IPv6
+/// address such as `[::]` also takes IPv4 clients on most systems (Linux
+/// unless `net.ipv6.bindv6only` is set).
 #[derive(Debug)]
 pub struct EndPoint {
     pub port: u16,
-    pub ip_address: [u8; 4],
+    pub ip_address: IpAddr,
 }
 
 pub struct HttpServer {
     end_point: EndPoint,
     thread_number: u16,
     router: HashMap<String, RouterValue>,
+    hosts: HashMap<String, RouteTable>,
     config_: ServerConfig,
+    connect_handler: Option<Arc<dyn ConnectHandler + Send + Sync>>,
+    // picks a connection's worker, see `set_worker_affinity`
+    worker_affinity: Option<WorkerAffinity>,
+    // see `on_accept`
+    accept_filter: Option<AcceptFilter>,
+    shutdown: Arc<AtomicBool>,
+    // whether the queued connections are served after a shutdown
+    shutdown_drain: Arc<AtomicBool>,
+    worker_restarts: Arc<AtomicUsize>,
+    connection_counters: Arc<ConnectionCounters>,
+    #[cfg(feature = "tls")]
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+}
+
+type WorkerAffinity = Box<dyn Fn(&SocketAddr) -> u64 + Send>;
+type AcceptFilter = Box<dyn Fn(&SocketAddr) -> bool + Send>;
+
+/// Stops a running `HttpServer`, see `HttpServer::shutdown_handle`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    drain: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Makes `run` stop accepting connections, finish the requests already
+    /// taken, join the worker threads and return. A kept-alive connection
+    /// is closed after its current response, an idle one right away, or
+    /// over TLS once its keep-alive timeout ends. Once shut down, the server
+    /// stays so.
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    /// Like `shutdown`, but the connections still waiting for a worker get
+    /// a 503 rather than being served.
+    pub fn shutdown_now(&self) {
+        self.drain.store(false, Ordering::Relaxed);
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Reads how often a worker thread was replaced, see
+/// `HttpServer::worker_restarts`.
+#[derive(Clone)]
+pub struct WorkerRestarts {
+    count: Arc<AtomicUsize>,
+}
+
+impl WorkerRestarts {
+    pub fn get(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+struct ConnectionCounters {
+    accepted: AtomicU64,
+    queued: AtomicU64,
+    shed: AtomicU64,
+}
+
+/// Reads what became of the accepted connections, see
+/// `HttpServer::connection_stats`.
+#[derive(Clone)]
+pub struct ConnectionStats {
+    counters: Arc<ConnectionCounters>,
+}
+
+impl ConnectionStats {
+    /// Every connection taken from the listener.
+    pub fn accepted(&self) -> u64 {
+        self.counters.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Those handed on to the workers.
+    pub fn queued(&self) -> u64 {
+        self.counters.queued.load(Ordering::Relaxed)
+    }
+
+    /// Those turned away with a 503 because the queue was full.
+    pub fn shed(&self) -> u64 {
+        self.counters.shed.load(Ordering::Relaxed)
+    }
 }
 
 pub struct RouterRegister<'a> {
-    server: &'a mut HttpServer,
-    path: &'a str,
-    methods: Vec<&'a str>,
+    router: &'a mut RouteTable,
+    path: String,
+    methods: Vec<String>,
+    // run ahead of the route's own, see `RouteGroup`
+    group_middlewares: Option<MiddleWareVec>,
+    after_middlewares: Option<AfterMiddleWareVec>,
 }
 
 impl<'a> RouterRegister<'a> {
+    /// Middlewares run after the router, or after a middleware stopped the
+    /// chain, last registered first. Set them before calling `reg`.
+    pub fn after_middlewares(
+        &mut self,
+        middlewares: Vec<Arc<dyn AfterMiddleWare + Send + Sync>>,
+    ) -> &mut Self {
+        self.after_middlewares = match self.after_middlewares.take() {
+            Some(group) => Some(group.into_iter().chain(middlewares).collect()),
+            None => Some(middlewares),
+        };
+        self
+    }
+
     pub fn reg<F>(&mut self, f: F)
     where
         F: Router + Send + Sync + 'static + Clone,
     {
-        for e in &self.methods {
-            let router_path = format!("{}{}", e, self.path);
-            self.server
-                .router
-                .insert(router_path, (None, Arc::new(f.clone())));
-        }
+        let middlewares = self.group_middlewares.clone();
+        self.insert(middlewares, None, f);
     }
 
     pub fn reg_with_middlewares<F>(
@@ -92,45 +227,156 @@ impl<'a> RouterRegister<'a> {
         f: F,
     ) where
         F: Router + Send + Sync + 'static + Clone,
+    {
+        let middlewares = match &self.group_middlewares {
+            Some(group) => group.iter().cloned().chain(middlewares).collect(),
+            None => middlewares,
+        };
+        self.insert(Some(middlewares), None, f);
+    }
+
+    /// Like `reg_with_middlewares`, with `hooks` that see this route's
+    /// response once it is final, ahead of those `HttpServer::after` added;
+    /// they run even when a middleware stopped the chain.
+    pub fn reg_with_hooks<F>(
+        &mut self,
+        middlewares: Vec<Arc<dyn MiddleWare + Send + Sync>>,
+        hooks: Vec<Arc<dyn AfterWare + Send + Sync>>,
+        f: F,
+    ) where
+        F: Router + Send + Sync + 'static + Clone,
+    {
+        let middlewares = match &self.group_middlewares {
+            Some(group) => group.iter().cloned().chain(middlewares).collect(),
+            None => middlewares,
+        };
+        self.insert(Some(middlewares), Some(hooks), f);
+    }
+
+    fn insert<F>(&mut self, middlewares: Option<MiddleWareVec>, hooks: Option<AfterWareVec>, f: F)
+    where
+        F: Router + Send + Sync + 'static + Clone,
     {
         for e in &self.methods {
             let router_path = format!("{}{}", e, self.path);
-            self.server.router.insert(
+            self.router.insert(
                 router_path,
-                (Some(middlewares.clone()), Arc::new(f.clone())),
+                (
+                    middlewares.clone(),
+                    Arc::new(f.clone()),
+                    self.after_middlewares.clone(),
+                    hooks.clone(),
+                ),
             );
         }
     }
 }
 
+/// Routes sharing a path prefix and middlewares, see `HttpServer::group`.
+pub struct RouteGroup<'a> {
+    router: &'a mut RouteTable,
+    prefix: String,
+    middlewares: Option<MiddleWareVec>,
+    after_middlewares: Option<AfterMiddleWareVec>,
+}
+
+impl<'a> RouteGroup<'a> {
+    /// Middlewares run for every route registered through this group from
+    /// now on, before those given to `reg_with_middlewares`.
+    pub fn middlewares(
+        &mut self,
+        middlewares: Vec<Arc<dyn MiddleWare + Send + Sync>>,
+    ) -> &mut Self {
+        self.middlewares = Some(middlewares);
+        self
+    }
+
+    /// After-middlewares for every route registered through this group
+    /// from now on, run after those the route adds itself.
+    pub fn after_middlewares(
+        &mut self,
+        middlewares: Vec<Arc<dyn AfterMiddleWare + Send + Sync>>,
+    ) -> &mut Self {
+        self.after_middlewares = Some(middlewares);
+        self
+    }
+
+    /// Like `HttpServer::route`, with the group's prefix put in front of
+    /// `path`.
+    pub fn route<T: SerializationMethods>(&mut self, methods: T, path: &str) -> RouterRegister<'_> {
+        let path = format!("{}{}", self.prefix, path.trim());
+        let mut register = register(self.router, methods, &path);
+        register.group_middlewares = self.middlewares.clone();
+        register.after_middlewares = self.after_middlewares.clone();
+        register
+    }
+}
+
 impl HttpServer {
     pub fn create(end: EndPoint, count: u16) -> Self {
         Self {
             end_point: end,
             thread_number: count,
             router: HashMap::new(),
+            hosts: HashMap::new(),
             config_: ServerConfig {
                 upload_directory: String::from("./upload"),
                 read_timeout: 5 * 1000,
                 chunk_size: 1024 * 5,
+                flush_each_chunk: true,
                 write_timeout: 5 * 1000,
                 open_log: false,
                 max_body_size: 3 * 1024 * 1024,
+                truncate_body: false,
+                max_response_memory: 0,
                 max_header_size: 3 * 1024 * 1024,
                 read_buff_increase_size: 1024,
+                upload_write_buffer: 64 * 1024,
+                expect_continue: true,
+                keep_alive_timeout: 5 * 1000,
+                header_timeout: 30 * 1000,
+                handler_timeout: 0,
+                max_pending_connections: 0,
+                max_keep_alive_requests: 100,
+                enable_trace: false,
+                json_errors: false,
+                on_request: Vec::new(),
+                internal_error: None,
+                after_middlewares: Vec::new(),
+                after: Vec::new(),
+                after_write: Vec::new(),
+                access_log: Vec::new(),
+                state: HashMap::new(),
+                #[cfg(feature = "log")]
+                logger: Arc::new(http_parser::LogCrateLogger),
+                #[cfg(not(feature = "log"))]
+                logger: Arc::new(StdoutLogger),
+                #[cfg(feature = "compression")]
+                compression: None,
             },
+            connect_handler: None,
+            worker_affinity: None,
+            accept_filter: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            shutdown_drain: Arc::new(AtomicBool::new(true)),
+            worker_restarts: Arc::new(AtomicUsize::new(0)),
+            connection_counters: Arc::default(),
+            #[cfg(feature = "tls")]
+            tls_config: None,
         }
     }
 
     fn create_directory(&self) -> io::Result<bool> {
-        let _ = std::fs::create_dir(self.config_.upload_directory.clone())?;
+        std::fs::create_dir(self.config_.upload_directory.clone())?;
         Ok(true)
     }
 
+    /// How long a read from a client may block, 0 for no timeout.
     pub fn set_read_timeout(&mut self, millis: u32) {
         self.config_.read_timeout = millis;
     }
 
+    /// How long a write to a client may block, 0 for no timeout.
     pub fn set_write_timeout(&mut self, millis: u32) {
         self.config_.write_timeout = millis;
     }
@@ -139,104 +385,545 @@ impl HttpServer {
         self.config_.chunk_size = size;
     }
 
+    /// Flush the connection after every chunk of a chunked body, on by
+    /// default. Turned off, the body is flushed once at its end, which saves
+    /// work for many small chunks when the client needn't see each one
+    /// as soon as it is written.
+    pub fn set_flush_each_chunk(&mut self, enable: bool) {
+        self.config_.flush_each_chunk = enable;
+    }
+
+    /// Off by default. Turned off, no line reaches the logger whichever it is.
     pub fn open_server_log(&mut self, open: bool) {
         self.config_.open_log = open;
     }
 
+    /// Sends the server's log lines to `logger` rather than the `log` crate,
+    /// or stdout without the `log` feature. A closure taking the `LogLevel`
+    /// and the message will do. Nothing is logged until
+    /// `open_server_log(true)`.
+    pub fn set_logger<L>(&mut self, logger: L)
+    where
+        L: ServerLogger + Send + Sync + 'static,
+    {
+        self.config_.logger = Arc::new(logger);
+    }
+
+    /// No smaller than `set_read_buff_increase_size`, `run` refuses that.
     pub fn set_max_body_size(&mut self, size: usize) {
-        self.config_.max_header_size = size;
+        self.config_.max_body_size = size;
+    }
+
+    /// Instead of refusing a body whose `Content-Length` is over the max
+    /// body size with 413, read that much of it and let the handler see it
+    /// with `Request::body_is_truncated`. The rest is never read, so the
+    /// connection is closed after the response. Off by default; chunked
+    /// bodies are still refused.
+    pub fn set_truncate_oversized_body(&mut self, enable: bool) {
+        self.config_.truncate_body = enable;
+    }
+
+    /// Largest body a handler may build in memory, 0 for no limit. A larger
+    /// one is replaced by a 500.
+    pub fn set_max_response_memory(&mut self, size: usize) {
+        self.config_.max_response_memory = size;
     }
 
     pub fn set_max_header_size(&mut self, size: usize) {
-        self.config_.max_body_size = size;
+        self.config_.max_header_size = size;
     }
 
-	pub fn set_read_buff_increase_size(&mut self, size: usize){
+    pub fn set_read_buff_increase_size(&mut self, size: usize) {
         self.config_.read_buff_increase_size = size;
-	}
+    }
+
+    /// How many bytes of an uploaded file are gathered before each write to
+    /// disk.
+    pub fn set_upload_write_buffer(&mut self, size: usize) {
+        self.config_.upload_write_buffer = size;
+    }
+
+    /// How long a kept-alive connection may sit idle between requests, 0 for
+    /// no limit. A worker doesn't keep one while another connection waits
+    /// with no worker free: the current response closes it, and over plain
+    /// HTTP so does the idle wait the moment such a connection arrives.
+    pub fn set_keep_alive_timeout(&mut self, millis: u32) {
+        self.config_.keep_alive_timeout = millis;
+    }
+
+    /// How many accepted connections may wait for a free worker, 0 (the
+    /// default) for no limit. Past it a new connection gets a 503 and is
+    /// closed, rather than queued without bound under a flood; it carries
+    /// `Retry-After: 1`, and `connection_stats` counts it as shed. With
+    /// `set_worker_affinity` the limit is per worker.
+    pub fn set_max_pending_connections(&mut self, count: usize) {
+        self.config_.max_pending_connections = count;
+    }
 
-    pub fn run(&mut self) {
-        let [a, b, c, d] = self.end_point.ip_address;
-        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), self.end_point.port);
-        let listen = TcpListener::bind(socket);
+    /// Pins each connection to the worker `f` picks from the client's
+    /// address, the result taken modulo the number of workers; e.g. a hash
+    /// of the IP, so a client keeps landing on the same worker and a
+    /// `thread_local!` cache serves it without locking. Each worker then
+    /// has its own queue, so a busy one isn't helped out by the others. By
+    /// default connections go to whichever worker is free.
+    pub fn set_worker_affinity<F>(&mut self, f: F)
+    where
+        F: Fn(&SocketAddr) -> u64 + Send + 'static,
+    {
+        self.worker_affinity = Some(Box::new(f));
+    }
+
+    /// Asks `f` about each connection as it is accepted, before anything is
+    /// read from it; `false` closes it right away, without a response and
+    /// without taking up a worker. Meant for connection-level defenses such
+    /// as a blocklist or a per-IP connection rate, `f` runs on the thread
+    /// in `run` so it should be quick.
+    pub fn on_accept<F>(&mut self, f: F)
+    where
+        F: Fn(&SocketAddr) -> bool + Send + 'static,
+    {
+        self.accept_filter = Some(Box::new(f));
+    }
+
+    /// Answer a request with 504 once its middlewares and router have run
+    /// for `millis`, 0 (the default) for no limit. Each connection is then
+    /// served on a thread of its own that the worker watches; a handler
+    /// can't be stopped, so its thread is abandoned to it and only ends if
+    /// the handler does. A handler that writes to the connection itself,
+    /// e.g. with `write_sse` or `upgrade_websocket`, counts as running all
+    /// along and is cut off too.
+    pub fn set_handler_timeout(&mut self, millis: u32) {
+        self.config_.handler_timeout = millis;
+    }
+
+    /// How long a client may take to send a whole request head, counted
+    /// from its first byte; 30 seconds by default, 0 for no limit. The read
+    /// timeout alone starts over with each byte, so it can't stop a client
+    /// that holds the connection by dribbling its head. Such a client, like
+    /// one that stops halfway through its head, gets a 408.
+    pub fn set_header_timeout(&mut self, millis: u32) {
+        self.config_.header_timeout = millis;
+    }
+
+    /// Close a connection after it has served this many requests, 0 for no limit.
+    pub fn set_max_keep_alive_requests(&mut self, count: u32) {
+        self.config_.max_keep_alive_requests = count;
+    }
+
+    /// Answer `Expect: 100-continue` before reading the body, on by default.
+    pub fn set_expect_continue(&mut self, enable: bool) {
+        self.config_.expect_continue = enable;
+    }
+
+    /// Answer TRACE by echoing the request back, without its `Cookie` and
+    /// `Authorization` headers. Off by default, in which case TRACE gets a
+    /// 405.
+    pub fn enable_trace(&mut self) {
+        self.config_.enable_trace = true;
+    }
+
+    /// Answer the requests the server refuses itself (malformed, too large,
+    /// a panicking handler, ...) with a JSON body as `Response::json_error`
+    /// writes, instead of plain text.
+    pub fn set_json_errors(&mut self, enable: bool) {
+        self.config_.json_errors = enable;
+    }
+
+    /// Shares `state` with every handler, which reads it back with
+    /// `Request::state::<S>()`; one value per type, a later one of the same
+    /// type replaces it. E.g. a database pool, without an `Arc` cloned into
+    /// each closure.
+    pub fn set_state<S: Send + Sync + 'static>(&mut self, state: S) {
+        self.config_
+            .state
+            .insert(TypeId::of::<S>(), Arc::new(state));
+    }
+
+    /// Runs `f` for every request before it is routed, e.g. to assign a
+    /// request id or start a timer. Returning `false` answers the request
+    /// with what `f` left in the response, and neither the route nor its
+    /// middlewares run. Hooks run in the order they were added, all of them
+    /// ahead of any route middleware; the after-middlewares still run.
+    pub fn on_request<F>(&mut self, f: F)
+    where
+        F: Fn(&Request, &mut Response) -> bool + Send + Sync + 'static,
+    {
+        self.config_.on_request.push(Arc::new(f));
+    }
+
+    /// Runs `f` with every response once it is final, just before it is
+    /// written: after the route, its middlewares and the after-middlewares,
+    /// short-circuited and unmatched requests included. `f` can only look,
+    /// e.g. for an access log with the status. Hooks run in the order they
+    /// were added, after the route's own, see `RouterRegister::reg_with_hooks`.
+    pub fn after<F>(&mut self, f: F)
+    where
+        F: AfterWare + Send + Sync + 'static,
+    {
+        self.config_.after.push(Arc::new(f));
+    }
+
+    /// Runs `f` once each response has been written, or failed to be, with
+    /// whether it was; e.g. for an access log or metrics. Hooks run in the
+    /// order they were added, for every response the router produced,
+    /// short-circuited and unmatched requests included.
+    pub fn after_write<F>(&mut self, f: F)
+    where
+        F: Fn(&Request, &Response, bool) + Send + Sync + 'static,
+    {
+        self.config_.after_write.push(Arc::new(f));
+    }
+
+    /// Runs `f` with an `AccessLogEntry` (method, url, status, bytes sent,
+    /// time taken) once each response has been written, or failed to be;
+    /// e.g. for one access log line per request. Nothing is collected
+    /// without a hook.
+    pub fn access_log<F>(&mut self, f: F)
+    where
+        F: Fn(&AccessLogEntry) + Send + Sync + 'static,
+    {
+        self.config_.access_log.push(Arc::new(f));
+    }
+
+    /// After-middlewares for every request but `TRACE`, the unmatched ones
+    /// and those an `on_request` hook stopped included. They run once the
+    /// route's own are done, last registered first.
+    pub fn set_after_middlewares(
+        &mut self,
+        middlewares: Vec<Arc<dyn AfterMiddleWare + Send + Sync>>,
+    ) {
+        self.config_.after_middlewares = middlewares;
+    }
+
+    /// Compress in-memory bodies and files of at least `min_size` bytes, and
+    /// chunked streams, with gzip or deflate for clients that accept it.
+    /// `content_types` lists the media types to compress, e.g.
+    /// `["application/json", "text/html"]`; an empty list compresses any type
+    /// that isn't compressed already. Ranges are sent as is.
+    #[cfg(feature = "compression")]
+    pub fn enable_compression(&mut self, min_size: usize, content_types: &[&str]) {
+        self.config_.compression = Some(http_parser::compress::CompressionConfig::new(
+            min_size,
+            content_types,
+        ));
+    }
+
+    /// Serve HTTPS instead of plain HTTP. Both files are PEM encoded; the
+    /// certificate file may hold the whole chain.
+    #[cfg(feature = "tls")]
+    pub fn enable_tls(&mut self, cert_pem_path: &str, key_pem_path: &str) -> io::Result<()> {
+        let config = http_parser::stream::load_tls_config(cert_pem_path, key_pem_path)?;
+        self.tls_config = Some(config);
+        Ok(())
+    }
+
+    /// Listens and serves until stopped with a `ShutdownHandle`, then returns
+    /// once the requests in flight are done. Errors before that, a
+    /// setting the server can't work with, an upload directory that can't be
+    /// created or an address that can't be bound, are returned.
+    pub fn run(&mut self) -> io::Result<()> {
+        if self.thread_number == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "thread number must be greater than 0",
+            ));
+        }
+        self.config_.validate()?;
+        let socket = SocketAddr::new(self.end_point.ip_address, self.end_point.port);
         self.not_found_default_if_not_set();
         match self.create_directory() {
             Ok(_) => {}
-            Err(e) => match e.kind() {
-                io::ErrorKind::AlreadyExists => {}
-                _ => {
-                    panic!("{}", e.to_string())
-                }
-            },
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
         };
-        let safe_router = Arc::new(self.router.clone());
+        let listen = TcpListener::bind(socket)?;
+        let mut router_map = self.hosts.clone();
+        router_map.insert(String::from(DEFAULT_HOST), self.router.clone());
+        let safe_router = Arc::new(router_map);
         let conn_data = Arc::new(ConnectionData {
             router_map: safe_router,
             server_config: self.config_.clone(),
+            connect_handler: self.connect_handler.clone(),
+            shutdown: Arc::clone(&self.shutdown),
+            #[cfg(feature = "tls")]
+            tls_config: self.tls_config.clone(),
         });
-        match listen {
-            Ok(x) => {
-                let mut pool =
-                    thread_pool::ThreadPool::new(self.thread_number, http_parser::handle_incoming);
-                for conn in x.incoming() {
-                    match conn {
-                        Ok(stream) => {
-                            let conn_data = conn_data.clone();
-                            match pool.poll((conn_data, stream)) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    if self.config_.open_log {
-                                        println!("Send Connection Error: {}", e.to_string());
-                                    }
-                                }
-                            }
+        let mut pool = thread_pool::ThreadPool::new(
+            self.thread_number,
+            self.config_.max_pending_connections,
+            self.worker_affinity.is_some(),
+            http_parser::handle_incoming,
+            Arc::clone(&self.worker_restarts),
+        );
+        let counters = Arc::clone(&self.connection_counters);
+        let rejecter = http_parser::Rejecter::start();
+        // polled rather than blocking in accept, so a shutdown is noticed
+        listen.set_nonblocking(true)?;
+        while !self.shutdown.load(Ordering::Relaxed) {
+            match listen.accept() {
+                Ok((stream, peer)) => {
+                    counters.accepted.fetch_add(1, Ordering::Relaxed);
+                    if let Some(filter) = &self.accept_filter {
+                        if !filter(&peer) {
+                            self.config_.log(
+                                LogLevel::Debug,
+                                format_args!("{}: refused by on_accept", peer),
+                            );
+                            continue;
+                        }
+                    }
+                    // the worker reads with timeouts, which need a blocking socket
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        self.config_.log(
+                            LogLevel::Error,
+                            format_args!("{}: on connection error:{}", peer, e),
+                        );
+                        continue;
+                    }
+                    let conn_data = conn_data.clone();
+                    let worker = self.worker_affinity.as_ref().map(|f| f(&peer));
+                    match pool.poll((conn_data, stream), worker) {
+                        Ok(_) => {
+                            counters.queued.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(TrySendError::Full((conn_data, stream))) => {
+                            counters.shed.fetch_add(1, Ordering::Relaxed);
+                            self.config_.log(
+                                LogLevel::Warn,
+                                format_args!("{}: connection queue is full, refused", peer),
+                            );
+                            rejecter.reject_unavailable(&conn_data, stream, "the server is busy");
                         }
                         Err(e) => {
-                            if self.config_.open_log {
-                                println!("on connection error:{}", e.to_string());
-                            }
+                            self.config_.log(
+                                LogLevel::Error,
+                                format_args!("{}: Send Connection Error: {}", peer, e),
+                            );
                         }
                     }
                 }
-                pool.join();
-            }
-            Err(e) => {
-                panic!("listen error, the reason is: {}", e.to_string());
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => {
+                    self.config_
+                        .log(LogLevel::Error, format_args!("on connection error:{}", e));
+                }
             }
         }
+        drop(listen);
+        pool.shutdown(
+            self.shutdown_drain.load(Ordering::Relaxed),
+            |(conn_data, stream)| {
+                rejecter.reject_unavailable(&conn_data, stream, "the server is shutting down")
+            },
+        );
+        Ok(())
+    }
+
+    /// A handle that stops `run` from another thread, e.g. on a signal or
+    /// at the end of a test. Take it before calling `run`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            flag: Arc::clone(&self.shutdown),
+            drain: Arc::clone(&self.shutdown_drain),
+        }
+    }
+
+    /// Counts the worker threads that ended and were replaced, the
+    /// connections queued for them go to the new one. A panic that got past
+    /// the per-connection guard doesn't count: the worker catches it, loses
+    /// that connection and goes on.
+    pub fn worker_restarts(&self) -> WorkerRestarts {
+        WorkerRestarts {
+            count: Arc::clone(&self.worker_restarts),
+        }
+    }
+
+    /// Counts the connections accepted, queued for a worker and shed with
+    /// a 503 under `set_max_pending_connections`, e.g. for a metrics
+    /// endpoint. Take it before calling `run`.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            counters: Arc::clone(&self.connection_counters),
+        }
     }
 
     pub fn route<'a, T: SerializationMethods>(
         &'a mut self,
         methods: T,
         path: &'a str,
-    ) -> RouterRegister<'_> {
-        //let method = get_httpmethod_from_code(M);
-        if path.trim() == "/*" {
-            panic!("/* => wildcard of root path is not permitted!")
+    ) -> RouterRegister<'a> {
+        register(&mut self.router, methods, path)
+    }
+
+    /// Groups routes under `prefix`, e.g. `group("/api/v1").route(GET,
+    /// "/users")` registers `/api/v1/users`. Middlewares set on the group
+    /// apply to each route registered through it.
+    pub fn group(&mut self, prefix: &str) -> RouteGroup<'_> {
+        RouteGroup {
+            router: &mut self.router,
+            prefix: prefix.trim().trim_end_matches('/').to_string(),
+            middlewares: None,
+            after_middlewares: None,
         }
-        RouterRegister {
-            server: self,
-            methods: methods.serialize(),
-            path,
+    }
+
+    /// Serves the files under `dir` at `url_prefix`, e.g.
+    /// `serve_static("/assets", "./public")` answers `/assets/app.js` with
+    /// `./public/app.js`. A directory gets its `index.html`, or a listing with
+    /// `auto_index`; anything missing gets a 404 and a path leading outside
+    /// `dir` a 403. Ranges and `If-None-Match` are supported. With the
+    /// prefix `/` the files answer any path that no other route takes.
+    pub fn serve_static(&mut self, url_prefix: &str, dir: &str) -> StaticRegister<'_> {
+        StaticRegister::new(&mut self.router, url_prefix, dir)
+    }
+
+    /// The same as `serve_static`, e.g. `serve_dir("/static", "./public")`.
+    pub fn serve_dir(&mut self, url_prefix: &str, dir: &str) -> StaticRegister<'_> {
+        self.serve_static(url_prefix, dir)
+    }
+
+    /// Serves the file at `fs_path` for `GET` and `HEAD` on `url`, e.g.
+    /// `serve_file_at("/favicon.ico", "./public/favicon.ico")`, with the
+    /// same type, range and `If-None-Match` handling as `serve_static`.
+    pub fn serve_file_at(&mut self, url: &str, fs_path: &str) {
+        register(&mut self.router, [GET, HEAD], url.trim())
+            .reg(static_files::StaticFile::new(fs_path));
+    }
+
+    /// Accepts resumable uploads under `url_prefix` with the tus protocol
+    /// (1.0.0 core and the creation extension), storing them in `dir`. A
+    /// `POST` to `url_prefix` creates an upload; `HEAD` and `PATCH` on the
+    /// returned `Location` resume it. A `PATCH` body is written to the upload
+    /// as it arrives, though one declaring more than `set_max_body_size` is
+    /// still refused with a 413.
+    pub fn serve_tus(&mut self, url_prefix: &str, dir: &str) {
+        let prefix = url_prefix.trim().trim_end_matches('/');
+        let handler = tus::TusDir::new(prefix, dir);
+        register(&mut self.router, [POST, OPTIONS], prefix).reg(handler.clone());
+        let uploads = format!("{}/*", prefix);
+        register(&mut self.router, [HEAD, PATCH, OPTIONS], &uploads).reg(handler);
+    }
+
+    /// Routes that only answer requests whose `Host` is `name` (any port,
+    /// case-insensitive). Paths it doesn't register fall back to the ones
+    /// added through `route`.
+    pub fn host(&mut self, name: &str) -> VirtualHost<'_> {
+        VirtualHost {
+            router: self.hosts.entry(name.to_lowercase()).or_default(),
         }
     }
 
+    /// Answers requests for a host that none of `host` registered and that
+    /// no default route takes either, 404 by default. Only consulted once
+    /// some virtual host exists.
+    pub fn set_unknown_host<F>(&mut self, f: F)
+    where
+        F: Router + Send + Sync + 'static,
+    {
+        self.router.insert(
+            String::from("UNKNOWN_HOST_FOR_ALL"),
+            (None, Arc::new(f), None, None),
+        );
+    }
+
     pub fn set_not_found<F>(&mut self, f: F)
     where
         F: Router + Send + Sync + 'static,
     {
-        self.router
-            .insert(String::from("NEVER_FOUND_FOR_ALL"), (None, Arc::new(f)));
+        self.router.insert(
+            String::from("NEVER_FOUND_FOR_ALL"),
+            (None, Arc::new(f), None, None),
+        );
+    }
+
+    /// Answers in place of a route or middleware that panicked. `f` gets a
+    /// response reset to an empty 500, and should it panic as well the
+    /// default `internal server error` goes out.
+    pub fn set_internal_error<F>(&mut self, f: F)
+    where
+        F: Router + Send + Sync + 'static,
+    {
+        self.config_.internal_error = Some(Arc::new(f));
+    }
+
+    /// Hand `CONNECT` requests to `f` instead of the router, for forward
+    /// proxies. The handler owns the socket from then on, see
+    /// [`ConnectHandler`]. Not available on a TLS listener.
+    pub fn on_connect<F>(&mut self, f: F)
+    where
+        F: ConnectHandler + Send + Sync + 'static,
+    {
+        self.connect_handler = Some(Arc::new(f));
     }
 
     fn not_found_default_if_not_set(&mut self) {
         let r = &self.router.get(&String::from("NEVER_FOUND_FOR_ALL"));
-        if let None = *r {
+        if r.is_none() {
             self.set_not_found(|_req: &Request, res: &mut Response| {
                 res.write_state(404);
             });
         }
+        let r = &self.router.get(&String::from("UNKNOWN_HOST_FOR_ALL"));
+        if r.is_none() {
+            self.set_unknown_host(|_req: &Request, res: &mut Response| {
+                res.write_state(404);
+            });
+        }
+    }
+}
+
+pub struct VirtualHost<'a> {
+    router: &'a mut RouteTable,
+}
+
+impl<'a> VirtualHost<'a> {
+    pub fn route<'b, T: SerializationMethods>(
+        &'b mut self,
+        methods: T,
+        path: &'b str,
+    ) -> RouterRegister<'b> {
+        register(self.router, methods, path)
+    }
+}
+
+fn register<'a, T: SerializationMethods>(
+    router: &'a mut RouteTable,
+    methods: T,
+    path: &str,
+) -> RouterRegister<'a> {
+    //let method = get_httpmethod_from_code(M);
+    if path.trim() == "/*" {
+        panic!("/* => wildcard of root path is not permitted!")
+    }
+    register_path(router, methods, path)
+}
+
+/// `register` that takes `/*` too, for `serve_static("/", ..)`: its files
+/// answer whatever path no other route does.
+fn register_path<'a, T: SerializationMethods>(
+    router: &'a mut RouteTable,
+    methods: T,
+    path: &str,
+) -> RouterRegister<'a> {
+    let methods = methods.serialize();
+    // a method is an RFC 9110 token, e.g. `PURGE`; anything else could never
+    // match a request line and is most likely a typo
+    for m in &methods {
+        let is_token = !m.is_empty()
+            && m.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !is_token {
+            panic!("{:?} is not a valid http method", m)
+        }
+    }
+    RouterRegister {
+        router,
+        methods,
+        path: path.to_string(),
+        group_middlewares: None,
+        after_middlewares: None,
     }
 }
 
@@ -252,6 +939,30 @@ macro_rules! inject_middlewares {
 	};
 }
 
+#[macro_export]
+macro_rules! inject_after_middlewares {
+	($($m:expr),*) => {
+		{
+			use std::sync::Arc;
+			type T = Arc<dyn AfterMiddleWare + Send + Sync>;
+			let x = vec![$( Arc::new($m) as T ,)*];
+			x
+		}
+	};
+}
+
+#[macro_export]
+macro_rules! inject_hooks {
+	($($m:expr),*) => {
+		{
+			use std::sync::Arc;
+			type T = Arc<dyn AfterWare + Send + Sync>;
+			let x = vec![$( Arc::new($m) as T ,)*];
+			x
+		}
+	};
+}
+
 // #[macro_export]
 // macro_rules! end_point {
 //     ($a:expr,$b:expr,$c:expr,$d:expr ; $port:expr) => {{
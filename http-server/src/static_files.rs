@@ -0,0 +1,297 @@
+use std::path::{Path, PathBuf};
+
+use crate::http_parser::connection::date::http_date;
+use crate::{register_path, Request, Response, RouteTable, Router, GET, HEAD};
+
+/// Serves the files under `root` for the wildcard route registered by
+/// `HttpServer::serve_static`.
+#[derive(Clone)]
+struct StaticDir {
+    prefix: String,
+    root: PathBuf,
+    index: bool,
+    auto_index: bool,
+    show_hidden: bool,
+}
+
+enum Target {
+    File(PathBuf),
+    // a directory without an index.html
+    Dir(PathBuf),
+    // a path leading out of `root`, with `..` or through a symlink
+    Outside,
+}
+
+impl StaticDir {
+    /// What a request path maps to, or `None` when it doesn't exist.
+    fn resolve(&self, path: &str) -> Option<Target> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        if !rest.starts_with('/') {
+            return None;
+        }
+        // decoded before the check, so `%2e%2e/` is caught like `../`
+        let rest = String::from_utf8(percent_decode(rest)?).ok()?;
+        if rest.contains('\0') {
+            return None;
+        }
+        // `..` past the root is refused whether or not the target exists
+        let mut depth = 0usize;
+        for segment in rest.split(['/', '\\']) {
+            match segment {
+                "" | "." => {}
+                ".." if depth == 0 => return Some(Target::Outside),
+                ".." => depth -= 1,
+                _ => depth += 1,
+            }
+        }
+        let root = self.root.canonicalize().ok()?;
+        let mut file = root
+            .join(rest.trim_start_matches('/'))
+            .canonicalize()
+            .ok()?;
+        // symlinks are resolved too, so this also keeps them from leading out
+        if !file.starts_with(&root) {
+            return Some(Target::Outside);
+        }
+        if file.is_dir() {
+            match file.join("index.html").canonicalize() {
+                Ok(index) if self.index => file = index,
+                _ if self.auto_index => return Some(Target::Dir(file)),
+                _ => return None,
+            }
+        }
+        if !file.starts_with(&root) {
+            return Some(Target::Outside);
+        }
+        if !file.is_file() {
+            return None;
+        }
+        Some(Target::File(file))
+    }
+
+    fn listing(&self, dir: &Path, path: &str) -> Option<String> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(dir).ok()?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') && !self.show_hidden {
+                continue;
+            }
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            entries.push((meta.is_dir(), name, meta));
+        }
+        // directories first, then by name
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        // `path` is what the client sent, so it is still percent-encoded
+        let base = if path.ends_with('/') {
+            path.to_string()
+        } else {
+            format!("{}/", path)
+        };
+        let title = html_escape(&String::from_utf8_lossy(&percent_decode(&base)?));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body><h1>Index of {0}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+            title
+        );
+        if base != format!("{}/", self.prefix) {
+            let parent = base
+                .trim_end_matches('/')
+                .rsplit_once('/')
+                .map_or("", |p| p.0);
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n",
+                html_escape(parent)
+            ));
+        }
+        for (is_dir, name, meta) in entries {
+            let slash = if is_dir { "/" } else { "" };
+            let size = if is_dir {
+                String::from("-")
+            } else {
+                meta.len().to_string()
+            };
+            let modified = meta.modified().map(http_date).unwrap_or_default();
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                html_escape(&base),
+                percent_encode(&name),
+                slash,
+                html_escape(&name),
+                slash,
+                size,
+                modified
+            ));
+        }
+        html.push_str("</table>\n</body></html>\n");
+        Some(html)
+    }
+}
+
+impl Router for StaticDir {
+    fn call(&self, req: &Request, res: &mut Response) {
+        let url = req.get_url();
+        let path = url.split_once('?').map_or(url, |(path, _)| path);
+        match self.resolve(path) {
+            Some(Target::File(file)) => {
+                res.write_file(file.to_string_lossy().into_owned())
+                    .enable_cache()
+                    .enable_range();
+            }
+            Some(Target::Dir(dir)) => match self.listing(&dir, path) {
+                Some(html) => {
+                    res.add_header(
+                        String::from("Content-Type"),
+                        String::from("text/html; charset=utf-8"),
+                    );
+                    res.write_string(&html);
+                }
+                None => res.write_state(404),
+            },
+            Some(Target::Outside) => res.write_state(403),
+            None => res.write_state(404),
+        }
+    }
+}
+
+/// Serves one file for the route registered by `HttpServer::serve_file_at`.
+#[derive(Clone)]
+pub(crate) struct StaticFile {
+    path: PathBuf,
+}
+
+impl StaticFile {
+    pub(crate) fn new(path: &str) -> Self {
+        StaticFile {
+            path: PathBuf::from(path),
+        }
+    }
+}
+
+impl Router for StaticFile {
+    fn call(&self, _req: &Request, res: &mut Response) {
+        // checked here, `write_file` would put the path in its 404
+        if !self.path.is_file() {
+            res.write_state(404);
+            return;
+        }
+        res.write_file(self.path.to_string_lossy().into_owned())
+            .enable_cache()
+            .enable_range();
+    }
+}
+
+/// Returned by `HttpServer::serve_static` to adjust the route it added.
+pub struct StaticRegister<'a> {
+    router: &'a mut RouteTable,
+    path: String,
+    dir: StaticDir,
+}
+
+impl<'a> StaticRegister<'a> {
+    pub(crate) fn new(router: &'a mut RouteTable, url_prefix: &str, dir: &str) -> Self {
+        let prefix = url_prefix.trim().trim_end_matches('/').to_string();
+        let mut register = StaticRegister {
+            router,
+            path: format!("{}/*", prefix),
+            dir: StaticDir {
+                prefix,
+                root: Path::new(dir).to_path_buf(),
+                index: true,
+                auto_index: false,
+                show_hidden: false,
+            },
+        };
+        register.reg();
+        register
+    }
+
+    fn reg(&mut self) {
+        register_path(self.router, [GET, HEAD], &self.path).reg(self.dir.clone());
+    }
+
+    /// Answer a directory with its `index.html`, on by default. Without it
+    /// a directory gets a 404, or its listing with `auto_index`.
+    pub fn index(&mut self, enable: bool) -> &mut Self {
+        self.dir.index = enable;
+        self.reg();
+        self
+    }
+
+    /// List a directory that has no `index.html` instead of answering 404.
+    pub fn auto_index(&mut self, enable: bool) -> &mut Self {
+        self.dir.auto_index = enable;
+        self.reg();
+        self
+    }
+
+    /// Include dotfiles in directory listings, off by default.
+    pub fn show_hidden(&mut self, enable: bool) -> &mut Self {
+        self.dir.show_hidden = enable;
+        self.reg();
+        self
+    }
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            // from_str_radix alone would take a sign, `%+1` among them
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            out.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::percent_decode;
+
+    #[test]
+    fn decodes_only_two_hex_digits() {
+        assert_eq!(percent_decode("a%2Fb%2e").unwrap(), b"a/b.");
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%-1"), None);
+        assert_eq!(percent_decode("%g0"), None);
+        assert_eq!(percent_decode("%2"), None);
+    }
+}
@@ -1,69 +1,364 @@
-use std::sync::mpsc::{self, SendError, Sender};
+use std::net::{Shutdown, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
+/// How busy the workers taking from one queue are, handed to the pool's
+/// function along with each item; e.g. to hold on to a kept-alive
+/// connection only while nothing else needs the worker.
+#[derive(Default)]
+pub struct WorkerLoad {
+    // items in the queue
+    queued: AtomicUsize,
+    // workers of the queue waiting for an item
+    idle: AtomicUsize,
+    // kept-alive connections idling on these workers, see `park`
+    parked: Mutex<Vec<(u64, TcpStream)>>,
+    next_id: AtomicU64,
+}
+
+impl WorkerLoad {
+    /// Whether an item waits that no idle worker is there to take.
+    pub(crate) fn backed_up(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) > self.idle.load(Ordering::SeqCst)
+    }
+
+    /// Registers `sock` as idle between requests until the guard is
+    /// dropped. Should an item come that no worker is free for, `poll`
+    /// shuts its read side, which ends a read waiting on it at once.
+    pub(crate) fn park(&self, sock: &TcpStream) -> Option<Parked<'_>> {
+        let sock = sock.try_clone().ok()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock_parked().push((id, sock));
+        Some(Parked { load: self, id })
+    }
+
+    fn wake(&self, all: bool) {
+        let mut parked = self.lock_parked();
+        let count = if all {
+            parked.len()
+        } else {
+            parked.len().min(1)
+        };
+        for (_, sock) in parked.drain(..count) {
+            let _ = sock.shutdown(Shutdown::Read);
+        }
+    }
 
-struct MyTask<T> {
-    task: thread::JoinHandle<()>,
-	sender:Sender<T>
+    fn lock_parked(&self) -> std::sync::MutexGuard<'_, Vec<(u64, TcpStream)>> {
+        self.parked.lock().unwrap_or_else(|e| e.into_inner())
+    }
 }
-pub struct ThreadPool<T> {
-    tasks: Vec<Box<MyTask<T>>>,
-	index:u16,
-	max:u16
+
+/// A connection registered with `WorkerLoad::park`.
+pub(crate) struct Parked<'l> {
+    load: &'l WorkerLoad,
+    id: u64,
 }
-impl<T: 'static + Send> ThreadPool<T> {
-    pub(super) fn new<F: FnMut(T) + Clone + Send + 'static>(num: u16, f: F) -> Self {
-        let mut r = Self {
-            tasks: Vec::new(),
-			index:0,
-			max:num
-        };
+
+impl Parked<'_> {
+    /// Whether the pool woke it to make room for another connection.
+    pub(crate) fn woken(&self) -> bool {
+        !self.load.lock_parked().iter().any(|(id, _)| *id == self.id)
+    }
+}
+
+impl Drop for Parked<'_> {
+    fn drop(&mut self) {
+        self.load.lock_parked().retain(|(id, _)| *id != self.id);
+    }
+}
+
+enum Queue<T> {
+    Unbounded(Sender<T>),
+    Bounded(SyncSender<T>),
+}
+
+impl<T> Queue<T> {
+    /// `capacity` is how many items may wait, 0 for no limit.
+    fn new(capacity: usize) -> (Self, Arc<Mutex<Receiver<T>>>) {
+        if capacity == 0 {
+            let (tx, rx) = mpsc::channel();
+            (Queue::Unbounded(tx), Arc::new(Mutex::new(rx)))
+        } else {
+            let (tx, rx) = mpsc::sync_channel(capacity);
+            (Queue::Bounded(tx), Arc::new(Mutex::new(rx)))
+        }
+    }
+
+    fn push(&self, data: T) -> Result<(), TrySendError<T>> {
+        match self {
+            Queue::Unbounded(tx) => tx.send(data).map_err(|e| TrySendError::Disconnected(e.0)),
+            Queue::Bounded(tx) => tx.try_send(data),
+        }
+    }
+}
+
+struct Worker<T> {
+    handle: thread::JoinHandle<()>,
+    // kept here as well, so the queue outlives the worker and a new one
+    // can take it over
+    rx: Arc<Mutex<Receiver<T>>>,
+    load: Arc<WorkerLoad>,
+}
+
+pub struct ThreadPool<T, F> {
+    workers: Vec<Worker<T>>,
+    // one queue for all the workers, so whichever is idle takes the next
+    // connection instead of it waiting behind a slow one; or with `sticky`
+    // one per worker, so `poll` decides who serves what
+    senders: Vec<Queue<T>>,
+    // how busy the workers behind each of `senders` are
+    loads: Vec<Arc<WorkerLoad>>,
+    f: F,
+    restarts: Arc<AtomicUsize>,
+}
+impl<T: 'static + Send, F: FnMut(T, &Arc<WorkerLoad>) + Clone + Send + 'static> ThreadPool<T, F> {
+    /// `capacity` is how many items may wait for a worker, or for each
+    /// worker when `sticky`, 0 for no limit. `restarts` counts the workers
+    /// whose thread ended and was replaced. `f` gets, besides
+    /// the item, the load of the workers it was queued for, e.g. to cut a
+    /// kept-alive connection short for others waiting.
+    pub(super) fn new(
+        num: u16,
+        capacity: usize,
+        sticky: bool,
+        f: F,
+        restarts: Arc<AtomicUsize>,
+    ) -> Self {
+        // `HttpServer::run` refuses 0 already, a pool without a worker
+        // could never take anything
+        let num = num.max(1);
+        let mut senders = Vec::new();
+        let mut loads = Vec::new();
+        let mut shared = None;
+        let mut workers = Vec::new();
         for _ in 0..num {
-            let mut f = f.clone();
-			let (tx, rx) = mpsc::channel();
-            r.tasks.push(Box::new(MyTask {
-				sender:tx,
-                task: thread::spawn(move || {
-                    loop {
-                        let r = rx.recv();
-                        match r {
-                            Ok(stream) => {
-                                f(stream);
-                            }
-                            Err(e) => {
-								println!("recv() error: {}",e.to_string());
-							}
+            let mut open_queue = || {
+                let (tx, rx) = Queue::new(capacity);
+                let load = Arc::new(WorkerLoad::default());
+                senders.push(tx);
+                loads.push(Arc::clone(&load));
+                (rx, load)
+            };
+            let (rx, load) = if sticky {
+                open_queue()
+            } else {
+                let (rx, load) = shared.get_or_insert_with(open_queue);
+                (Arc::clone(rx), Arc::clone(load))
+            };
+            workers.push(Worker {
+                handle: spawn_worker(Arc::clone(&rx), Arc::clone(&load), f.clone()),
+                rx,
+                load,
+            });
+        }
+        Self {
+            workers,
+            senders,
+            loads,
+            f,
+            restarts,
+        }
+    }
+
+    /// Queues `data` without blocking. For a sticky pool `worker` picks the
+    /// worker (modulo their number), without it the least busy one takes
+    /// it. Should no worker be free for it, one idling on a kept-alive
+    /// connection is woken to give that up. `data` is handed back with
+    /// `Full` when the queue is at its capacity, or `Disconnected` once
+    /// every worker is gone.
+    pub(super) fn poll(&mut self, data: T, worker: Option<u64>) -> Result<(), TrySendError<T>> {
+        self.replace_dead_workers();
+        let count = self.senders.len();
+        let index = match worker {
+            Some(key) => (key % count as u64) as usize,
+            None => (0..count)
+                .min_by_key(|&i| {
+                    let load = &self.loads[i];
+                    let queued = load.queued.load(Ordering::SeqCst) as isize;
+                    queued - load.idle.load(Ordering::SeqCst) as isize
+                })
+                .unwrap_or(0),
+        };
+        let load = &self.loads[index];
+        // counted up front, the worker may take it before `push` returns
+        load.queued.fetch_add(1, Ordering::SeqCst);
+        let pushed = self.senders[index].push(data);
+        if pushed.is_err() {
+            load.queued.fetch_sub(1, Ordering::SeqCst);
+        } else if load.backed_up() {
+            load.wake(false);
+        }
+        pushed
+    }
+
+    // a panic in `f` is caught in the worker, so a thread ends only through
+    // something outside it; its queue would otherwise be left undrained
+    fn replace_dead_workers(&mut self) {
+        for worker in &mut self.workers {
+            if !worker.handle.is_finished() {
+                continue;
+            }
+            self.restarts.fetch_add(1, Ordering::Relaxed);
+            worker.handle = spawn_worker(
+                Arc::clone(&worker.rx),
+                Arc::clone(&worker.load),
+                self.f.clone(),
+            );
+        }
+    }
+
+    /// Stops the pool and waits for the workers to finish what they are
+    /// on. Dropping the senders wakes a worker waiting for an item and
+    /// makes it leave its loop once its queue is empty. With `drain` the
+    /// items still queued are served first, otherwise they go to `reject`;
+    /// a worker that was just taking one may still serve it. Kept-alive
+    /// connections idling on a worker are woken to close.
+    pub(super) fn shutdown(self, drain: bool, mut reject: impl FnMut(T)) {
+        drop(self.senders);
+        for load in &self.loads {
+            load.wake(true);
+        }
+        if !drain {
+            // the workers share a queue unless sticky, emptying it once is
+            // enough then
+            for worker in &self.workers {
+                loop {
+                    let item = worker
+                        .rx
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .try_recv();
+                    match item {
+                        Ok(item) => {
+                            worker.load.queued.fetch_sub(1, Ordering::SeqCst);
+                            reject(item);
                         }
+                        Err(_) => break,
                     }
-                }),
-            }))
+                }
+            }
+        }
+        for worker in self.workers {
+            let _r = worker.handle.join();
         }
-        r
     }
+}
 
-    pub(super) fn poll(&mut self, data: T) -> Result<(), SendError<T>> {
-        if self.index >= self.max {
-            self.index = 0;
+fn spawn_worker<T, F>(
+    rx: Arc<Mutex<Receiver<T>>>,
+    load: Arc<WorkerLoad>,
+    mut f: F,
+) -> thread::JoinHandle<()>
+where
+    T: 'static + Send,
+    F: FnMut(T, &Arc<WorkerLoad>) + Send + 'static,
+{
+    thread::spawn(move || loop {
+        load.idle.fetch_add(1, Ordering::SeqCst);
+        // the lock is held only while waiting, not while serving
+        let r = rx.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        if r.is_ok() {
+            // the item first, or it would look like no one took it
+            load.queued.fetch_sub(1, Ordering::SeqCst);
         }
-        //println!("current:{}", self.index);
-        if let Some(task) = self.tasks.get(self.index as usize) {
-            match task.sender.send(data) {
-                Ok(_) => {
-                    self.index += 1;
-                    return Ok(());
-                }
-                Err(e) => {
-                    //println!("dispatch stream error:{}",e.to_string());
-                    return Err(e);
-                }
+        load.idle.fetch_sub(1, Ordering::SeqCst);
+        match r {
+            Ok(stream) => {
+                // costs the one item, the worker goes on with the next
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| f(stream, &load)));
             }
+            // the pool is gone, see `join`
+            Err(_) => break,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ThreadPool, WorkerLoad};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // the worker holds on to item 0 until the gate opens, so the others
+    // stay queued
+    #[derive(Default)]
+    struct Gate {
+        open: Mutex<bool>,
+        opened: Condvar,
+    }
+
+    impl Gate {
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+
+        fn wait(&self) {
+            let open = self.open.lock().unwrap();
+            let _open = self.opened.wait_while(open, |open| !*open).unwrap();
         }
-        Ok(())
     }
 
-    pub(super) fn join(self) {
-        for task in self.tasks {
-            let _r = task.task.join();
+    type Served = Arc<Mutex<Vec<usize>>>;
+
+    fn pool(
+        gate: &Arc<Gate>,
+        served: &Served,
+    ) -> ThreadPool<usize, impl FnMut(usize, &Arc<WorkerLoad>) + Clone + Send + 'static> {
+        let (gate, served) = (Arc::clone(gate), Arc::clone(served));
+        let f = move |item: usize, _load: &Arc<WorkerLoad>| {
+            if item == 0 {
+                gate.wait();
+            }
+            served.lock().unwrap().push(item);
+        };
+        let mut pool = ThreadPool::new(1, 0, false, f, Arc::new(AtomicUsize::new(0)));
+        pool.poll(0, None).unwrap();
+        // until the worker is in item 0
+        while pool.loads[0].queued.load(Ordering::SeqCst) > 0
+            || pool.loads[0].idle.load(Ordering::SeqCst) > 0
+        {
+            thread::sleep(Duration::from_millis(1));
         }
+        for item in 1..5 {
+            pool.poll(item, None).unwrap();
+        }
+        pool
+    }
+
+    #[test]
+    fn shutdown_with_drain_serves_the_queued_items() {
+        let (gate, served) = (Arc::new(Gate::default()), Served::default());
+        let pool = pool(&gate, &served);
+        let opener = {
+            let gate = Arc::clone(&gate);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                gate.open();
+            })
+        };
+        pool.shutdown(true, |item| panic!("{} was rejected", item));
+        opener.join().unwrap();
+        assert_eq!(*served.lock().unwrap(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn shutdown_without_drain_rejects_the_queued_items() {
+        let (gate, served) = (Arc::new(Gate::default()), Served::default());
+        let pool = pool(&gate, &served);
+        let mut rejected = Vec::new();
+        pool.shutdown(false, |item| {
+            rejected.push(item);
+            // the worker is let go only now, it can't take any of them
+            gate.open();
+        });
+        assert_eq!(rejected, [1, 2, 3, 4]);
+        assert_eq!(*served.lock().unwrap(), [0]);
     }
 }
@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::{Request, Response, Router};
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Answers the tus 1.0.0 core protocol plus the creation extension for the
+/// routes registered by `HttpServer::serve_tus`. Each upload is a file named
+/// by its id under `dir`, so its offset is simply the file's length, next to
+/// a `<id>.info` file holding the declared `Upload-Length` and metadata.
+#[derive(Clone)]
+pub(crate) struct TusDir {
+    prefix: String,
+    dir: PathBuf,
+    // the uploads a PATCH is writing to; a second PATCH on one of them gets
+    // a 423 rather than appending at the same offset
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+/// An upload claimed for one PATCH, released when dropped.
+struct Claim<'d> {
+    busy: &'d Mutex<HashSet<String>>,
+    id: String,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.busy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+struct UploadInfo {
+    length: u64,
+    metadata: Option<String>,
+}
+
+impl TusDir {
+    pub(crate) fn new(prefix: &str, dir: &str) -> Self {
+        TusDir {
+            prefix: prefix.to_string(),
+            dir: PathBuf::from(dir),
+            busy: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// The upload id in the request path, if it is one this server could
+    /// have handed out; anything else (e.g. `..`) never touches the disk.
+    fn upload_id<'u>(&self, url: &'u str) -> Option<&'u str> {
+        let path = url.split_once('?').map_or(url, |(path, _)| path);
+        let id = path.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        let valid = !id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit() || b == b'-');
+        if valid {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    fn read_info(&self, id: &str) -> Option<UploadInfo> {
+        let info = std::fs::read_to_string(self.dir.join(format!("{}.info", id))).ok()?;
+        let mut lines = info.lines();
+        let length = lines.next()?.parse().ok()?;
+        let metadata = lines.next().map(|v| v.to_string());
+        Some(UploadInfo { length, metadata })
+    }
+
+    fn claim(&self, id: &str) -> Option<Claim<'_>> {
+        let mut busy = self.busy.lock().unwrap_or_else(|e| e.into_inner());
+        if !busy.insert(id.to_string()) {
+            return None;
+        }
+        Some(Claim {
+            busy: &self.busy,
+            id: id.to_string(),
+        })
+    }
+
+    fn offset(&self, id: &str) -> Option<u64> {
+        std::fs::metadata(self.dir.join(id)).ok().map(|m| m.len())
+    }
+
+    fn create(&self, req: &Request, res: &mut Response) {
+        let length = match req
+            .get_header("Upload-Length")
+            .map(|v| v.trim().parse::<u64>())
+        {
+            Some(Ok(length)) => length,
+            _ => {
+                res.write_string("missing or invalid Upload-Length")
+                    .status(400);
+                return;
+            }
+        };
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut info = length.to_string();
+        if let Some(metadata) = req.get_header("Upload-Metadata") {
+            info.push('\n');
+            info.push_str(metadata);
+        }
+        let created = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(self.dir.join(&id), b""))
+            .and_then(|_| std::fs::write(self.dir.join(format!("{}.info", id)), info));
+        if created.is_err() {
+            res.write_string("could not create the upload").status(500);
+            return;
+        }
+        res.write_state(201);
+        res.add_header(String::from("Location"), format!("{}/{}", self.prefix, id));
+    }
+
+    fn head(&self, id: &str, res: &mut Response) {
+        let (info, offset) = match (self.read_info(id), self.offset(id)) {
+            (Some(info), Some(offset)) => (info, offset),
+            _ => {
+                res.write_state(404);
+                return;
+            }
+        };
+        res.write_state(200);
+        res.add_header(String::from("Upload-Offset"), offset.to_string());
+        res.add_header(String::from("Upload-Length"), info.length.to_string());
+        if let Some(metadata) = info.metadata {
+            res.add_header(String::from("Upload-Metadata"), metadata);
+        }
+        res.add_header(String::from("Cache-Control"), String::from("no-store"));
+    }
+
+    fn append(&self, id: &str, req: &Request, res: &mut Response) {
+        let content_type = req.get_header("Content-Type").unwrap_or("");
+        if !content_type
+            .trim()
+            .eq_ignore_ascii_case("application/offset+octet-stream")
+        {
+            res.write_string("Content-Type must be application/offset+octet-stream")
+                .status(415);
+            return;
+        }
+        let claimed = match req
+            .get_header("Upload-Offset")
+            .map(|v| v.trim().parse::<u64>())
+        {
+            Some(Ok(offset)) => offset,
+            _ => {
+                res.write_string("missing or invalid Upload-Offset")
+                    .status(400);
+                return;
+            }
+        };
+        let info = match self.read_info(id) {
+            Some(info) => info,
+            None => {
+                res.write_state(404);
+                return;
+            }
+        };
+        let _claim = match self.claim(id) {
+            Some(claim) => claim,
+            None => {
+                res.write_string("another PATCH is writing to this upload")
+                    .status(423);
+                return;
+            }
+        };
+        let offset = match self.offset(id) {
+            Some(offset) => offset,
+            None => {
+                res.write_state(404);
+                return;
+            }
+        };
+        if claimed != offset {
+            res.write_string(&format!("the upload is at offset {}", offset))
+                .status(409);
+            return;
+        }
+        let room = info.length.saturating_sub(offset);
+        let declared = req
+            .get_header("Content-Length")
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        let (mut body, mut file) = match (req.body_reader(), declared) {
+            (_, Some(len)) if len > room => {
+                res.write_string("the chunk goes past Upload-Length")
+                    .status(400);
+                return;
+            }
+            (Some(body), _) => match OpenOptions::new().append(true).open(self.dir.join(id)) {
+                Ok(file) => (body, file),
+                Err(_) => {
+                    res.write_string("could not store the chunk").status(500);
+                    return;
+                }
+            },
+            (None, _) => {
+                res.write_string("missing body").status(400);
+                return;
+            }
+        };
+        // written as it arrives; a chunk without a length is only known to
+        // be too long once a byte past the room shows up
+        match io::copy(&mut (&mut body).take(room + 1), &mut file) {
+            Ok(written) if written > room => {
+                let _ = file.set_len(offset);
+                res.write_string("the chunk goes past Upload-Length")
+                    .status(400);
+            }
+            Ok(written) => {
+                res.write_state(204);
+                res.add_header(
+                    String::from("Upload-Offset"),
+                    (offset + written).to_string(),
+                );
+            }
+            // what did arrive is kept, a HEAD tells the client where to
+            // resume
+            Err(_) => {
+                res.write_string("could not store the chunk").status(500);
+            }
+        }
+    }
+}
+
+impl Router for TusDir {
+    fn reads_body(&self) -> bool {
+        true
+    }
+
+    fn call(&self, req: &Request, res: &mut Response) {
+        if req.get_method() == "OPTIONS" {
+            res.write_state(204);
+            res.add_header(String::from("Tus-Version"), String::from(TUS_VERSION));
+            res.add_header(String::from("Tus-Extension"), String::from("creation"));
+            return;
+        }
+        res.add_header(String::from("Tus-Resumable"), String::from(TUS_VERSION));
+        if req.get_header("Tus-Resumable").map(|v| v.trim()) != Some(TUS_VERSION) {
+            res.write_state(412);
+            res.add_header(String::from("Tus-Version"), String::from(TUS_VERSION));
+            return;
+        }
+        if req.get_method() == "POST" {
+            self.create(req, res);
+            return;
+        }
+        let id = match self.upload_id(req.get_url()) {
+            Some(id) => id,
+            None => {
+                res.write_state(404);
+                return;
+            }
+        };
+        match req.get_method() {
+            "HEAD" => self.head(id, res),
+            "PATCH" => self.append(id, req, res),
+            _ => res.write_state(405),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use common::{read_reply, TestServer};
+use http_server::{Request, Response, GET, POST};
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn full_queue_gets_503_while_the_worker_is_busy() {
+    let server = TestServer::start(1, |s| {
+        s.set_max_pending_connections(1);
+        s.route(GET, "/slow")
+            .reg(|_req: &Request, res: &mut Response| {
+                thread::sleep(Duration::from_millis(800));
+                res.write_string("slow");
+            });
+        s.route([GET, POST], "/fast")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string("fast");
+            });
+    });
+    let mut busy = server.connect();
+    busy.write_all(b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut queued = server.connect();
+    queued
+        .write_all(b"GET /fast HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // the body is never read by a worker, the 503 must survive it
+    let started = Instant::now();
+    let mut shed = server.connect();
+    shed.write_all(b"POST /fast HTTP/1.1\r\nHost: a\r\nContent-Length: 65536\r\n\r\n")
+        .unwrap();
+    shed.write_all(&[b'x'; 65536]).unwrap();
+    let reply = read_reply(&mut shed);
+    assert_eq!(reply.status, 503);
+    assert_eq!(reply.header("Retry-After"), Some("1"));
+    assert!(started.elapsed() < Duration::from_millis(500));
+
+    assert_eq!(read_reply(&mut busy).text(), "slow");
+    assert_eq!(read_reply(&mut queued).text(), "fast");
+}
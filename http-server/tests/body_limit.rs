@@ -0,0 +1,123 @@
+mod common;
+
+use common::{read_reply, TestServer};
+use http_server::{Request, Response, POST};
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+const LIMIT: usize = 1024;
+
+fn server() -> TestServer {
+    server_with(|_| {})
+}
+
+fn server_with(setup: impl FnOnce(&mut http_server::HttpServer) + Send + 'static) -> TestServer {
+    TestServer::start(1, |s| {
+        setup(s);
+        s.set_max_body_size(LIMIT);
+        s.route(POST, "/echo")
+            .reg(|req: &Request, res: &mut Response| {
+                res.write_string(&req.body_bytes().map_or(0, |b| b.len()).to_string());
+            });
+    })
+}
+
+fn post(server: &TestServer, len: usize) -> common::Reply {
+    let mut request = format!(
+        "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n",
+        len
+    )
+    .into_bytes();
+    request.resize(request.len() + len, b'a');
+    server.send(&request)
+}
+
+#[test]
+fn body_at_the_limit_is_read() {
+    let server = server();
+    let reply = post(&server, LIMIT);
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.text(), LIMIT.to_string());
+}
+
+#[test]
+fn body_just_over_the_limit_gets_413() {
+    let server = server();
+    let reply = post(&server, LIMIT + 1);
+    assert!(reply.head.starts_with("HTTP/1.1 413 "), "{}", reply.head);
+    assert_eq!(reply.header("Connection"), Some("close"));
+}
+
+#[test]
+fn large_unread_body_does_not_reset_the_413() {
+    let server = server();
+    let mut stream = server.connect();
+    let len = 512 * 1024;
+    write!(
+        stream,
+        "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n",
+        len
+    )
+    .unwrap();
+    // keeps sending while the server has long answered
+    let mut writer = stream.try_clone().unwrap();
+    let sender = thread::spawn(move || {
+        let _ = writer.write_all(&vec![b'a'; len]);
+    });
+    // a reset arriving before the client reads would take the 413 with it
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(read_reply(&mut stream).status, 413);
+    drop(stream);
+    sender.join().unwrap();
+}
+
+#[test]
+fn truncated_body_answer_survives_the_unread_rest() {
+    let server = server_with(|s| s.set_truncate_oversized_body(true));
+    let mut stream = server.connect();
+    let len = 512 * 1024;
+    write!(
+        stream,
+        "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n",
+        len
+    )
+    .unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let sender = thread::spawn(move || {
+        let _ = writer.write_all(&vec![b'a'; len]);
+    });
+    thread::sleep(Duration::from_millis(300));
+    let reply = read_reply(&mut stream);
+    assert_eq!(
+        (reply.status, reply.text()),
+        (200, LIMIT.to_string().as_str())
+    );
+    assert_eq!(reply.header("Connection"), Some("close"));
+    drop(stream);
+    sender.join().unwrap();
+}
+
+#[test]
+fn chunked_body_over_the_limit_gets_413() {
+    let server = server();
+    let mut request = b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Type: text/plain\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n"
+        .to_vec();
+    for _ in 0..3 {
+        request.extend_from_slice(b"200\r\n");
+        request.extend_from_slice(&[b'a'; 0x200]);
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"0\r\n\r\n");
+    assert_eq!(server.send(&request).status, 413);
+}
+
+#[test]
+fn huge_chunk_size_gets_413() {
+    let server = server();
+    // with a chunk already read, adding this size would overflow usize
+    let request = b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Type: text/plain\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\nffffffffffffffff\r\nabcd\r\n0\r\n\r\n";
+    assert_eq!(server.send(request).status, 413);
+}
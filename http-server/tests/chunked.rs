@@ -0,0 +1,136 @@
+mod common;
+
+use common::TestServer;
+use http_server::{Request, Response, POST};
+
+fn server() -> TestServer {
+    TestServer::start(1, |s| {
+        s.route(POST, "/echo")
+            .reg(|req: &Request, res: &mut Response| {
+                res.write_string(req.plain_body().unwrap_or("<none>"));
+            });
+        s.route(POST, "/form")
+            .reg(|req: &Request, res: &mut Response| {
+                let file = req.get_file("f").unwrap();
+                let content = std::fs::read_to_string(&file.filepath).unwrap();
+                let _ = std::fs::remove_file(&file.filepath);
+                res.write_string(&content);
+            });
+        s.route(POST, "/trailers")
+            .reg(|req: &Request, res: &mut Response| {
+                let header = |name| req.get_header(name).unwrap_or("-");
+                res.write_string(&format!(
+                    "{} {} {} {}",
+                    req.plain_body().unwrap_or("<none>"),
+                    header("X-Checksum"),
+                    header("X-Undeclared"),
+                    header("Content-Type")
+                ));
+            });
+    })
+}
+
+fn chunked(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for piece in body.chunks(7) {
+        out.extend_from_slice(format!("{:x}\r\n", piece.len()).as_bytes());
+        out.extend_from_slice(piece);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"0\r\n\r\n");
+    out
+}
+
+fn post(server: &TestServer, path: &str, head: &str, body: &[u8]) -> common::Reply {
+    let mut request = format!("POST {} HTTP/1.1\r\nHost: a\r\n{}\r\n", path, head).into_bytes();
+    request.extend_from_slice(body);
+    server.send(&request)
+}
+
+#[test]
+fn plain_body() {
+    let server = server();
+    let reply = post(
+        &server,
+        "/echo",
+        "Content-Type: text/plain\r\nTransfer-Encoding: chunked\r\n",
+        &chunked(b"hello chunked world"),
+    );
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.text(), "hello chunked world");
+}
+
+#[test]
+fn declared_trailer_reaches_the_handler() {
+    let server = server();
+    let mut body = chunked(b"hello");
+    // swap the empty trailer section for one with fields
+    body.truncate(body.len() - 2);
+    body.extend_from_slice(
+        b"X-Checksum: abc123\r\nX-Undeclared: no\r\nContent-Type: text/html\r\n\r\n",
+    );
+    let reply = post(
+        &server,
+        "/trailers",
+        "Content-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\
+         Trailer: X-Checksum, Content-Type\r\n",
+        &body,
+    );
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.text(), "hello abc123 - text/plain");
+}
+
+#[test]
+fn multipart_body() {
+    let server = server();
+    let form = b"--XYZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                 Content-Type: text/plain\r\n\r\nfile\r\nbody\r\n--XYZ--\r\n";
+    let reply = post(
+        &server,
+        "/form",
+        "Content-Type: multipart/form-data; boundary=XYZ\r\nTransfer-Encoding: chunked\r\n",
+        &chunked(form),
+    );
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.text(), "file\r\nbody");
+}
+
+#[test]
+fn multipart_body_cut_short() {
+    let server = server();
+    // no closing boundary, the parser runs out of decoded body
+    let form = b"--XYZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                 Content-Type: text/plain\r\n\r\nfile body";
+    let reply = post(
+        &server,
+        "/form",
+        "Content-Type: multipart/form-data; boundary=XYZ\r\nTransfer-Encoding: chunked\r\n",
+        &chunked(form),
+    );
+    assert_eq!(reply.status, 400);
+}
+
+#[test]
+fn other_transfer_codings_get_501() {
+    let server = server();
+    for coding in ["gzip", "gzip, chunked"] {
+        let head = format!(
+            "Content-Type: text/plain\r\nTransfer-Encoding: {}\r\n",
+            coding
+        );
+        let reply = post(&server, "/echo", &head, &chunked(b"x"));
+        assert_eq!(reply.status, 501, "{}", coding);
+    }
+}
+
+#[test]
+fn ambiguous_framing_gets_400() {
+    let server = server();
+    for head in [
+        "Transfer-Encoding: chunked\r\nContent-Length: 6\r\n",
+        "Transfer-Encoding: chunked, gzip\r\n",
+    ] {
+        let reply = post(&server, "/echo", head, &chunked(b"x"));
+        assert_eq!(reply.status, 400, "{}", head);
+    }
+}
@@ -0,0 +1,209 @@
+// Runs an `HttpServer` on a free local port for the length of a test and
+// talks raw HTTP to it, so the bytes on the wire are what is checked.
+#![allow(dead_code)]
+
+use http_server::{EndPoint, HttpServer, ShutdownHandle};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    handle: ShutdownHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Builds the server with `setup` on its own thread and returns once it
+    /// accepts connections. Dropping it shuts the server down.
+    pub fn start<F>(threads: u16, setup: F) -> TestServer
+    where
+        F: FnOnce(&mut HttpServer) + Send + 'static,
+    {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let end = EndPoint {
+                port,
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            };
+            let mut server = HttpServer::create(end, threads);
+            setup(&mut server);
+            tx.send(server.shutdown_handle()).unwrap();
+            server.run().unwrap();
+        });
+        let handle = rx.recv().expect("the server setup panicked");
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut probe = loop {
+            match TcpStream::connect(addr) {
+                Ok(probe) => break probe,
+                Err(_) => {
+                    assert!(Instant::now() < deadline, "the server did not start");
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        };
+        // wait for a worker to be done with the probe, so it isn't queued
+        // when the test starts
+        probe.shutdown(std::net::Shutdown::Write).unwrap();
+        probe
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let _ = probe.read(&mut [0u8; 1]);
+        TestServer {
+            addr,
+            handle,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    /// Sends `request` on a fresh connection and reads one response.
+    pub fn send(&self, request: &[u8]) -> Reply {
+        let mut stream = self.connect();
+        stream.write_all(request).unwrap();
+        read_reply(&mut stream)
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        if let Some(thread) = self.thread.take() {
+            // a failing test is already unwinding, don't panic twice
+            if thread.join().is_err() && !thread::panicking() {
+                panic!("the server thread panicked");
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Reply {
+    pub status: u16,
+    pub head: String,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.split("\r\n").skip(1).find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            k.trim().eq_ignore_ascii_case(name).then(|| v.trim())
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap()
+    }
+}
+
+/// Reads a status line and headers, the body is left on the stream.
+pub fn read_head(stream: &mut TcpStream) -> Reply {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut byte) {
+            Ok(0) => panic!(
+                "closed in the middle of a head: {:?}",
+                String::from_utf8_lossy(&head)
+            ),
+            Ok(_) => head.push(byte[0]),
+            Err(e) => panic!("{} after {:?}", e, String::from_utf8_lossy(&head)),
+        }
+    }
+    let head = String::from_utf8(head).unwrap();
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or_else(|| panic!("bad status line in {:?}", head));
+    Reply {
+        status,
+        head,
+        body: Vec::new(),
+    }
+}
+
+/// Reads one response, its body framed by `Content-length`, chunked, or
+/// the end of the connection. A `1xx` comes back on its own.
+pub fn read_reply(stream: &mut TcpStream) -> Reply {
+    let mut reply = read_head(stream);
+    if reply.status < 200 || reply.status == 204 || reply.status == 304 {
+        return reply;
+    }
+    let chunked = reply
+        .header("Transfer-Encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        reply.body = read_chunked(stream);
+    } else if let Some(len) = reply.header("Content-length") {
+        let mut body = vec![0u8; len.parse().unwrap()];
+        stream.read_exact(&mut body).unwrap();
+        reply.body = body;
+    } else {
+        stream.read_to_end(&mut reply.body).unwrap();
+    }
+    reply
+}
+
+fn read_line(stream: &mut TcpStream) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).unwrap()
+}
+
+fn read_chunked(stream: &mut TcpStream) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(stream);
+        let size = line.split(';').next().unwrap().trim();
+        let size = usize::from_str_radix(size, 16).unwrap();
+        if size == 0 {
+            // trailers, up to the empty line
+            while !read_line(stream).is_empty() {}
+            return body;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..]).unwrap();
+        assert_eq!(read_line(stream), "");
+    }
+}
+
+/// Whether the server closed the connection, rather than leaving it open
+/// for another request.
+pub fn is_closed(stream: &mut TcpStream) -> bool {
+    stream
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let mut byte = [0u8; 1];
+    match stream.read(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => panic!("unexpected data on the connection"),
+        Err(e) => !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+    }
+}
@@ -0,0 +1,40 @@
+use http_server::{end_point, EndPoint, HttpServer};
+use std::io::ErrorKind;
+
+// each refused before anything is bound, so the port is never used
+fn refused(threads: u16, setup: impl FnOnce(&mut HttpServer)) -> String {
+    let mut server = HttpServer::create(end_point!(127.0.0.1:1), threads);
+    setup(&mut server);
+    let e = server.run().expect_err("the config should be refused");
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    e.to_string()
+}
+
+#[test]
+fn no_threads() {
+    assert!(refused(0, |_| {}).contains("thread number"));
+}
+
+#[test]
+fn zero_chunk_size() {
+    assert!(refused(1, |s| s.set_chunksize(0)).contains("chunk size"));
+}
+
+#[test]
+fn zero_read_buffer_increase() {
+    assert!(refused(1, |s| s.set_read_buff_increase_size(0)).contains("read buffer"));
+}
+
+#[test]
+fn zero_header_size() {
+    assert!(refused(1, |s| s.set_max_header_size(0)).contains("max header size"));
+}
+
+#[test]
+fn body_limit_below_the_read_buffer() {
+    let e = refused(1, |s| {
+        s.set_read_buff_increase_size(4096);
+        s.set_max_body_size(1024);
+    });
+    assert!(e.contains("max body size"));
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use common::TestServer;
+use http_server::{Request, Response, GET};
+
+fn server() -> TestServer {
+    TestServer::start(1, |s| {
+        s.route("PURGE", "/cache/:key")
+            .reg(|req: &Request, res: &mut Response| {
+                res.write_string(&format!(
+                    "{} {}",
+                    req.get_method(),
+                    req.get_path_param("key").unwrap()
+                ));
+            });
+        let methods: &[&str] = &["LINK", "UNLINK"];
+        s.route(methods, "/rel")
+            .reg(|req: &Request, res: &mut Response| {
+                res.write_string(req.get_method());
+            });
+        s.route(GET, "/rel")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string("get");
+            });
+    })
+}
+
+#[test]
+fn purge_route_is_dispatched() {
+    let server = server();
+    let reply = server.send(b"PURGE /cache/home HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.text(), "PURGE home");
+}
+
+#[test]
+fn other_methods_on_a_purge_route_get_405() {
+    let server = server();
+    let reply = server.send(b"GET /cache/home HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 405);
+    assert_eq!(reply.header("Allow"), Some("PURGE"));
+}
+
+#[test]
+fn a_list_of_method_names() {
+    let server = server();
+    for method in ["LINK", "UNLINK"] {
+        let request = format!("{} /rel HTTP/1.1\r\nHost: a\r\n\r\n", method);
+        assert_eq!(server.send(request.as_bytes()).text(), method);
+    }
+    assert_eq!(
+        server.send(b"GET /rel HTTP/1.1\r\nHost: a\r\n\r\n").text(),
+        "get"
+    );
+}
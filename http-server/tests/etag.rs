@@ -0,0 +1,68 @@
+mod common;
+
+use common::{read_reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+
+fn server(name: &str) -> (TestServer, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("etag-{}-{}.txt", name, std::process::id()));
+    std::fs::write(&path, "cached").unwrap();
+    let file = path.to_string_lossy().into_owned();
+    let server = TestServer::start(1, move |s| {
+        let plain = file.clone();
+        s.route(GET, "/file")
+            .reg(move |_req: &Request, res: &mut Response| {
+                res.write_file(plain.clone());
+            });
+        s.route(GET, "/hashed")
+            .reg(move |_req: &Request, res: &mut Response| {
+                res.write_file(file.clone()).etag("v2");
+            });
+    });
+    (server, path)
+}
+
+fn get(url: &str, if_none_match: Option<&str>) -> Vec<u8> {
+    let mut request = format!("GET {} HTTP/1.1\r\nHost: a\r\n", url);
+    if let Some(tag) = if_none_match {
+        request.push_str(&format!("If-None-Match: {}\r\n", tag));
+    }
+    request.push_str("\r\n");
+    request.into_bytes()
+}
+
+#[test]
+fn second_request_on_a_kept_alive_connection_gets_304() {
+    let (server, path) = server("keep-alive");
+    let mut stream = server.connect();
+    stream.write_all(&get("/file", None)).unwrap();
+    let first = read_reply(&mut stream);
+    assert_eq!((first.status, first.text()), (200, "cached"));
+    let tag = first.header("ETag").unwrap().to_string();
+    stream.write_all(&get("/file", Some(&tag))).unwrap();
+    let second = read_reply(&mut stream);
+    assert_eq!(second.status, 304);
+    assert!(second.body.is_empty());
+    assert_eq!(second.header("ETag"), Some(tag.as_str()));
+    // nothing of a body was left behind on the connection
+    stream.write_all(&get("/file", None)).unwrap();
+    assert_eq!(read_reply(&mut stream).status, 200);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn custom_tag_is_checked_instead_of_the_default() {
+    let (server, path) = server("custom");
+    let weak = server
+        .send(&get("/file", None))
+        .header("ETag")
+        .unwrap()
+        .to_string();
+    // the default tag no longer describes what `/hashed` sends
+    let reply = server.send(&get("/hashed", Some(&weak)));
+    assert_eq!((reply.status, reply.text()), (200, "cached"));
+    assert_eq!(reply.header("ETag"), Some("\"v2\""));
+    let reply = server.send(&get("/hashed", Some("\"v2\"")));
+    assert_eq!(reply.status, 304);
+    std::fs::remove_file(path).unwrap();
+}
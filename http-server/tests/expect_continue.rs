@@ -0,0 +1,139 @@
+mod common;
+
+use common::{read_reply, TestServer};
+use http_server::{Request, Response, POST};
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+fn server() -> TestServer {
+    TestServer::start(2, |s| {
+        s.set_read_buff_increase_size(64);
+        s.set_max_body_size(64);
+        s.route(POST, "/echo")
+            .reg(|req: &Request, res: &mut Response| {
+                res.write_string(req.plain_body().unwrap_or(""));
+            });
+        s.route(POST, "/upload")
+            .reg(|req: &Request, res: &mut Response| {
+                let file = req.get_file("f").unwrap();
+                let len = std::fs::metadata(&file.filepath).unwrap().len();
+                let _ = std::fs::remove_file(&file.filepath);
+                res.write_string(&len.to_string());
+            });
+    })
+}
+
+// sends the head, then the body once the server had time to answer
+// without it, as a client tired of waiting for 100 Continue would
+fn send_body_late(stream: &TcpStream, head: &str) -> thread::JoinHandle<()> {
+    let len = 512 * 1024;
+    let mut writer = stream.try_clone().unwrap();
+    write!(writer, "{}Content-Length: {}\r\n\r\n", head, len).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    thread::spawn(move || {
+        let _ = writer.write_all(&vec![b'a'; len]);
+    })
+}
+
+#[test]
+fn http11_gets_continue_before_the_body() {
+    let server = server();
+    let mut stream = server.connect();
+    stream
+        .write_all(
+            b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Type: text/plain\r\n\
+              Expect: 100-continue\r\n\r\n",
+        )
+        .unwrap();
+    assert_eq!(read_reply(&mut stream).status, 100);
+    stream.write_all(b"hello").unwrap();
+    let reply = read_reply(&mut stream);
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.text(), "hello");
+}
+
+#[test]
+fn http10_never_gets_continue() {
+    let server = server();
+    let mut stream = server.connect();
+    stream
+        .write_all(
+            b"POST /echo HTTP/1.0\r\nHost: a\r\nContent-Length: 5\r\nContent-Type: text/plain\r\n\
+              Expect: 100-continue\r\n\r\n",
+        )
+        .unwrap();
+    // an HTTP/1.0 client sends the body after a while regardless
+    thread::sleep(Duration::from_millis(200));
+    stream.write_all(b"hello").unwrap();
+    let reply = read_reply(&mut stream);
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.text(), "hello");
+}
+
+#[test]
+fn multipart_is_held_to_max_body_size() {
+    let server = server();
+    let file = vec![b'x'; 1000];
+    let mut body = Vec::new();
+    body.extend_from_slice(
+        b"--XYZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+          Content-Type: text/plain\r\n\r\n",
+    );
+    body.extend_from_slice(&file);
+    body.extend_from_slice(b"\r\n--XYZ--\r\n");
+    let mut stream = server.connect();
+    write!(
+        stream,
+        "POST /upload HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\
+         Content-Type: multipart/form-data; boundary=XYZ\r\nExpect: 100-continue\r\n\r\n",
+        body.len()
+    )
+    .unwrap();
+    // refused before the client sends any of it
+    assert_eq!(read_reply(&mut stream).status, 413);
+}
+
+#[test]
+fn oversized_plain_body_is_refused_up_front() {
+    let server = server();
+    let mut stream = server.connect();
+    stream
+        .write_all(
+            b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 100\r\nContent-Type: text/plain\r\n\
+              Expect: 100-continue\r\n\r\n",
+        )
+        .unwrap();
+    assert_eq!(read_reply(&mut stream).status, 413);
+}
+
+#[test]
+fn unknown_expectation_answer_survives_the_unread_body() {
+    let server = server();
+    let mut stream = server.connect();
+    let sender = send_body_late(
+        &stream,
+        "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Type: text/plain\r\nExpect: pony\r\n",
+    );
+    // a reset arriving before the client reads would take the 417 with it
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(read_reply(&mut stream).status, 417);
+    drop(stream);
+    sender.join().unwrap();
+}
+
+#[test]
+fn unrouted_answer_survives_the_unread_body() {
+    let server = server();
+    let mut stream = server.connect();
+    let sender = send_body_late(
+        &stream,
+        "POST /nowhere HTTP/1.1\r\nHost: a\r\nContent-Type: text/plain\r\n\
+         Expect: 100-continue\r\n",
+    );
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(read_reply(&mut stream).status, 404);
+    drop(stream);
+    sender.join().unwrap();
+}
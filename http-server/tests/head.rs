@@ -0,0 +1,58 @@
+mod common;
+
+use common::{read_head, read_reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+
+fn server() -> TestServer {
+    TestServer::start(1, |s| {
+        s.route(GET, "/wildcard/*")
+            .reg(|req: &Request, res: &mut Response| {
+                res.add_header(String::from("X-Route"), String::from("wildcard"));
+                res.write_string(&format!("wildcard {}", req.get_url()));
+            });
+        s.route(GET, "/user/:id")
+            .reg(|req: &Request, res: &mut Response| {
+                res.add_header(String::from("X-Route"), String::from("user"));
+                res.write_string(&format!("user {}", req.get_path_param("id").unwrap()));
+            });
+    })
+}
+
+// HEAD runs the GET route and sends its head alone; a body sent anyway
+// would be read as the head of the GET that follows on the connection
+fn head_then_get(server: &TestServer, url: &str, route: &str, body: &str) {
+    let mut stream = server.connect();
+    stream
+        .write_all(format!("HEAD {} HTTP/1.1\r\nHost: a\r\n\r\n", url).as_bytes())
+        .unwrap();
+    let head = read_head(&mut stream);
+    assert_eq!(head.status, 200, "{}", url);
+    assert_eq!(head.header("X-Route"), Some(route));
+    assert_eq!(
+        head.header("Content-length"),
+        Some(body.len().to_string().as_str())
+    );
+    stream
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", url).as_bytes())
+        .unwrap();
+    let get = read_reply(&mut stream);
+    assert_eq!((get.status, get.text()), (200, body));
+}
+
+#[test]
+fn head_of_a_wildcard_route() {
+    let server = server();
+    head_then_get(
+        &server,
+        "/wildcard/a/b",
+        "wildcard",
+        "wildcard /wildcard/a/b",
+    );
+}
+
+#[test]
+fn head_of_a_param_route() {
+    let server = server();
+    head_then_get(&server, "/user/42", "user", "user 42");
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{read_reply, TestServer};
+use http_server::{HttpServer, Request, Response, GET};
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+fn server_with(setup: fn(&mut HttpServer)) -> TestServer {
+    TestServer::start(1, move |s| {
+        setup(s);
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.write_string("hi");
+        });
+    })
+}
+
+// the request line now, `rest` once the header time limit has passed
+fn send_late(server: &TestServer, rest: &[u8]) -> common::Reply {
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    thread::sleep(Duration::from_millis(400));
+    stream.write_all(rest).unwrap();
+    read_reply(&mut stream)
+}
+
+#[test]
+fn dribbled_head_gets_408() {
+    let server = server_with(|s| s.set_header_timeout(200));
+    let reply = send_late(&server, b"Host: a\r\n");
+    assert_eq!(reply.status, 408);
+    assert_eq!(reply.header("Connection"), Some("close"));
+}
+
+#[test]
+fn head_finished_by_the_late_read_is_served() {
+    let server = server_with(|s| s.set_header_timeout(200));
+    let reply = send_late(&server, b"Host: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (200, "hi"));
+}
+
+#[test]
+fn head_stalled_past_the_read_timeout_gets_408() {
+    let server = server_with(|s| {
+        s.set_header_timeout(0);
+        s.set_read_timeout(200);
+    });
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap();
+    assert_eq!(read_reply(&mut stream).status, 408);
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use common::TestServer;
+use http_server::{Request, Response, GET};
+
+fn header_lines<'r>(head: &'r str, name: &str) -> Vec<&'r str> {
+    head.split("\r\n")
+        .filter(|line| {
+            line.split_once(':')
+                .is_some_and(|(k, _)| k.eq_ignore_ascii_case(name))
+        })
+        .collect()
+}
+
+#[test]
+fn add_header_replaces_whatever_the_case() {
+    let server = TestServer::start(1, |s| {
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.add_header("X-Token".into(), "1".into());
+            res.add_header("x-token".into(), "2".into());
+            res.add_header_multi("X-Both".into(), "a".into());
+            res.add_header("x-both".into(), "b".into());
+            res.write_string("hi");
+        });
+    });
+    let reply = server.send(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(header_lines(&reply.head, "X-Token"), ["x-token: 2"]);
+    assert_eq!(header_lines(&reply.head, "X-Both"), ["x-both: b"]);
+}
+
+#[test]
+fn add_header_multi_keeps_one_spelling() {
+    let server = TestServer::start(1, |s| {
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.add_header("Vary".into(), "Accept".into());
+            res.add_header_multi("vary".into(), "Origin".into());
+            res.add_header_multi("VARY".into(), "Cookie".into());
+            res.write_string("hi");
+        });
+    });
+    let reply = server.send(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(
+        header_lines(&reply.head, "Vary"),
+        ["Vary: Accept", "Vary: Origin", "Vary: Cookie"]
+    );
+}
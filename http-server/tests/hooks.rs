@@ -0,0 +1,79 @@
+mod common;
+
+use common::TestServer;
+use http_server::{
+    inject_after_middlewares, inject_hooks, inject_middlewares, AfterMiddleWare, AfterWare,
+    MiddleWare, Request, Response, GET,
+};
+use std::sync::{Arc, Mutex};
+
+type Seen = Arc<Mutex<Vec<String>>>;
+
+fn server(seen: &Seen) -> TestServer {
+    let (global, route) = (Arc::clone(seen), Arc::clone(seen));
+    TestServer::start(1, move |s| {
+        s.after(move |req: &Request, res: &Response| {
+            let line = format!("after {} {}", req.get_url(), res.get_status());
+            global.lock().unwrap().push(line);
+        });
+        s.set_after_middlewares(inject_after_middlewares!(
+            |_req: &Request, res: &mut Response| {
+                if res.get_status() == 403 {
+                    res.write_state(401);
+                }
+            }
+        ));
+        s.route(GET, "/hooked").reg_with_hooks(
+            inject_middlewares!(|req: &Request, res: &mut Response| {
+                if req.get_header("X-Deny").is_some() {
+                    res.write_state(403);
+                    return false;
+                }
+                true
+            }),
+            inject_hooks!(move |_req: &Request, res: &Response| {
+                let line = format!("route {}", res.get_status());
+                route.lock().unwrap().push(line);
+            }),
+            |_req: &Request, res: &mut Response| {
+                res.write_string("hooked");
+            },
+        );
+    })
+}
+
+#[test]
+fn hooks_see_the_final_response() {
+    let seen = Seen::default();
+    let server = server(&seen);
+    assert_eq!(
+        server
+            .send(b"GET /hooked HTTP/1.1\r\nHost: a\r\n\r\n")
+            .status,
+        200
+    );
+    assert_eq!(*seen.lock().unwrap(), ["route 200", "after /hooked 200"]);
+}
+
+#[test]
+fn hooks_run_for_a_short_circuited_request() {
+    let seen = Seen::default();
+    let server = server(&seen);
+    let reply = server.send(b"GET /hooked HTTP/1.1\r\nHost: a\r\nX-Deny: 1\r\n\r\n");
+    // the after-middleware had its say before the hooks
+    assert_eq!(reply.status, 401);
+    assert_eq!(*seen.lock().unwrap(), ["route 401", "after /hooked 401"]);
+}
+
+#[test]
+fn unmatched_request_runs_the_global_hooks_only() {
+    let seen = Seen::default();
+    let server = server(&seen);
+    assert_eq!(
+        server
+            .send(b"GET /nowhere HTTP/1.1\r\nHost: a\r\n\r\n")
+            .status,
+        404
+    );
+    assert_eq!(*seen.lock().unwrap(), ["after /nowhere 404"]);
+}
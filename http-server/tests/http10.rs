@@ -0,0 +1,49 @@
+mod common;
+
+use common::{is_closed, read_reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+
+fn server() -> TestServer {
+    TestServer::start(1, |s| {
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.write_string(&"chunky ".repeat(100)).chunked();
+        });
+    })
+}
+
+#[test]
+fn gets_no_chunked_reply() {
+    let server = server();
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let reply = read_reply(&mut stream);
+    assert_eq!(reply.status, 200);
+    assert!(reply.head.starts_with("HTTP/1.0 "), "{}", reply.head);
+    assert_eq!(reply.header("Transfer-Encoding"), None);
+    assert_eq!(reply.text(), "chunky ".repeat(100));
+    assert!(is_closed(&mut stream));
+}
+
+#[test]
+fn http11_still_gets_chunked() {
+    let server = server();
+    let reply = server.send(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.header("Transfer-Encoding"), Some("chunked"));
+    assert_eq!(reply.text(), "chunky ".repeat(100));
+}
+
+#[test]
+fn kept_alive_only_when_asked() {
+    let server = server();
+    let mut stream = server.connect();
+    stream
+        .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+        .unwrap();
+    let reply = read_reply(&mut stream);
+    assert_eq!(reply.header("Transfer-Encoding"), None);
+    assert!(reply.header("Content-length").is_some());
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    assert_eq!(read_reply(&mut stream).status, 200);
+    assert!(is_closed(&mut stream));
+}
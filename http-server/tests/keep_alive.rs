@@ -0,0 +1,55 @@
+mod common;
+
+use common::{is_closed, read_reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+const GET_ROOT: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+
+fn server(setup: impl FnOnce(&mut http_server::HttpServer) + Send + 'static) -> TestServer {
+    TestServer::start(2, |s| {
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.write_string("hi");
+        });
+        setup(s);
+    })
+}
+
+#[test]
+fn closes_after_the_request_limit() {
+    let server = server(|s| s.set_max_keep_alive_requests(3));
+    let mut stream = server.connect();
+    for i in 1..=3 {
+        stream.write_all(GET_ROOT).unwrap();
+        let reply = read_reply(&mut stream);
+        assert_eq!(reply.status, 200);
+        let closing = reply
+            .header("Connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        assert_eq!(closing, i == 3, "request {}", i);
+    }
+    assert!(is_closed(&mut stream));
+}
+
+#[test]
+fn closes_an_idle_connection() {
+    let server = server(|s| s.set_keep_alive_timeout(200));
+    let mut stream = server.connect();
+    stream.write_all(GET_ROOT).unwrap();
+    assert_eq!(read_reply(&mut stream).status, 200);
+    thread::sleep(Duration::from_millis(400));
+    assert!(is_closed(&mut stream));
+}
+
+#[test]
+fn zero_keep_alive_timeout_means_no_limit() {
+    let server = server(|s| s.set_keep_alive_timeout(0));
+    let mut stream = server.connect();
+    stream.write_all(GET_ROOT).unwrap();
+    assert_eq!(read_reply(&mut stream).status, 200);
+    thread::sleep(Duration::from_millis(300));
+    stream.write_all(GET_ROOT).unwrap();
+    assert_eq!(read_reply(&mut stream).status, 200);
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{is_closed, read_reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FAST: &[u8] = b"GET /fast HTTP/1.1\r\nHost: a\r\n\r\n";
+
+fn server() -> TestServer {
+    TestServer::start(2, |s| {
+        s.set_keep_alive_timeout(10_000);
+        s.route(GET, "/slow")
+            .reg(|_req: &Request, res: &mut Response| {
+                thread::sleep(Duration::from_millis(400));
+                res.write_string("slow");
+            });
+        s.route(GET, "/fast")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string("fast");
+            });
+    })
+}
+
+#[test]
+fn fast_route_keeps_its_latency_next_to_a_slow_one() {
+    let server = server();
+    let addr = server.addr;
+    // one worker is kept in the slow route the whole time
+    let slow = thread::spawn(move || {
+        for _ in 0..3 {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET /slow HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            assert_eq!(read_reply(&mut stream).text(), "slow");
+        }
+    });
+    thread::sleep(Duration::from_millis(50));
+    // every fast client stays connected, so the other worker idles on a
+    // kept-alive connection each time the next one comes
+    let mut clients = Vec::new();
+    let mut total = Duration::ZERO;
+    for _ in 0..20 {
+        let started = Instant::now();
+        let mut stream = server.connect();
+        stream.write_all(FAST).unwrap();
+        assert_eq!(read_reply(&mut stream).text(), "fast");
+        total += started.elapsed();
+        clients.push(stream);
+    }
+    // the worker is woken for a new client instead of noticing it at its
+    // next look; the accept loop's own 10 ms poll is most of what is left
+    let mean = total / 20;
+    assert!(
+        mean < Duration::from_millis(30),
+        "took {:?} on average",
+        mean
+    );
+    // given up for the next client rather than held on to
+    for stream in &mut clients[..19] {
+        assert!(is_closed(stream));
+    }
+    slow.join().unwrap();
+}
+
+#[test]
+fn kept_alive_connection_stays_while_a_worker_is_free() {
+    let server = server();
+    let mut kept = server.connect();
+    kept.write_all(FAST).unwrap();
+    assert_eq!(
+        read_reply(&mut kept).header("Connection"),
+        Some("keep-alive")
+    );
+    // the other worker takes this one, the first keeps its connection
+    assert_eq!(server.send(FAST).text(), "fast");
+    kept.write_all(FAST).unwrap();
+    let reply = read_reply(&mut kept);
+    assert_eq!((reply.status, reply.text()), (200, "fast"));
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use common::{is_closed, read_reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+use std::net::Shutdown;
+
+fn server() -> TestServer {
+    TestServer::start(1, |s| {
+        s.set_max_header_size(256);
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.write_string("hi");
+        });
+    })
+}
+
+#[test]
+fn broken_request_line_gets_400() {
+    let server = server();
+    let reply = server.send(b"GET /\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 400);
+}
+
+#[test]
+fn head_that_isnt_utf8_gets_400() {
+    let server = server();
+    let reply = server.send(b"GET / HTTP/1.1\r\nX-Bad: \xff\xfe\r\n\r\n");
+    assert_eq!(reply.status, 400);
+}
+
+#[test]
+fn head_cut_off_gets_400() {
+    let server = server();
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_reply(&mut stream).status, 400);
+}
+
+#[test]
+fn oversized_head_gets_431() {
+    let server = server();
+    // still no end of the head in sight past the limit
+    let mut request = b"GET / HTTP/1.1\r\nX-Long: ".to_vec();
+    request.extend_from_slice(&[b'a'; 400]);
+    let reply = server.send(&request);
+    assert_eq!(reply.status, 431);
+}
+
+#[test]
+fn closing_without_a_request_gets_nothing() {
+    let server = server();
+    let mut stream = server.connect();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(is_closed(&mut stream));
+}
+
+#[test]
+fn other_http_version_gets_505() {
+    let server = server();
+    for version in ["HTTP/2.0", "HTTP/0.9"] {
+        let mut stream = server.connect();
+        stream
+            .write_all(format!("GET / {}\r\nHost: a\r\n\r\n", version).as_bytes())
+            .unwrap();
+        let reply = read_reply(&mut stream);
+        assert_eq!(reply.status, 505, "{}", version);
+        assert_eq!(reply.header("Connection"), Some("close"));
+        assert!(is_closed(&mut stream));
+    }
+    // not HTTP at all is just malformed
+    let reply = server.send(b"GET / SIP/2.0\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 400);
+}
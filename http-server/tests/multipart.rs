@@ -0,0 +1,92 @@
+mod common;
+
+use common::TestServer;
+use http_server::{Request, Response, GET, POST};
+use std::path::Path;
+
+fn server() -> TestServer {
+    TestServer::start(1, |s| {
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.write_string("hi");
+        });
+        s.route(POST, "/form")
+            .reg(|req: &Request, res: &mut Response| {
+                let files = req.get_files().unwrap_or_default();
+                for file in &files {
+                    let _ = std::fs::remove_file(&file.filepath);
+                }
+                res.write_string(&format!("{:?} {}", req.get_query("t"), files.len()));
+            });
+    })
+}
+
+fn post_form(server: &TestServer, body: &[u8]) -> u16 {
+    let mut request = format!(
+        "POST /form HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\
+         Content-Type: multipart/form-data; boundary=XYZ\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    server.send(&request).status
+}
+
+// the server is still there for the next request
+fn assert_alive(server: &TestServer) {
+    let reply = server.send(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 200);
+}
+
+#[test]
+fn well_formed_form() {
+    let server = server();
+    let body = b"--XYZ\r\nContent-Disposition: form-data; name=\"t\"\r\n\r\nvalue\r\n\
+                 --XYZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                 Content-Type: text/plain\r\n\r\nfile body\r\n--XYZ--\r\n";
+    assert_eq!(post_form(&server, body), 200);
+}
+
+#[test]
+fn file_part_without_a_closing_quote() {
+    let server = server();
+    let body = b"--XYZ\r\nContent-Disposition: form-data; filename=\"a.txt\r\n\
+                 Content-Type: text/plain\r\n\r\nx\r\n--XYZ--\r\n";
+    assert_eq!(post_form(&server, body), 400);
+    assert_alive(&server);
+}
+
+#[test]
+fn disposition_that_isnt_utf8() {
+    let server = server();
+    let body = b"--XYZ\r\nContent-Disposition: form-data; name=\"f\xff\"; filename=\"a\"\r\n\
+                 Content-Type: text/plain\r\n\r\nx\r\n--XYZ--\r\n";
+    assert_eq!(post_form(&server, body), 400);
+    assert_alive(&server);
+}
+
+#[test]
+fn text_part_without_a_name() {
+    let server = server();
+    let body = b"--XYZ\r\nContent-Disposition: form-data\r\n\r\nvalue\r\n--XYZ--\r\n";
+    assert_eq!(post_form(&server, body), 400);
+    assert_alive(&server);
+}
+
+#[test]
+fn failed_form_leaves_no_file_behind() {
+    let server = server();
+    let body = b"--XYZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.leftover\"\r\n\
+                 Content-Type: text/plain\r\n\r\nfile body\r\n\
+                 --XYZ\r\nContent-Disposition: form-data\r\n\r\nvalue\r\n--XYZ--\r\n";
+    assert_eq!(post_form(&server, body), 400);
+    let leftover = std::fs::read_dir(Path::new("upload"))
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| {
+            entry
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "leftover")
+        });
+    assert!(!leftover);
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use common::{is_closed, read_reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+
+const BLOCKED: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+fn sockaddr(addr: SocketAddr) -> libc::sockaddr_in {
+    let SocketAddr::V4(addr) = addr else {
+        panic!("{} is not IPv4", addr);
+    };
+    libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    }
+}
+
+// any 127/8 address reaches the server on 127.0.0.1, this one is the peer
+fn connect_from(source: Ipv4Addr, server: SocketAddr) -> TcpStream {
+    let len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let local = sockaddr(SocketAddr::new(IpAddr::V4(source), 0));
+    let remote = sockaddr(server);
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+        assert!(fd >= 0);
+        let stream = TcpStream::from_raw_fd(fd);
+        let r = libc::bind(fd, &local as *const _ as *const libc::sockaddr, len);
+        assert_eq!(r, 0, "bind {}", source);
+        let r = libc::connect(fd, &remote as *const _ as *const libc::sockaddr, len);
+        assert_eq!(r, 0, "connect from {}", source);
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+}
+
+#[test]
+fn rejected_peer_is_closed_and_others_are_served() {
+    let server = TestServer::start(1, |s| {
+        s.on_accept(|peer: &SocketAddr| peer.ip() != IpAddr::V4(BLOCKED));
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.write_string("hi");
+        });
+    });
+    let mut blocked = connect_from(BLOCKED, server.addr);
+    // the request may or may not make it out before the close
+    let _ = blocked.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert!(is_closed(&mut blocked));
+
+    let mut allowed = connect_from(Ipv4Addr::new(127, 0, 0, 3), server.addr);
+    allowed
+        .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    let reply = read_reply(&mut allowed);
+    assert_eq!((reply.status, reply.text()), (200, "hi"));
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::TestServer;
+use http_server::{Request, Response, GET};
+
+fn server(custom: bool) -> TestServer {
+    TestServer::start(1, move |s| {
+        s.route(GET, "/boom")
+            .reg(|_req: &Request, _res: &mut Response| {
+                panic!("the route blew up");
+            });
+        s.route(GET, "/ok")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string("ok");
+            });
+        if custom {
+            s.set_internal_error(|_req: &Request, res: &mut Response| {
+                res.write_string("sorry").status(500);
+            });
+        }
+    })
+}
+
+#[test]
+fn panicking_route_gets_500_and_the_worker_goes_on() {
+    // a single worker, the next connection needs the one that panicked
+    let server = server(false);
+    let reply = server.send(b"GET /boom HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 500);
+    let reply = server.send(b"GET /ok HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (200, "ok"));
+}
+
+#[test]
+fn panicking_route_gets_the_custom_answer() {
+    let server = server(true);
+    let reply = server.send(b"GET /boom HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (500, "sorry"));
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::TestServer;
+use http_server::{Request, Response, GET};
+
+// a directory of its own per test, holding `app.js` and the given sidecars
+fn assets(name: &str, sidecars: &[&str]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("precompressed-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("app.js"), "console.log(1);").unwrap();
+    for suffix in sidecars {
+        std::fs::write(dir.join(format!("app.js{}", suffix)), suffix.as_bytes()).unwrap();
+    }
+    dir
+}
+
+fn serve(dir: &Path) -> TestServer {
+    let path = dir.join("app.js").to_string_lossy().into_owned();
+    TestServer::start(1, move |s| {
+        s.route(GET, "/app.js")
+            .reg(move |_req: &Request, res: &mut Response| {
+                res.write_file(path.clone()).precompressed().enable_range();
+            });
+    })
+}
+
+#[test]
+fn sidecar_is_served_to_a_client_accepting_it() {
+    let dir = assets("accepted", &[".gz"]);
+    let server = serve(&dir);
+    let reply =
+        server.send(b"GET /app.js HTTP/1.1\r\nHost: a\r\nAccept-Encoding: br, gzip\r\n\r\n");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(reply.text(), ".gz");
+    assert!(reply.header("Content-Type").unwrap().contains("javascript"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn missing_sidecar_falls_back_to_the_original() {
+    let dir = assets("missing", &[]);
+    let server = serve(&dir);
+    let reply =
+        server.send(b"GET /app.js HTTP/1.1\r\nHost: a\r\nAccept-Encoding: br, gzip\r\n\r\n");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Encoding"), None);
+    assert_eq!(reply.text(), "console.log(1);");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_without_accept_encoding_gets_the_original() {
+    let dir = assets("identity", &[".br", ".gz"]);
+    let server = serve(&dir);
+    let reply = server.send(b"GET /app.js HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Encoding"), None);
+    assert_eq!(reply.text(), "console.log(1);");
+    assert_eq!(reply.header("Vary"), Some("Accept-Encoding"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn range_is_taken_from_the_original() {
+    let dir = assets("range", &[".gz"]);
+    let server = serve(&dir);
+    let reply = server.send(
+        b"GET /app.js HTTP/1.1\r\nHost: a\r\nAccept-Encoding: gzip\r\nRange: bytes=0-6\r\n\r\n",
+    );
+    assert_eq!(reply.status, 206);
+    assert_eq!(reply.header("Content-Encoding"), None);
+    assert_eq!(reply.text(), "console");
+    std::fs::remove_dir_all(dir).unwrap();
+}
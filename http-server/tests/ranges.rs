@@ -0,0 +1,157 @@
+mod common;
+
+use common::{read_head, Reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+
+const BODY: &str = "0123456789abcdefghij";
+
+fn server() -> (TestServer, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("ranges-{}.txt", std::process::id()));
+    std::fs::write(&path, BODY).unwrap();
+    let file = path.to_string_lossy().into_owned();
+    let server = TestServer::start(1, move |s| {
+        s.route(GET, "/memory")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string(BODY).enable_range();
+            });
+        s.route(GET, "/file")
+            .reg(move |_req: &Request, res: &mut Response| {
+                res.write_file(file.clone()).enable_range();
+            });
+    });
+    (server, path)
+}
+
+fn get(server: &TestServer, url: &str, range: &str) -> Reply {
+    server.send(
+        format!(
+            "GET {} HTTP/1.1\r\nHost: a\r\nRange: {}\r\n\r\n",
+            url, range
+        )
+        .as_bytes(),
+    )
+}
+
+#[test]
+fn single_range() {
+    let (server, path) = server();
+    for url in ["/memory", "/file"] {
+        let reply = get(&server, url, "bytes=2-5");
+        assert_eq!((reply.status, reply.text()), (206, "2345"), "{}", url);
+        assert_eq!(
+            reply.header("Content-Range"),
+            Some("bytes 2-5/20"),
+            "{}",
+            url
+        );
+        let reply = get(&server, url, "bytes=-3");
+        assert_eq!((reply.status, reply.text()), (206, "hij"), "{}", url);
+        let reply = get(&server, url, "bytes=15-");
+        assert_eq!((reply.status, reply.text()), (206, "fghij"), "{}", url);
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn spaced_and_oddly_cased_ranges() {
+    let (server, path) = server();
+    for url in ["/memory", "/file"] {
+        let reply = get(&server, url, "Bytes = 2 - 5");
+        assert_eq!((reply.status, reply.text()), (206, "2345"), "{}", url);
+        let reply = get(&server, url, "BYTES=-3");
+        assert_eq!((reply.status, reply.text()), (206, "hij"), "{}", url);
+        let reply = get(&server, url, "bytes=0-1 , 10-11");
+        assert_eq!(reply.status, 206, "{}", url);
+        assert!(
+            reply.text().contains("Content-Range: bytes 10-11/20"),
+            "{}",
+            url
+        );
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn several_ranges() {
+    let (server, path) = server();
+    // a part names the body's type, and `write_string` sets none
+    for (url, part_type) in [("/memory", ""), ("/file", "Content-Type: text/plain\r\n")] {
+        let reply = get(&server, url, "bytes=0-1,10-11");
+        assert_eq!(reply.status, 206, "{}", url);
+        let content_type = reply.header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "--{0}\r\n{1}Content-Range: bytes 0-1/20\r\n\r\n01\r\n\
+             --{0}\r\n{1}Content-Range: bytes 10-11/20\r\n\r\nab\r\n--{0}--\r\n",
+            boundary, part_type
+        );
+        assert_eq!(reply.text(), expected, "{}", url);
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn unsatisfiable_and_malformed_ranges() {
+    let (server, path) = server();
+    for url in ["/memory", "/file"] {
+        let reply = get(&server, url, "bytes=20-");
+        assert_eq!(reply.status, 416, "{}", url);
+        assert_eq!(reply.header("Content-Range"), Some("bytes */20"), "{}", url);
+        assert_eq!(get(&server, url, "bytes=5-2").status, 416, "{}", url);
+        // another unit is ignored, the whole body is sent
+        let reply = get(&server, url, "items=0-1");
+        assert_eq!((reply.status, reply.text()), (200, BODY), "{}", url);
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn too_many_ranges_get_the_whole_body() {
+    let (server, path) = server();
+    let many: Vec<String> = (0..33).map(|i| format!("{0}-{0}", i * 2 % 20)).collect();
+    let range = format!("bytes={}", many.join(","));
+    for url in ["/memory", "/file"] {
+        let reply = get(&server, url, &range);
+        assert_eq!((reply.status, reply.text()), (200, BODY), "{}", url);
+        // one under the cap still gets its parts
+        let range = format!("bytes={}", many[..32].join(","));
+        let reply = get(&server, url, &range);
+        assert_eq!(reply.status, 206, "{}", url);
+        assert!(
+            reply.text().contains("Content-Range: bytes 18-18/20"),
+            "{}",
+            url
+        );
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn get_of_a_video_advertises_ranges() {
+    let path = std::env::temp_dir().join(format!("ranges-{}.mp4", std::process::id()));
+    std::fs::write(&path, BODY).unwrap();
+    let file = path.to_string_lossy().into_owned();
+    // as the example binary registers `/mp4`
+    let server = TestServer::start(1, move |s| {
+        s.route(GET, "/mp4")
+            .reg(move |_req: &Request, res: &mut Response| {
+                res.write_file(file.clone()).chunked().enable_range();
+            });
+    });
+    let reply = server.send(b"GET /mp4 HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (200, BODY));
+    assert_eq!(reply.header("Accept-Ranges"), Some("bytes"));
+    assert_eq!(reply.header("Content-Type"), Some("video/mp4"));
+    let mut stream = server.connect();
+    stream
+        .write_all(b"HEAD /mp4 HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    assert_eq!(
+        read_head(&mut stream).header("Accept-Ranges"),
+        Some("bytes")
+    );
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::{read_reply, TestServer};
+use http_server::{Request, Response, GET};
+use std::io::Write;
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::Duration;
+
+const SIZE: usize = 512 << 10;
+
+// the kernel may round it up, it is still far below the body
+fn shrink_send_buffer(stream: &TcpStream) {
+    let size: libc::c_int = 4096;
+    let r = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_SNDBUF,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(r, 0);
+}
+
+fn body() -> Vec<u8> {
+    (0..SIZE).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn body_arrives_whole_through_tiny_socket_buffers() {
+    let server = TestServer::start(1, |s| {
+        s.set_chunksize(1000);
+        s.route(GET, "/length")
+            .reg(|req: &Request, res: &mut Response| {
+                shrink_send_buffer(req.get_conn().borrow().tcp());
+                res.write_binary(body());
+            });
+        s.route(GET, "/chunked")
+            .reg(|req: &Request, res: &mut Response| {
+                shrink_send_buffer(req.get_conn().borrow().tcp());
+                res.write_binary(body()).chunked();
+            });
+    });
+    for url in ["/length", "/chunked"] {
+        let mut stream = server.connect();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", url).as_bytes())
+            .unwrap();
+        // let both buffers fill up before anything is read
+        thread::sleep(Duration::from_millis(200));
+        let reply = read_reply(&mut stream);
+        assert_eq!(reply.status, 200, "{}", url);
+        assert!(reply.body == body(), "{} came out different", url);
+    }
+}
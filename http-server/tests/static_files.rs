@@ -0,0 +1,59 @@
+mod common;
+
+use common::TestServer;
+use http_server::{Request, Response, GET};
+
+#[test]
+fn static_files_at_the_root_leave_other_routes_alone() {
+    let dir = std::env::temp_dir().join(format!("static-root-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("css")).unwrap();
+    std::fs::write(dir.join("index.html"), "home").unwrap();
+    std::fs::write(dir.join("css").join("site.css"), "body{}").unwrap();
+    let root = dir.to_string_lossy().into_owned();
+    let server = TestServer::start(1, move |s| {
+        s.serve_static("/", &root);
+        s.route(GET, "/api")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string("api");
+            });
+    });
+    let reply = server.send(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (200, "home"));
+    let reply = server.send(b"GET /css/site.css HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (200, "body{}"));
+    let reply = server.send(b"GET /api HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (200, "api"));
+    let reply = server.send(b"GET /missing.js HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 404);
+    let reply = server.send(b"GET /../../etc/passwd HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 403);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn serve_dir_refuses_traversal_and_can_skip_the_index() {
+    let dir = std::env::temp_dir().join(format!("serve-dir-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("docs")).unwrap();
+    std::fs::write(dir.join("docs").join("index.html"), "docs").unwrap();
+    std::fs::write(dir.join("app.js"), "js").unwrap();
+    let root = dir.to_string_lossy().into_owned();
+    let server = TestServer::start(1, move |s| {
+        s.serve_dir("/static", &root);
+        s.serve_dir("/bare", &root).index(false);
+    });
+    let reply = server.send(b"GET /static/app.js HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (200, "js"));
+    assert!(reply.header("Content-Type").unwrap().contains("javascript"));
+    let reply = server.send(b"GET /static/docs/ HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!((reply.status, reply.text()), (200, "docs"));
+    let reply = server.send(b"GET /bare/docs/ HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 404);
+    for path in [
+        "/static/../../etc/passwd",
+        "/static/%2e%2e/%2e%2e/etc/passwd",
+    ] {
+        let request = format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path);
+        assert_eq!(server.send(request.as_bytes()).status, 403, "{}", path);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::TestServer;
+use http_server::{Request, Response, GET, POST};
+
+fn server(enable: bool) -> TestServer {
+    TestServer::start(1, move |s| {
+        s.route([GET, POST], "/items")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string("items");
+            });
+        if enable {
+            s.enable_trace();
+        }
+    })
+}
+
+#[test]
+fn refused_with_the_route_methods_in_allow() {
+    let server = server(false);
+    let reply = server.send(b"TRACE /items HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(reply.status, 405);
+    assert_eq!(reply.header("Allow"), Some("GET, HEAD, POST"));
+}
+
+#[test]
+fn echo_leaves_credentials_out() {
+    let server = server(true);
+    let reply = server.send(
+        b"TRACE /items HTTP/1.1\r\nHost: a\r\nCookie: sid=1\r\nauthorization: Basic eDp5\r\n\
+          X-Probe: yes\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Type"), Some("message/http"));
+    let echo = reply.text();
+    assert!(echo.starts_with("TRACE /items HTTP/1.1\r\n"), "{}", echo);
+    assert!(echo.contains("X-Probe: yes\r\n"), "{}", echo);
+    assert!(!echo.to_ascii_lowercase().contains("cookie"), "{}", echo);
+    assert!(
+        !echo.to_ascii_lowercase().contains("authorization"),
+        "{}",
+        echo
+    );
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use common::{read_reply, TestServer};
+use http_server::{Request, Response, POST};
+use std::io::Write;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ECHO_HEAD: &[u8] =
+    b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\n";
+
+fn server(name: &str) -> (TestServer, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("tus-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let store = dir.to_string_lossy().into_owned();
+    let server = TestServer::start(1, move |s| {
+        s.serve_tus("/files", &store);
+        s.route(POST, "/echo")
+            .reg(|req: &Request, res: &mut Response| {
+                res.write_string(req.plain_body().unwrap_or("-"));
+            });
+    });
+    (server, dir)
+}
+
+fn create(server: &TestServer, length: u64) -> String {
+    let reply = server.send(
+        format!(
+            "POST /files HTTP/1.1\r\nHost: a\r\nTus-Resumable: 1.0.0\r\n\
+             Upload-Length: {}\r\nContent-Length: 0\r\n\r\n",
+            length
+        )
+        .as_bytes(),
+    );
+    assert_eq!(reply.status, 201);
+    reply.header("Location").unwrap().to_string()
+}
+
+fn patch_head(location: &str, offset: u64, len: usize) -> String {
+    format!(
+        "PATCH {} HTTP/1.1\r\nHost: a\r\nTus-Resumable: 1.0.0\r\n\
+         Content-Type: application/offset+octet-stream\r\n\
+         Upload-Offset: {}\r\nContent-Length: {}\r\n\r\n",
+        location, offset, len
+    )
+}
+
+#[test]
+fn binary_chunks_are_appended() {
+    let (server, dir) = server("append");
+    let location = create(&server, 6);
+    let mut stream = server.connect();
+    let mut request = patch_head(&location, 0, 3).into_bytes();
+    request.extend_from_slice(&[0xff, 0x00, 0xfe]);
+    stream.write_all(&request).unwrap();
+    let reply = read_reply(&mut stream);
+    assert_eq!(reply.status, 204);
+    assert_eq!(reply.header("Upload-Offset"), Some("3"));
+    let mut request = patch_head(&location, 3, 3).into_bytes();
+    request.extend_from_slice(b"abc");
+    stream.write_all(&request).unwrap();
+    let reply = read_reply(&mut stream);
+    assert_eq!(reply.header("Upload-Offset"), Some("6"));
+    let id = location.rsplit('/').next().unwrap();
+    assert_eq!(
+        std::fs::read(dir.join(id)).unwrap(),
+        [0xff, 0x00, 0xfe, b'a', b'b', b'c']
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn chunk_is_written_before_it_has_all_arrived() {
+    let (server, dir) = server("streamed");
+    let location = create(&server, 10);
+    let id = location.rsplit('/').next().unwrap();
+    let mut stream = server.connect();
+    stream
+        .write_all(patch_head(&location, 0, 10).as_bytes())
+        .unwrap();
+    stream.write_all(b"01234").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while std::fs::metadata(dir.join(id)).unwrap().len() < 5 {
+        assert!(Instant::now() < deadline, "nothing was written yet");
+        thread::sleep(Duration::from_millis(10));
+    }
+    stream.write_all(b"56789").unwrap();
+    let reply = read_reply(&mut stream);
+    assert_eq!(reply.header("Upload-Offset"), Some("10"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn refused_chunk_is_skipped_for_the_next_request() {
+    let (server, dir) = server("refused");
+    let location = create(&server, 4);
+    let mut stream = server.connect();
+    // the wrong offset, and then longer than the upload
+    for (offset, body) in [(2, &b"ab"[..]), (0, &b"abcdef"[..])] {
+        let mut request = patch_head(&location, offset, body.len()).into_bytes();
+        request.extend_from_slice(body);
+        stream.write_all(&request).unwrap();
+        let reply = read_reply(&mut stream);
+        assert_eq!(reply.status, if offset == 2 { 409 } else { 400 });
+    }
+    stream.write_all(ECHO_HEAD).unwrap();
+    stream.write_all(b"ok").unwrap();
+    let reply = read_reply(&mut stream);
+    assert_eq!((reply.status, reply.text()), (200, "ok"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn binary_body_is_still_refused_elsewhere() {
+    let (server, dir) = server("elsewhere");
+    let mut request = ECHO_HEAD.to_vec();
+    request.extend_from_slice(&[0xff, 0xfe]);
+    assert_eq!(server.send(&request).status, 400);
+    let _ = std::fs::remove_dir_all(dir);
+}
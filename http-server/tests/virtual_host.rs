@@ -0,0 +1,51 @@
+mod common;
+
+use common::TestServer;
+use http_server::{Request, Response, GET};
+
+fn server() -> TestServer {
+    TestServer::start(1, |s| {
+        s.route(GET, "/").reg(|_req: &Request, res: &mut Response| {
+            res.write_string("default");
+        });
+        s.route(GET, "/shared")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string("shared");
+            });
+        s.host("example.com")
+            .route(GET, "/")
+            .reg(|_req: &Request, res: &mut Response| {
+                res.write_string("example");
+            });
+        s.set_unknown_host(|_req: &Request, res: &mut Response| {
+            res.write_string("unknown host").status(404);
+        });
+    })
+}
+
+fn get(server: &TestServer, path: &str, host: &str) -> common::Reply {
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host);
+    server.send(request.as_bytes())
+}
+
+#[test]
+fn known_host_gets_its_routes() {
+    let server = server();
+    assert_eq!(get(&server, "/", "Example.COM:8080").text(), "example");
+    assert_eq!(get(&server, "/shared", "example.com").text(), "shared");
+}
+
+#[test]
+fn unknown_host_falls_back_to_the_default_routes() {
+    let server = server();
+    assert_eq!(get(&server, "/", "other.org").text(), "default");
+    assert_eq!(get(&server, "/shared", "other.org").text(), "shared");
+}
+
+#[test]
+fn unknown_host_handler_takes_what_nothing_else_does() {
+    let server = server();
+    let reply = get(&server, "/missing", "other.org");
+    assert_eq!(reply.status, 404);
+    assert_eq!(reply.text(), "unknown host");
+}
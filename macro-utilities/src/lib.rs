@@ -1,22 +1,48 @@
-use std::{str::FromStr};
+use std::net::IpAddr;
+use std::str::FromStr;
 
 use proc_macro::TokenStream;
+/// `end_point!(0.0.0.0:8080)` for IPv4, `end_point!([::1]:8080)` for IPv6.
 #[proc_macro]
-pub fn end_point(input:TokenStream)->TokenStream{
-   let str = input.to_string();
-   let split = str.split(":");
-   let size = split.clone().count();
-   if size!=2{
-	  panic!("invalid endpoint value!")
-   }else{
-	  let vec:Vec<&str> = split.collect();
-	  let addr = vec[0];
-	  let port = vec[1];
-	  let addr:Vec<&str> = addr.split(".").collect();
-	  let addr = addr.join(",");
-	  let mut value = format!("port:{},ip_address:[{}]",port,addr);
-	  value  = "EndPoint{".to_string() + &value;
-	  value = value + "}";
-	  TokenStream::from_str(&value).unwrap()
-   }
+pub fn end_point(input: TokenStream) -> TokenStream {
+    // the literal comes in as tokens, e.g. `127.0 . 0.1`, so the spacing
+    // between them is dropped first
+    let str: String = input
+        .to_string()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let split = match str.strip_prefix('[') {
+        Some(v6) => v6.split_once("]:"),
+        None => str.rsplit_once(':'),
+    };
+    let (addr, port) = match split {
+        Some(v) => v,
+        None => panic!("invalid endpoint value!"),
+    };
+    let port: u16 = match port.parse() {
+        Ok(port) => port,
+        Err(_) => panic!("invalid endpoint port!"),
+    };
+    let addr = match addr.parse::<IpAddr>() {
+        Ok(IpAddr::V4(v4)) => {
+            let [a, b, c, d] = v4.octets();
+            format!(
+                "::std::net::IpAddr::V4(::std::net::Ipv4Addr::new({},{},{},{}))",
+                a, b, c, d
+            )
+        }
+        Ok(IpAddr::V6(v6)) => {
+            let segments: Vec<String> = v6.segments().iter().map(|s| s.to_string()).collect();
+            format!(
+                "::std::net::IpAddr::V6(::std::net::Ipv6Addr::new({}))",
+                segments.join(",")
+            )
+        }
+        Err(_) => panic!("invalid endpoint address!"),
+    };
+    let mut value = format!("port:{},ip_address: {}", port, addr);
+    value = "EndPoint{".to_string() + &value;
+    value += "}";
+    TokenStream::from_str(&value).unwrap()
 }